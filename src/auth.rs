@@ -7,10 +7,11 @@ use iron::{Request, IronResult, IronError, BeforeMiddleware};
 use iron::status;
 use iron::typemap::Key;
 use hyper::header::{Authorization, Basic};
-use persistent::Write;
-use plugin::Extensible;
+use postgres::Connection;
+use url::form_urlencoded;
 
 use model::{Database, Session, User};
+use error::FictResult;
 
 #[derive(Debug)]
 struct AuthError;
@@ -43,6 +44,52 @@ pub struct AuthUser;
 
 impl Key for AuthUser { type Value = User; }
 
+/// Identify the `User` whose session token is presented as the password of an `Authorization`
+/// header. Produce `Ok(None)` if the token is missing, malformed, or doesn't match a valid
+/// session.
+pub fn session_user(conn: &Connection, auth: &Authorization<Basic>) -> FictResult<Option<User>> {
+    let password = match auth.password {
+        Some(ref password) => password,
+        None => {
+            warn!("No password present in Authorization header.");
+            return Ok(None);
+        }
+    };
+
+    let token = match password.parse::<i64>() {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Unable to parse token id from a request: [{}]", e);
+            return Ok(None);
+        }
+    };
+
+    match try!(Session::validate(conn, token)) {
+        Some(session) => session.user(conn).map(Some),
+        None => Ok(None)
+    }
+}
+
+/// Identify the `User` whose session token accompanies a request to a server that browsers connect
+/// to directly, where scripts can't always set headers: either the `token` query parameter of
+/// `path`, or the password of its `Authorization` header. Produce `Ok(None)` if neither is present
+/// or the token doesn't match a valid session.
+pub fn request_user(conn: &Connection, path: &str, auth: Option<&Authorization<Basic>>) -> FictResult<Option<User>> {
+    let query = path.splitn(2, '?').nth(1).unwrap_or("");
+    let token = form_urlencoded::parse(query.as_bytes()).into_iter()
+        .find(|&(ref key, _)| key == "token")
+        .map(|(_, value)| value);
+
+    match (token, auth) {
+        (Some(token), _) => session_user(conn, &Authorization(Basic {
+            username: String::new(),
+            password: Some(token)
+        })),
+        (None, Some(auth)) => session_user(conn, auth),
+        (None, None) => Ok(None)
+    }
+}
+
 /// Link this middleware before a handler to ensure that an incoming request is accompanied by
 /// a valid API key. If so, the Session will be added to the request. Otherwise, a 401 response
 /// will be returned.
@@ -55,34 +102,15 @@ impl BeforeMiddleware for RequireUser {
 
         match auth_opt {
             Some(auth) => {
-                let mutex = req.extensions().get::<Write<Database>>()
-                    .cloned()
-                    .expect("No database connection available");
-                let pool = mutex.lock().unwrap();
-                let conn = pool.get().unwrap();
-
-                let password = try!(auth.password.clone().ok_or_else(|| {
-                    warn!("No password present in Authorization header.");
-                    AuthError::iron()
-                }));
+                let conn = try!(Database::connection(req));
 
-                let token = try!(password.parse::<i64>().map_err(|e| {
-                    warn!("Unable to parse token id from a request: [{}]", e);
-                    AuthError::iron()
-                }));
-
-                let session_opt = try!(Session::validate(&*conn, token).map_err(|e| {
+                let user_opt = try!(session_user(&*conn, &auth).map_err(|e| {
                     error!("Unable to query the database for a session: [{}]", e);
                     AuthError::iron()
                 }));
 
-                match session_opt {
-                    Some(session) => {
-                        let user = try!(session.user(&*conn).map_err(|e| {
-                            error!("Unable to query the database for a user: [{}]", e);
-                            AuthError::iron()
-                        }));
-
+                match user_opt {
+                    Some(user) => {
                         req.extensions_mut().insert::<AuthUser>(user);
 
                         Ok(())
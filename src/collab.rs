@@ -0,0 +1,577 @@
+//! WebSocket collaboration channels, served alongside the HTTP API.
+//!
+//! Connect to `/stories/:id/collab` on `FICTION_WS_ADDRESS` with the same `Authorization` header
+//! used for API requests or, because browsers can't set headers on WebSocket connections, with
+//! your session token as the `token` query parameter. Each channel reports who is viewing the
+//! story, who holds its lock, and who is typing, as a `presence` message whenever any of those
+//! change. Members who lose access to the story are disconnected.
+//!
+//! Clients send JSON commands:
+//!
+//! * `{"command": "typing", "typing": true}` - Report whether or not you're typing.
+//! * `{"command": "acquire_lock"}` - Acquire the story's lock, as `POST /stories/:id/lock` does.
+//! * `{"command": "release_lock"}` - Release the story's lock, as `DELETE /stories/:id/lock` does.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{self, Sender as Outbox};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use hyper::header::{Authorization, Basic};
+use hyper::uri::RequestUri;
+use rustc_serialize::json;
+use chrono::UTC;
+use websocket::{Server, Message, Sender, Receiver};
+
+use config::env_opt;
+use auth::request_user;
+use model::{PostgresPool, Story, StoryEvent, EventPosition, User, PromptSettings, ConstraintSettings};
+use error::{FictResult, FictError, fict_err};
+use responses::timestamp;
+
+/// Address to listen on when `FICTION_WS_ADDRESS` is unset.
+const DEFAULT_ADDRESS: &'static str = "localhost:3001";
+
+/// Number of channels that may be open at once when `FICTION_WS_CONNECTIONS` is unset.
+const DEFAULT_CONNECTIONS: usize = 64;
+
+/// Milliseconds to wait between checks for story activity that happened outside of a channel.
+const REFRESH_INTERVAL_MS: u64 = 2000;
+
+/// Source of a unique ID for each connection.
+static NEXT_CONNECTION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A connection to a story's channel.
+struct Member {
+    connection_id: usize,
+    user: User,
+    typing: bool,
+    outbox: Outbox<Message>
+}
+
+/// Members of each story's channel, by story ID.
+type Rooms = Arc<Mutex<HashMap<i64, Vec<Member>>>>;
+
+/// Count of open connections, each of which occupies two threads, kept within `limit`.
+struct Slots {
+    open: Mutex<usize>,
+    freed: Condvar,
+    limit: usize
+}
+
+impl Slots {
+
+    /// Wait until fewer than `limit` connections are open, then claim a place for another.
+    fn claim(slots: &Arc<Slots>) -> Slot {
+        let mut open = slots.open.lock().expect("Collaboration slots poisoned");
+        while *open >= slots.limit {
+            open = slots.freed.wait(open).expect("Collaboration slots poisoned");
+        }
+        *open += 1;
+
+        Slot(slots.clone())
+    }
+
+}
+
+/// A place among the open connections, given up when it's dropped.
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.0.open.lock().expect("Collaboration slots poisoned");
+        *open -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct Command {
+    command: String,
+    typing: Option<bool>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ViewerDoc<'a> {
+    id: Option<i64>,
+    name: &'a str,
+    typing: bool
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockDoc {
+    state: &'static str,
+    owner: Option<String>,
+    expires: Option<String>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct PresenceMessage<'a> {
+    kind: &'static str,
+    story_id: i64,
+    viewers: Vec<ViewerDoc<'a>>,
+    lock: LockDoc
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ExcerptDoc<'a> {
+    position: i32,
+    content: &'a str,
+    truncated: bool
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockGrantedMessage<'a> {
+    kind: &'static str,
+    expires: String,
+    snippets: Vec<ExcerptDoc<'a>>,
+    prompt: Option<PromptSettings>,
+    constraints: Vec<ConstraintSettings>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ErrorMessage<'a> {
+    kind: &'static str,
+    command: &'a str,
+    reason: &'a str
+}
+
+/// Launch a thread that accepts WebSocket connections, and another that keeps each channel's lock
+/// state current, using connections from `pool`. Once `FICTION_WS_CONNECTIONS` channels are open,
+/// new connections wait to be accepted until one closes.
+pub fn start(pool: PostgresPool) -> FictResult<()> {
+    let address = try!(env_opt::<String>("FICTION_WS_ADDRESS"))
+        .unwrap_or(DEFAULT_ADDRESS.to_owned());
+    let limit = try!(env_opt::<usize>("FICTION_WS_CONNECTIONS"))
+        .unwrap_or(DEFAULT_CONNECTIONS);
+
+    let mut server = try!(Server::bind(&*address));
+    let slots = Arc::new(Slots{ open: Mutex::new(0), freed: Condvar::new(), limit: limit });
+    let rooms: Rooms = Arc::new(Mutex::new(HashMap::new()));
+
+    let refresh_pool = pool.clone();
+    let refresh_rooms = rooms.clone();
+    try!(thread::Builder::new().name("collab-refresh".to_owned()).spawn(move || {
+        refresh(refresh_pool, refresh_rooms);
+    }));
+
+    try!(thread::Builder::new().name("collab".to_owned()).spawn(move || {
+        info!("Accepting up to {} collaboration channels on {}.", limit, address);
+
+        loop {
+            let slot = Slots::claim(&slots);
+            let connection = server.accept();
+            let pool = pool.clone();
+            let rooms = rooms.clone();
+
+            thread::spawn(move || {
+                let _slot = slot;
+
+                let request = match connection {
+                    Ok(connection) => match connection.read_request() {
+                        Ok(request) => request,
+                        Err(e) => {
+                            warn!("Unable to read a collaboration request: {}", e);
+                            return;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Unable to accept a collaboration connection: {}", e);
+                        return;
+                    }
+                };
+
+                let admitted = match request.url {
+                    RequestUri::AbsolutePath(ref path) => {
+                        admit(&pool, path, request.headers.get::<Authorization<Basic>>())
+                    },
+                    _ => Err(fict_err("Unsupported collaboration request URI"))
+                };
+
+                let (story_id, user) = match admitted {
+                    Ok(admitted) => admitted,
+                    Err(e) => {
+                        debug!("Collaboration request refused: {}", e);
+                        let _ = request.fail().send();
+                        return;
+                    }
+                };
+
+                if let Err(e) = request.validate() {
+                    debug!("Invalid collaboration handshake: {}", e);
+                    let _ = request.fail().send();
+                    return;
+                }
+
+                let client = match request.accept().send() {
+                    Ok(client) => client,
+                    Err(e) => {
+                        warn!("Unable to complete a collaboration handshake: {}", e);
+                        return;
+                    }
+                };
+
+                let (mut sender, mut receiver) = client.split();
+                let (outbox, queued) = mpsc::channel::<Message>();
+
+                // Messages for this connection are written from their own thread, so that a slow
+                // client never blocks a broadcast.
+                thread::spawn(move || {
+                    for message in queued.iter() {
+                        if sender.send_message(message).is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+                debug!("{} joined the channel of story {}.", user.name, story_id);
+
+                join(&rooms, story_id, Member{
+                    connection_id: connection_id,
+                    user: user.clone(),
+                    typing: false,
+                    outbox: outbox.clone()
+                });
+                broadcast(&pool, &rooms, story_id);
+
+                for message in receiver.incoming_messages() {
+                    let message: Message = match message {
+                        Ok(message) => message,
+                        Err(_) => break
+                    };
+
+                    // Members who've lost access to the story have already been removed.
+                    if ! is_member(&rooms, story_id, connection_id) {
+                        break;
+                    }
+
+                    match message {
+                        Message::Text(text) => {
+                            perform(&pool, &rooms, story_id, connection_id, &user, &outbox, &text);
+                        },
+                        Message::Ping(data) => {
+                            let _ = outbox.send(Message::Pong(data));
+                        },
+                        Message::Close(_) => {
+                            let _ = outbox.send(Message::Close(None));
+                            break;
+                        },
+                        _ => ()
+                    }
+                }
+
+                debug!("{} left the channel of story {}.", user.name, story_id);
+
+                leave(&rooms, story_id, connection_id);
+                broadcast(&pool, &rooms, story_id);
+            });
+        }
+    }));
+
+    Ok(())
+}
+
+/// Identify the story and authenticated user of a request for a channel at `path`, by its `token`
+/// query parameter or `Authorization` header. Only users who may read the story are admitted.
+fn admit(pool: &PostgresPool, path: &str, auth: Option<&Authorization<Basic>>) -> FictResult<(i64, User)> {
+    let segments: Vec<&str> = path.split('?').next().unwrap_or("").split('/').collect();
+
+    if segments.len() != 4 || segments[0] != "" || segments[1] != "stories" || segments[3] != "collab" {
+        return Err(fict_err(format!("No collaboration channel at {}", path)));
+    }
+
+    let story_id = try!(segments[2].parse::<i64>().map_err(|_| fict_err("Story id must be numeric")));
+
+    let conn = try!(pool.get());
+    let user = try!(try!(request_user(&*conn, path, auth)).ok_or(fict_err("Invalid or missing session")));
+
+    try!(Story::visible_to(&*conn, story_id, &user));
+
+    Ok((story_id, user))
+}
+
+/// Carry out a command sent by the member `connection_id` over a story's channel. Problems are
+/// reported back to the member alone.
+fn perform(pool: &PostgresPool, rooms: &Rooms, story_id: i64, connection_id: usize, user: &User, outbox: &Outbox<Message>, text: &str) {
+    let command = match json::decode::<Command>(text) {
+        Ok(command) => command,
+        Err(_) => {
+            reply_error(outbox, "", "Unable to parse command");
+            return;
+        }
+    };
+
+    let result = match &command.command[..] {
+        "typing" => {
+            set_typing(rooms, story_id, connection_id, command.typing.unwrap_or(false));
+            Ok(())
+        },
+        "acquire_lock" => acquire_lock(pool, story_id, user, outbox),
+        "release_lock" => release_lock(pool, story_id, user),
+        _ => {
+            reply_error(outbox, &command.command, "Unknown command");
+            return;
+        }
+    };
+
+    match result {
+        Ok(()) => broadcast(pool, rooms, story_id),
+        Err(e) => reply_error(outbox, &command.command, e.description())
+    }
+}
+
+/// Acquire a story's lock on behalf of `user`, following the same rules as `POST
+/// /stories/:id/lock`, and send them the same view of the story: as much of it so far as its
+/// visibility policy permits, its prompt, and its constraints.
+fn acquire_lock(pool: &PostgresPool, story_id: i64, user: &User, outbox: &Outbox<Message>) -> FictResult<()> {
+    let conn = try!(pool.get());
+
+    let grant = try!(Story::acquire_lock(&*conn, story_id, user));
+
+    let m = LockGrantedMessage {
+        kind: "lock_granted",
+        expires: timestamp(&grant.expiration),
+        snippets: grant.excerpts.iter().map(|excerpt| ExcerptDoc{
+            position: excerpt.position,
+            content: &excerpt.content,
+            truncated: excerpt.truncated
+        }).collect(),
+        prompt: grant.prompt.as_ref().map(|p| p.settings()),
+        constraints: grant.constraints.iter().map(|c| c.settings()).collect()
+    };
+
+    send(outbox, &json::encode(&m).expect("Unable to encode message JSON"));
+
+    Ok(())
+}
+
+/// Release a story's lock on behalf of `user`, following the same rules as `DELETE
+/// /stories/:id/lock`.
+fn release_lock(pool: &PostgresPool, story_id: i64, user: &User) -> FictResult<()> {
+    let conn = try!(pool.get());
+
+    try!(Story::release_lock(&*conn, story_id, user, UTC::now()));
+
+    Ok(())
+}
+
+/// Add a member to the channel of a story.
+fn join(rooms: &Rooms, story_id: i64, member: Member) {
+    let mut rooms = rooms.lock().expect("Collaboration rooms poisoned");
+    rooms.entry(story_id).or_insert(Vec::new()).push(member);
+}
+
+/// Remove the member `connection_id` from the channel of a story, discarding the channel once it's
+/// empty.
+fn leave(rooms: &Rooms, story_id: i64, connection_id: usize) {
+    let mut rooms = rooms.lock().expect("Collaboration rooms poisoned");
+
+    let empty = match rooms.get_mut(&story_id) {
+        Some(members) => {
+            members.retain(|m| m.connection_id != connection_id);
+            members.is_empty()
+        },
+        None => false
+    };
+
+    if empty {
+        rooms.remove(&story_id);
+    }
+}
+
+/// Determine whether or not the member `connection_id` is still in the channel of a story.
+fn is_member(rooms: &Rooms, story_id: i64, connection_id: usize) -> bool {
+    let rooms = rooms.lock().expect("Collaboration rooms poisoned");
+
+    rooms.get(&story_id)
+        .map(|members| members.iter().any(|m| m.connection_id == connection_id))
+        .unwrap_or(false)
+}
+
+/// Disconnect each member of a story's channel who may no longer read the story.
+fn evict_unauthorized(pool: &PostgresPool, rooms: &Rooms, story_id: i64) -> FictResult<()> {
+    let mut users: Vec<User> = Vec::new();
+    {
+        let rooms = rooms.lock().expect("Collaboration rooms poisoned");
+        for member in rooms.get(&story_id).map(|m| &m[..]).unwrap_or(&[]) {
+            if ! users.iter().any(|u| u.id == member.user.id) {
+                users.push(member.user.clone());
+            }
+        }
+    }
+
+    let conn = try!(pool.get());
+
+    let mut revoked = Vec::new();
+    for user in users.iter() {
+        match Story::visible_to(&*conn, story_id, user) {
+            Ok(..) => (),
+            Err(FictError::NotFound) => revoked.push(user.id),
+            Err(e) => return Err(e)
+        }
+    }
+
+    if revoked.is_empty() {
+        return Ok(());
+    }
+
+    let mut rooms = rooms.lock().expect("Collaboration rooms poisoned");
+
+    let empty = match rooms.get_mut(&story_id) {
+        Some(members) => {
+            for member in members.iter().filter(|m| revoked.contains(&m.user.id)) {
+                debug!("Removing {} from the channel of story {}.", member.user.name, story_id);
+                let _ = member.outbox.send(Message::Close(None));
+            }
+
+            members.retain(|m| ! revoked.contains(&m.user.id));
+            members.is_empty()
+        },
+        None => false
+    };
+
+    if empty {
+        rooms.remove(&story_id);
+    }
+
+    Ok(())
+}
+
+/// Record whether or not the member `connection_id` is typing.
+fn set_typing(rooms: &Rooms, story_id: i64, connection_id: usize, typing: bool) {
+    let mut rooms = rooms.lock().expect("Collaboration rooms poisoned");
+
+    if let Some(members) = rooms.get_mut(&story_id) {
+        for member in members.iter_mut().filter(|m| m.connection_id == connection_id) {
+            member.typing = typing;
+        }
+    }
+}
+
+/// Send a `presence` message describing the channel of a story to each of its members, after
+/// disconnecting any who may no longer read the story. Users connected more than once are listed
+/// once.
+fn broadcast(pool: &PostgresPool, rooms: &Rooms, story_id: i64) {
+    if let Err(e) = evict_unauthorized(pool, rooms, story_id) {
+        error!("Unable to check access to the channel of story {}: {}", story_id, e);
+        return;
+    }
+
+    let lock = match lock_state(pool, story_id) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Unable to determine the lock state of story {}: {}", story_id, e);
+            return;
+        }
+    };
+
+    let rooms = rooms.lock().expect("Collaboration rooms poisoned");
+    let members = match rooms.get(&story_id) {
+        Some(members) => members,
+        None => return
+    };
+
+    let mut viewers: Vec<ViewerDoc> = Vec::new();
+    for member in members.iter() {
+        match viewers.iter().position(|v| v.id == member.user.id) {
+            Some(i) => viewers[i].typing = viewers[i].typing || member.typing,
+            None => viewers.push(ViewerDoc{
+                id: member.user.id,
+                name: &member.user.name,
+                typing: member.typing
+            })
+        }
+    }
+
+    let m = PresenceMessage {
+        kind: "presence",
+        story_id: story_id,
+        viewers: viewers,
+        lock: lock
+    };
+
+    let encoded = json::encode(&m).expect("Unable to encode message JSON");
+
+    for member in members.iter() {
+        send(&member.outbox, &encoded);
+    }
+}
+
+/// Describe who currently holds the lock of a story.
+fn lock_state(pool: &PostgresPool, story_id: i64) -> FictResult<LockDoc> {
+    let conn = try!(pool.get());
+    let story = try!(try!(Story::with_id(&*conn, story_id)).ok_or(fict_err("Story not found")));
+
+    match (story.lock_user_id, story.lock_expiration) {
+        (Some(owner_id), Some(expiration)) if expiration > UTC::now() => {
+            let owner = try!(User::with_id(&*conn, owner_id));
+
+            Ok(LockDoc{
+                state: "locked",
+                owner: Some(owner.name),
+                expires: Some(timestamp(&expiration))
+            })
+        },
+        _ => Ok(LockDoc{ state: "unlocked", owner: None, expires: None })
+    }
+}
+
+/// Rebroadcast the presence of each channel whose story has new activity recorded by anything
+/// other than its channel, like an HTTP request or an expired lock.
+fn refresh(pool: PostgresPool, rooms: Rooms) {
+    let mut last: Option<EventPosition> = None;
+
+    loop {
+        thread::sleep(StdDuration::from_millis(REFRESH_INTERVAL_MS));
+
+        if let Err(e) = refresh_once(&pool, &rooms, &mut last) {
+            error!("Unable to refresh collaboration channels: {}", e);
+        }
+    }
+}
+
+/// Rebroadcast the presence of each channel whose story has had events recorded since the
+/// position `last`, then advance `last` to the most recent event.
+fn refresh_once(pool: &PostgresPool, rooms: &Rooms, last: &mut Option<EventPosition>) -> FictResult<()> {
+    let conn = try!(pool.get());
+    let latest = try!(StoryEvent::latest_position(&*conn));
+
+    if let Some(previous) = *last {
+        if latest != previous {
+            let story_ids: Vec<i64> = {
+                let rooms = rooms.lock().expect("Collaboration rooms poisoned");
+                rooms.keys().cloned().collect()
+            };
+
+            for story_id in story_ids {
+                if ! try!(StoryEvent::for_story(&*conn, story_id, &previous, 1)).is_empty() {
+                    broadcast(pool, rooms, story_id);
+                }
+            }
+        }
+    }
+
+    *last = Some(latest);
+
+    Ok(())
+}
+
+/// Report a problem with a command to the member who sent it.
+fn reply_error(outbox: &Outbox<Message>, command: &str, reason: &str) {
+    let m = ErrorMessage {
+        kind: "error",
+        command: command,
+        reason: reason
+    };
+
+    send(outbox, &json::encode(&m).expect("Unable to encode message JSON"));
+}
+
+/// Queue a text message for a member. Members who have disconnected are ignored.
+fn send(outbox: &Outbox<Message>, text: &str) {
+    let _ = outbox.send(Message::Text(text.to_owned()));
+}
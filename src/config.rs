@@ -0,0 +1,18 @@
+//! Environment-driven configuration helpers.
+
+use std::env;
+use std::str::FromStr;
+
+use error::{FictResult, fict_err};
+
+/// Read and parse an optional environment variable. Produce `Ok(None)` if the variable is unset
+/// and an error if it is present but cannot be parsed.
+pub fn env_opt<T: FromStr>(name: &str) -> FictResult<Option<T>> {
+    match env::var(name) {
+        Ok(value) => value.parse::<T>()
+            .map(Some)
+            .map_err(|_| fict_err(format!("Unable to parse {}: [{}]", name, value))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(From::from(e)),
+    }
+}
@@ -0,0 +1,194 @@
+//! Daily digests of activity across each story that a user can access.
+//!
+//! * `GET /digest` - Summarize the past day's activity across the stories you can access.
+//!
+//! Users who enable the `digest` notification preference are emailed their digest once a day, in
+//! place of individual notifications.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use plugin::Extensible;
+use rustc_serialize::json;
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+
+use model::{Database, Digest, DigestEntry, User};
+use model::{NotificationPreferences, NotificationKind, MailFailure};
+use auth::{AuthUser, RequireUser};
+use mail::{Mail, Transport};
+use notify::{public_url, unsubscribe_url, story_title};
+use error::{FictResult, IntoIronResult};
+use responses::timestamp;
+
+/// Maximum number of digests sent by each run of the scheduler.
+const BATCH_SIZE: i64 = 50;
+
+/// Hours to wait before trying again to send a digest that couldn't be sent.
+const RETRY_DELAY_H: i64 = 1;
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct DigestEntryDoc<'a> {
+    story_id: i64,
+    title: Option<&'a str>,
+    snippet_count: i64
+}
+
+impl<'a> DigestEntryDoc<'a> {
+
+    fn new(entry: &'a DigestEntry) -> DigestEntryDoc<'a> {
+        DigestEntryDoc{
+            story_id: entry.story_id,
+            title: entry.title.as_ref().map(|t| &t[..]),
+            snippet_count: entry.snippet_count
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct AwaitingDoc<'a> {
+    story_id: i64,
+    title: Option<&'a str>,
+    contribution_count: i32
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct DigestDoc<'a> {
+    since: String,
+    until: String,
+    contributions: Vec<DigestEntryDoc<'a>>,
+    publications: Vec<DigestEntryDoc<'a>>,
+    awaiting_turn: Vec<AwaitingDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct DigestResponse<'a> {
+    digest: DigestDoc<'a>
+}
+
+/// `GET /digest` to summarize the past day's activity across each story that you can access: the
+/// snippets contributed to each, the stories published, and the stories that are waiting for you
+/// to write next.
+pub fn show(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("GET /digest [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let now = UTC::now();
+    let digest = try!(Digest::compile(conn, &user, now - Duration::days(1), now).iron());
+
+    let r = DigestResponse {
+        digest: DigestDoc{
+            since: timestamp(&digest.since),
+            until: timestamp(&digest.until),
+            contributions: digest.contributions.iter().map(DigestEntryDoc::new).collect(),
+            publications: digest.publications.iter().map(DigestEntryDoc::new).collect(),
+            awaiting_turn: digest.awaiting_turn.iter().map(|story| AwaitingDoc{
+                story_id: story.id,
+                title: story.title.as_ref().map(|t| &t[..]),
+                contribution_count: story.contribution_count
+            }).collect()
+        }
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// Email a digest to each user who receives them and hasn't been sent one within the past day.
+/// Each digest covers the activity since the previous one, or the past day for a user's first.
+/// Digests with nothing to report aren't sent, but still count as the user's latest. A digest that
+/// can't be sent is recorded as a `MailFailure` and tried again after `RETRY_DELAY_H`, without
+/// holding up anyone else's. Produce the number of digests sent.
+pub fn send_due(conn: &GenericConnection, transport: &mut Transport, now: DateTime<UTC>) -> FictResult<usize> {
+    let public_url = try!(public_url());
+    let mut sent = 0;
+
+    for (user, mut preferences) in try!(NotificationPreferences::digests_due(conn, now, BATCH_SIZE)) {
+        let since = preferences.last_digest_time.unwrap_or(now - Duration::days(1));
+        let digest = try!(Digest::compile(conn, &user, since, now));
+
+        if ! digest.is_empty() {
+            if let Err(e) = transport.send(&compose(&user, &digest, &preferences, &public_url)) {
+                warn!("Unable to send a digest to {}: {}", user.name, e);
+
+                try!(MailFailure::record(conn, &user, &NotificationKind::Digest, None, &format!("{}", e)));
+                try!(preferences.defer_digest(conn, now + Duration::hours(RETRY_DELAY_H)));
+                continue;
+            }
+
+            sent += 1;
+        }
+
+        try!(preferences.record_digest(conn, now));
+    }
+
+    Ok(sent)
+}
+
+/// Write the message that delivers a digest.
+fn compose(recipient: &User, digest: &Digest, preferences: &NotificationPreferences, public_url: &str) -> Mail {
+    let mut body = format!("Hi {},\n\nHere's what happened in your stories since {}.\n", recipient.name, timestamp(&digest.since));
+
+    if ! digest.contributions.is_empty() {
+        body.push_str("\nNew snippets:\n");
+        for entry in digest.contributions.iter() {
+            body.push_str(&format!("* {}: {}\n", entry_title(entry), plural(entry.snippet_count, "snippet")));
+        }
+    }
+
+    if ! digest.publications.is_empty() {
+        body.push_str("\nPublished:\n");
+        for entry in digest.publications.iter() {
+            body.push_str(&format!("* {}, in {}\n", entry_title(entry), plural(entry.snippet_count, "snippet")));
+        }
+    }
+
+    if ! digest.awaiting_turn.is_empty() {
+        body.push_str("\nWaiting for you to write next:\n");
+        for story in digest.awaiting_turn.iter() {
+            body.push_str(&format!("* {}\n", story_title(story)));
+        }
+    }
+
+    let unsubscribe_url = unsubscribe_url(public_url, preferences, &NotificationKind::Digest);
+    body.push_str(&format!("\nTo stop receiving this digest, visit:\n{}\n", unsubscribe_url));
+
+    Mail {
+        to: recipient.email.clone(),
+        subject: "Your daily collaborative fiction digest".to_owned(),
+        body: body,
+        unsubscribe_url: Some(unsubscribe_url)
+    }
+}
+
+/// Name the story of a `DigestEntry` within a message.
+fn entry_title(entry: &DigestEntry) -> String {
+    match entry.title {
+        Some(ref title) => format!("\"{}\"", title),
+        None => format!("Story #{}", entry.story_id)
+    }
+}
+
+/// Describe a count of things, like "1 snippet" or "3 snippets".
+fn plural(count: i64, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+/// Register digest routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut show_chain = Chain::new(show);
+    show_chain.link_before(RequireUser);
+    router.get("/digest", show_chain);
+}
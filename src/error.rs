@@ -9,12 +9,43 @@ use log;
 use postgres;
 use r2d2;
 use hyper;
+use websocket;
+use lettre;
 use iron::status::{self, Status};
 use iron::{IronError, IronResult};
 use rustc_serialize;
+use rustc_serialize::json;
 use chrono::{DateTime, UTC};
 
-use error::FictError::{Message, Cause, NotFound, Unlocked, Cooldown, AlreadyLocked};
+use error::FictError::{Message, Cause, NotFound, Unlocked, Cooldown, AlreadyLocked, Unavailable, Invalid, Forbidden, EditClosed, NotMostRecent, NotYourTurn, Finished, Forked};
+use error::FictError::{VotingOnly, NoVoting, Duplicate, OwnCandidate, Scheduled, InviteUnusable};
+use error::FictError::Unrevealed;
+
+/// A problem with a single field of a request document, reported so that clients can display it
+/// alongside the offending input.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String
+}
+
+impl FieldError {
+
+    pub fn new<F: Into<String>, C: Into<String>, M: Into<String>>(field: F, code: C, message: M) -> FieldError {
+        FieldError{
+            field: field.into(),
+            code: code.into(),
+            message: message.into()
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct FieldErrorsResponse<'a> {
+    errors: &'a [FieldError]
+}
 
 /// An Error type that can be used throughout the application. It can provide its own error message
 /// or wrap an underlying error of a different type.
@@ -26,7 +57,22 @@ pub enum FictError {
     NotFound,
     Unlocked,
     Cooldown,
-    AlreadyLocked { username: String, expiration: DateTime<UTC> }
+    AlreadyLocked { username: String, expiration: DateTime<UTC> },
+    NotYourTurn { username: String },
+    Unavailable,
+    Invalid(Vec<FieldError>),
+    Forbidden,
+    EditClosed,
+    NotMostRecent,
+    Finished,
+    Forked,
+    VotingOnly,
+    NoVoting,
+    Duplicate,
+    OwnCandidate,
+    Scheduled,
+    InviteUnusable,
+    Unrevealed
 }
 
 impl FictError {
@@ -34,14 +80,36 @@ impl FictError {
     pub fn preferred_status(&self) -> Status {
         match *self {
             NotFound => status::NotFound,
-            Unlocked | Cooldown | AlreadyLocked {..} => status::Unauthorized,
+            Forbidden | OwnCandidate | Unrevealed => status::Forbidden,
+            EditClosed | NotMostRecent | Finished | Forked => status::Conflict,
+            VotingOnly | NoVoting | Duplicate | Scheduled => status::Conflict,
+            Unlocked | Cooldown | AlreadyLocked {..} | NotYourTurn {..} => status::Unauthorized,
+            InviteUnusable => status::Gone,
+            Unavailable => status::ServiceUnavailable,
+            Invalid(..) => status::UnprocessableEntity,
             _ => status::InternalServerError
         }
     }
 
     /// Consume the error to produce an IronError with a custom HTTP status code.
     pub fn to_iron_error(self, status: Status) -> IronError {
-        IronError::new(self, status)
+        match self.body() {
+            Some(body) => IronError::new(self, (status, body)),
+            None => IronError::new(self, status),
+        }
+    }
+
+    /// JSON document to send to the client along with the status code, for errors that carry
+    /// details that a client can act upon.
+    fn body(&self) -> Option<String> {
+        match *self {
+            Invalid(ref errors) => {
+                let r = FieldErrorsResponse{ errors: errors };
+
+                Some(json::encode(&r).expect("Unable to encode response JSON"))
+            },
+            _ => None
+        }
     }
 }
 
@@ -51,9 +119,24 @@ impl Error for FictError {
             Message(ref s) => s,
             Cause(ref e) => e.description(),
             NotFound => "Resource not found",
+            Forbidden => "Insufficient access",
+            EditClosed => "Snippet may no longer be edited",
+            NotMostRecent => "Only the most recent snippet may be retracted",
+            Finished => "Story has finished",
+            Forked => "Snippet is shared with a fork",
+            VotingOnly => "Story chooses its snippets by vote",
+            NoVoting => "Story does not choose its snippets by vote",
+            Duplicate => "Already submitted",
+            OwnCandidate => "You may not vote for your own candidate",
+            Scheduled => "Story's lock rotates on a schedule",
+            InviteUnusable => "Invite has expired, been revoked, or been used up",
+            Unrevealed => "Story text has not been revealed yet",
             Unlocked => "Resource not locked",
             Cooldown => "Last contribution too recent",
-            AlreadyLocked {..} => "Unable to acquire a lock"
+            AlreadyLocked {..} => "Unable to acquire a lock",
+            NotYourTurn {..} => "Another writer is next",
+            Unavailable => "Service temporarily unavailable",
+            Invalid(..) => "Validation failed"
         }
     }
 
@@ -90,6 +173,8 @@ impl NonFictError for postgres::error::ConnectError {}
 impl NonFictError for r2d2::InitializationError {}
 impl NonFictError for r2d2::GetTimeout {}
 impl NonFictError for hyper::Error {}
+impl NonFictError for websocket::result::WebSocketError {}
+impl NonFictError for lettre::transport::smtp::error::Error {}
 impl NonFictError for rustc_serialize::json::DecoderError {}
 impl NonFictError for rustc_serialize::json::EncoderError {}
 impl NonFictError for rustc_serialize::json::ParserError {}
@@ -0,0 +1,294 @@
+//! Server-Sent Event streams of story activity, served alongside the HTTP API.
+//!
+//! * `GET /stories/:id/events` - Follow activity within the story :id.
+//! * `GET /events` - Follow activity within every story you've been granted access to.
+//!
+//! Streams are served on `FICTION_SSE_ADDRESS` by a pool of `FICTION_SSE_THREADS` threads of
+//! their own, so that open streams never hold up API requests. Authenticate with the same
+//! `Authorization` header used for API requests or, because `EventSource` can't set headers, by
+//! passing your session token as the `token` query parameter.
+//!
+//! Each event is sent with its position in the log as its `id`, so clients that reconnect with a
+//! `Last-Event-ID` header resume where they left off. Streams close after a while to release
+//! their thread; `EventSource` clients reconnect and resume automatically.
+
+use std::io::{self, Write};
+use std::str;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use hyper::server::{Server, Handler, Request, Response};
+use hyper::header::{Headers, Authorization, Basic, ContentType, CacheControl, CacheDirective, Connection};
+use hyper::method::Method;
+use hyper::mime::Mime;
+use hyper::net::Fresh;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::json;
+use postgres::GenericConnection;
+use chrono::UTC;
+use chrono::duration::Duration;
+
+use config::env_opt;
+use auth::request_user;
+use model::{PostgresPool, Story, StoryEvent, EventPosition, User};
+use error::{FictResult, FictError};
+use responses::EventDoc;
+
+/// Address to listen on when `FICTION_SSE_ADDRESS` is unset.
+const DEFAULT_ADDRESS: &'static str = "localhost:3002";
+
+/// Number of streams that may be open at once when `FICTION_SSE_THREADS` is unset.
+const DEFAULT_THREADS: usize = 64;
+
+/// Seconds that a single stream stays open before the client is asked to reconnect.
+const STREAM_DURATION_S: i64 = 60;
+
+/// Milliseconds to wait between checks for new events.
+const POLL_INTERVAL_MS: u64 = 1000;
+
+/// Milliseconds that clients should wait before reconnecting to a closed stream.
+const RETRY_MS: u64 = 1000;
+
+/// Maximum number of events sent from a single check.
+const BATCH_SIZE: i64 = 100;
+
+/// Streams that may be requested, by path.
+enum Target {
+    Story(i64),
+    Feed
+}
+
+impl Target {
+
+    /// Find the stream requested by the path of a request, ignoring its query string.
+    fn from_path(path: &str) -> Option<Target> {
+        let path = path.splitn(2, '?').next().unwrap_or("");
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        if segments.len() == 1 && segments[0] == "events" {
+            return Some(Target::Feed);
+        }
+
+        if segments.len() == 3 && segments[0] == "stories" && segments[2] == "events" {
+            return segments[1].parse().ok().map(Target::Story);
+        }
+
+        None
+    }
+
+}
+
+/// Events that a stream follows.
+enum Source {
+    Story(i64, User),
+    User(User)
+}
+
+/// A stream of each new event matching its `Source` as it's recorded, until `STREAM_DURATION_S`
+/// elapses, the client disconnects, or the user loses access to the story.
+struct EventStream<'a> {
+    pool: &'a PostgresPool,
+    source: Source,
+    last: EventPosition
+}
+
+impl<'a> EventStream<'a> {
+
+    /// Retrieve the next batch of events after the last one sent. Access is checked again for each
+    /// batch: produce `None` if the user may no longer read the story that the stream follows.
+    /// Feeds only ever include stories that the user may read at the time of each check.
+    fn next_batch(&self) -> FictResult<Option<Vec<StoryEvent>>> {
+        let conn = try!(self.pool.get());
+
+        match self.source {
+            Source::Story(story_id, ref user) => {
+                match Story::visible_to(&*conn, story_id, user) {
+                    Ok(..) => {},
+                    Err(FictError::NotFound) => return Ok(None),
+                    Err(e) => return Err(e)
+                }
+
+                StoryEvent::for_story(&*conn, story_id, &self.last, BATCH_SIZE).map(Some)
+            },
+            Source::User(ref user) => StoryEvent::for_user(&*conn, user, &self.last, BATCH_SIZE).map(Some)
+        }
+    }
+
+    /// Write events to `out` as they're recorded.
+    fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let deadline = UTC::now() + Duration::seconds(STREAM_DURATION_S);
+
+        try!(write!(out, "retry: {}\n\n", RETRY_MS));
+        try!(out.flush());
+
+        while UTC::now() < deadline {
+            let batch = try!(self.next_batch().map_err(|e| {
+                error!("Unable to retrieve events: {}", e);
+                io::Error::new(io::ErrorKind::Other, format!("{}", e))
+            }));
+
+            let events = match batch {
+                Some(events) => events,
+                None => {
+                    debug!("Closing an event stream after its user lost access.");
+                    return Ok(());
+                }
+            };
+
+            if events.is_empty() {
+                // Comments keep intermediaries from closing an idle connection, and reveal
+                // clients that have gone away.
+                try!(write!(out, ": idle\n\n"));
+            }
+
+            for event in events.iter() {
+                let data = json::encode(&EventDoc::new(event))
+                    .expect("Unable to encode event JSON");
+
+                try!(write!(out, "id: {}\nevent: {}\ndata: {}\n\n", event.position(), event.kind.name(), data));
+                self.last = event.position();
+            }
+
+            try!(out.flush());
+
+            if events.len() < BATCH_SIZE as usize {
+                thread::sleep(StdDuration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+/// Handler for each request made to the event stream server.
+struct Streams {
+    pool: PostgresPool
+}
+
+impl Handler for Streams {
+
+    fn handle(&self, req: Request, mut res: Response<Fresh>) {
+        res.headers_mut().set(Connection::close());
+
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => return refuse(res, StatusCode::BadRequest, "Unsupported request URI")
+        };
+
+        if req.method != Method::Get {
+            return refuse(res, StatusCode::MethodNotAllowed, "Event streams only support GET");
+        }
+
+        let target = match Target::from_path(&path) {
+            Some(target) => target,
+            None => return refuse(res, StatusCode::NotFound, "No such event stream")
+        };
+
+        let (source, last) = match open(&self.pool, target, &path, &req.headers) {
+            Ok(Some(opened)) => opened,
+            Ok(None) => return refuse(res, StatusCode::Unauthorized, "Authentication is required"),
+            Err(FictError::NotFound) => return refuse(res, StatusCode::NotFound, "Story not found"),
+            Err(e) => {
+                error!("Unable to open an event stream: {}", e);
+                return refuse(res, StatusCode::InternalServerError, "Unable to open the event stream");
+            }
+        };
+
+        let mime: Mime = "text/event-stream".parse().expect("Invalid event stream MIME type");
+        res.headers_mut().set(ContentType(mime));
+        res.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
+
+        let mut out = match res.start() {
+            Ok(out) => out,
+            Err(e) => {
+                debug!("Unable to begin an event stream: {}", e);
+                return;
+            }
+        };
+
+        let mut stream = EventStream {
+            pool: &self.pool,
+            source: source,
+            last: last
+        };
+
+        if let Err(e) = stream.write_to(&mut out) {
+            debug!("Event stream closed: {}", e);
+            return;
+        }
+
+        let _ = out.end();
+    }
+
+}
+
+/// Authenticate a request for an event stream and determine where it begins. Produce `Ok(None)`
+/// if the request has no valid session token. A story's stream requires read access to the story.
+fn open(pool: &PostgresPool, target: Target, path: &str, headers: &Headers) -> FictResult<Option<(Source, EventPosition)>> {
+    let conn = try!(pool.get());
+
+    let user = match try!(request_user(&*conn, path, headers.get::<Authorization<Basic>>())) {
+        Some(user) => user,
+        None => return Ok(None)
+    };
+
+    let source = match target {
+        Target::Story(story_id) => {
+            try!(Story::visible_to(&*conn, story_id, &user));
+            Source::Story(story_id, user)
+        },
+        Target::Feed => Source::User(user)
+    };
+
+    let last = try!(resume_from(headers, &*conn));
+
+    Ok(Some((source, last)))
+}
+
+/// Determine the position of the last event that the client has seen: the `Last-Event-ID` header
+/// of a reconnecting client, or the most recent event for a new one.
+fn resume_from(headers: &Headers, conn: &GenericConnection) -> FictResult<EventPosition> {
+    let header = headers.get_raw("Last-Event-ID")
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(EventPosition::parse);
+
+    match header {
+        Some(position) => Ok(position),
+        None => StoryEvent::latest_position(conn)
+    }
+}
+
+/// Respond to a request that won't be streamed with a status and a short explanation.
+fn refuse(mut res: Response<Fresh>, status: StatusCode, reason: &str) {
+    *res.status_mut() = status;
+
+    if let Err(e) = res.send(reason.as_bytes()) {
+        debug!("Unable to refuse an event stream request: {}", e);
+    }
+}
+
+/// Launch a server that streams events to clients on its own threads, using connections from
+/// `pool`.
+pub fn start(pool: PostgresPool) -> FictResult<()> {
+    let address = try!(env_opt::<String>("FICTION_SSE_ADDRESS"))
+        .unwrap_or(DEFAULT_ADDRESS.to_owned());
+    let threads = try!(env_opt::<usize>("FICTION_SSE_THREADS"))
+        .unwrap_or(DEFAULT_THREADS);
+
+    let server = try!(Server::http(&*address));
+
+    try!(thread::Builder::new().name("events".to_owned()).spawn(move || {
+        match server.handle_threads(Streams { pool: pool }, threads) {
+            Ok(_listening) => {
+                // The listener blocks this thread until the server shuts down when it's dropped.
+                info!("Streaming events on {} with {} threads.", address, threads);
+            },
+            Err(e) => error!("Unable to stream events: {}", e)
+        }
+    }));
+
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! Story export routes.
+//!
+//! * `GET /stories/:id/export` - Download the full text of the story :id as a document.
+//!
+//! Exports are rendered as Markdown or plain text, chosen with the `format` query parameter.
+//! Setting `attribution` or `timestamps` to `true` credits each snippet to its author or notes when
+//! it was written.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use hyper::header::ContentType;
+use hyper::mime::Mime;
+use router::Router;
+use plugin::Extensible;
+
+use model::{Database, Story, Snippet};
+use auth::{AuthUser, RequireUser};
+use error::{IntoIronResult, FieldError};
+use error::FictError::{Invalid, Unrevealed};
+use responses::timestamp;
+use params;
+
+/// Document formats that a story may be exported to.
+enum Format {
+    Markdown,
+    Text
+}
+
+impl Format {
+
+    /// Find the format called `name` by the `format` query parameter.
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "markdown" => Some(Format::Markdown),
+            "text" => Some(Format::Text),
+            _ => None
+        }
+    }
+
+    /// MIME type of documents in this format.
+    fn mime(&self) -> &'static str {
+        match *self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Text => "text/plain; charset=utf-8"
+        }
+    }
+
+    /// File extension of documents in this format.
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Markdown => "md",
+            Format::Text => "txt"
+        }
+    }
+
+}
+
+/// `GET /stories/:id/export` to download the full text of a story that you can read, oldest
+/// snippet first. Set `format` to `markdown`, the default, or `text`. Like
+/// `GET /stories/:id/snippets`, the story's reveal policy must allow you to read its full text.
+pub fn export(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let format_name = params::query(req, "format").unwrap_or("markdown".to_owned());
+    let attribution = params::query(req, "attribution").map(|v| v == "true").unwrap_or(false);
+    let timestamps = params::query(req, "timestamps").map(|v| v == "true").unwrap_or(false);
+
+    debug!("GET /stories/{}/export?format={} [{}]", story_id, format_name, user.name);
+
+    let format = match Format::from_name(&format_name) {
+        Some(format) => format,
+        None => {
+            let errors = vec![FieldError::new(
+                "format", "unknown", "Format must be one of: markdown, text."
+            )];
+            return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+        }
+    };
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! story.reveal.reveals(&story, &access) {
+        return Err(Unrevealed.to_iron_error(status::Forbidden));
+    }
+
+    let snippets = try!(Snippet::for_story(conn, &story).iron());
+
+    let mut authors = HashMap::new();
+    if attribution {
+        for contributor in try!(story.contributors(conn).iron()) {
+            if let Some(id) = contributor.id {
+                authors.insert(id, contributor.name);
+            }
+        }
+    }
+
+    let document = render(&format, &story, &snippets, &authors, attribution, timestamps);
+
+    let mime: Mime = format.mime().parse().expect("Invalid export MIME type");
+    let disposition = format!("attachment; filename=\"{}.{}\"", file_name(&story), format.extension());
+
+    let mut res = Response::with((status::Ok, document));
+    res.headers.set(ContentType(mime));
+    res.headers.set_raw("Content-Disposition", vec![disposition.into_bytes()]);
+
+    Ok(res)
+}
+
+/// Render the title and snippets of a story as a document. `authors` maps the ID of each
+/// contributor to their name, if `attribution` is requested.
+fn render(format: &Format, story: &Story, snippets: &[Snippet], authors: &HashMap<i64, String>, attribution: bool, timestamps: bool) -> String {
+    let title = match story.title {
+        Some(ref title) => title.clone(),
+        None => format!("Story #{}", story.id)
+    };
+
+    let mut document = match *format {
+        Format::Markdown => format!("# {}\n", title),
+        Format::Text => format!("{}\n{}\n", title, repeat("=", title.chars().count()))
+    };
+
+    for snippet in snippets.iter() {
+        document.push_str("\n");
+        document.push_str(snippet.content.trim_right());
+        document.push_str("\n");
+
+        let mut credits = Vec::new();
+        if attribution {
+            credits.push(authors.get(&snippet.user_id).cloned().unwrap_or("Unknown author".to_owned()));
+        }
+        if timestamps {
+            credits.push(timestamp(&snippet.creation_time));
+        }
+
+        if ! credits.is_empty() {
+            let credit = credits.join(", ");
+
+            match *format {
+                Format::Markdown => document.push_str(&format!("\n*— {}*\n", credit)),
+                Format::Text => document.push_str(&format!("\n-- {}\n", credit))
+            }
+        }
+    }
+
+    document
+}
+
+/// Choose the name of a story's exported file, without its extension: its title, reduced to
+/// lowercase ASCII letters, digits, and hyphens, or `story-{id}` if that leaves nothing.
+fn file_name(story: &Story) -> String {
+    let title = story.title.as_ref().map(|t| &t[..]).unwrap_or("");
+
+    let mut name = String::new();
+    for c in title.chars() {
+        if c.is_ascii() && c.is_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if ! name.is_empty() && ! name.ends_with('-') {
+            name.push('-');
+        }
+    }
+
+    let name = name.trim_right_matches('-').to_owned();
+
+    if name.is_empty() {
+        format!("story-{}", story.id)
+    } else {
+        name
+    }
+}
+
+/// Repeat a string `count` times.
+fn repeat(s: &str, count: usize) -> String {
+    (0..count).map(|_| s).collect()
+}
+
+/// Register story export routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut export_chain = Chain::new(export);
+    export_chain.link_before(RequireUser);
+    router.get("/stories/:id/export", export_chain);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{UTC, TimeZone};
+
+    use model::{Story, Snippet};
+    use responses::timestamp;
+    use super::{Format, render, file_name};
+
+    /// Construct an unsaved snippet of `content` by the user with ID `user_id`.
+    fn snippet(ordinal: i32, user_id: i64, content: &str) -> Snippet {
+        Snippet {
+            id: ordinal as i64,
+            ordinal: ordinal,
+            position: ordinal,
+            user_id: user_id,
+            story_id: 1,
+            creation_time: UTC.ymd(2016, 3, 1).and_hms(12, 30, 0),
+            edit_time: None,
+            content: content.to_owned()
+        }
+    }
+
+    #[test]
+    fn file_name_reduces_the_title() {
+        assert_eq!(file_name(&Story::sample(1, Some("The Long  Night!"))), "the-long-night");
+        assert_eq!(file_name(&Story::sample(1, Some("  Déjà vu, 2nd ed. "))), "d-j-vu-2nd-ed");
+    }
+
+    #[test]
+    fn file_name_falls_back_to_the_id() {
+        assert_eq!(file_name(&Story::sample(7, None)), "story-7");
+        assert_eq!(file_name(&Story::sample(7, Some("???"))), "story-7");
+    }
+
+    #[test]
+    fn render_markdown() {
+        let story = Story::sample(1, Some("Tale"));
+        let snippets = vec![snippet(1, 10, "Once upon a time.\n"), snippet(2, 11, "The end.")];
+
+        let document = render(&Format::Markdown, &story, &snippets, &HashMap::new(), false, false);
+
+        assert_eq!(document, "# Tale\n\nOnce upon a time.\n\nThe end.\n");
+    }
+
+    #[test]
+    fn render_text_underlines_the_title() {
+        let story = Story::sample(3, None);
+        let snippets = vec![snippet(1, 10, "Once upon a time.")];
+
+        let document = render(&Format::Text, &story, &snippets, &HashMap::new(), false, false);
+
+        assert_eq!(document, "Story #3\n========\n\nOnce upon a time.\n");
+    }
+
+    #[test]
+    fn render_credits_authors_and_times() {
+        let story = Story::sample(1, Some("Tale"));
+        let snippets = vec![snippet(1, 10, "Once upon a time."), snippet(2, 11, "The end.")];
+        let mut authors = HashMap::new();
+        authors.insert(10, "alice".to_owned());
+        let written = timestamp(&snippets[0].creation_time);
+
+        let markdown = render(&Format::Markdown, &story, &snippets, &authors, true, true);
+        assert_eq!(markdown, format!(
+            "# Tale\n\nOnce upon a time.\n\n*— alice, {0}*\n\nThe end.\n\n*— Unknown author, {0}*\n",
+            written
+        ));
+
+        let text = render(&Format::Text, &story, &snippets[..1], &authors, true, false);
+        assert_eq!(text, "Tale\n====\n\nOnce upon a time.\n\n-- alice\n");
+    }
+}
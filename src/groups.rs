@@ -0,0 +1,354 @@
+//! Group routes.
+//!
+//! * `POST /groups` - Create a new group that you own.
+//! * `GET /groups` - List the groups that you belong to.
+//! * `GET /groups/:id/members` - List the members of the group :id.
+//! * `PUT /groups/:id/members/:user_id` - Add a user to the group :id.
+//! * `DELETE /groups/:id/members/:user_id` - Remove a user from the group :id.
+//! * `GET /stories/:id/groups` - List the groups granted access to the story :id.
+//! * `PUT /stories/:id/groups/:group_id` - Grant a group access to the story :id.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+use postgres::GenericConnection;
+
+use model::{Database, Story, Group, AccessLevel, User, normalize};
+use auth::{AuthUser, RequireUser};
+use error::{IntoIronResult, FieldError};
+use error::FictError::{NotFound, Forbidden, Invalid};
+use responses::timestamp;
+use params;
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CreationBody {
+    group: GroupSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct GroupSettingsBody {
+    name: String
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct GrantBody {
+    grant: GrantSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct GrantSettingsBody {
+    access_level: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct GroupDoc<'a> {
+    id: i64,
+    name: &'a str,
+    owner_id: i64,
+    creation_time: String
+}
+
+impl<'a> GroupDoc<'a> {
+
+    fn new(group: &'a Group) -> GroupDoc<'a> {
+        GroupDoc{
+            id: group.id,
+            name: &group.name,
+            owner_id: group.owner_id,
+            creation_time: timestamp(&group.creation_time)
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct MemberDoc<'a> {
+    id: Option<i64>,
+    name: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct GroupGrantDoc<'a> {
+    group: GroupDoc<'a>,
+    access_level: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct GroupResponse<'a> {
+    group: GroupDoc<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct GroupsResponse<'a> {
+    groups: Vec<GroupDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct MembersResponse<'a> {
+    group: GroupDoc<'a>,
+    members: Vec<MemberDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct GroupGrantsResponse<'a> {
+    grants: Vec<GroupGrantDoc<'a>>
+}
+
+/// `POST /groups` to create a new group of users. You own the group and become its first member.
+pub fn create(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let body = try!(params::body::<CreationBody>(req)).group;
+
+    debug!("POST /groups [{}]", user.name);
+
+    let name = normalize(&body.name).trim().to_owned();
+    if name.is_empty() {
+        let errors = vec![FieldError::new("group.name", "blank", "Name may not be blank.")];
+        return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+    }
+
+    let ref conn = *try!(Database::connection(req));
+
+    let group = try!(Group::create(conn, &user, name).iron());
+
+    debug!(".. Created group {}.", group.id);
+
+    let r = GroupResponse {
+        group: GroupDoc::new(&group)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `GET /groups` to list each group that you belong to, including those that you own.
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("GET /groups [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let groups = try!(Group::for_user(conn, &user).iron());
+
+    let r = GroupsResponse {
+        groups: groups.iter().map(GroupDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `GET /groups/:id/members` to list the members of a group that you belong to.
+pub fn members(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let group_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /groups/{}/members [{}]", group_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let group = try!(try!(Group::with_id(conn, group_id).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    if ! try!(group.has_member(conn, &user).iron()) {
+        return Err(NotFound.to_iron_error(status::NotFound));
+    }
+
+    let members = try!(group.members(conn).iron());
+
+    let r = MembersResponse {
+        group: GroupDoc::new(&group),
+        members: members.iter().map(|member| MemberDoc{
+            id: member.id,
+            name: &member.name
+        }).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /groups/:id/members/:user_id` to add a user to a group that you own. They immediately gain
+/// any access that's been granted to the group.
+pub fn add_member(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let group_id = try!(params::numeric(req, "id"));
+    let member_id = try!(params::numeric(req, "user_id"));
+
+    debug!("PUT /groups/{}/members/{} [{}]", group_id, member_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let group = try!(owned_group(conn, group_id, &user));
+    let member = try!(User::with_id(conn, member_id).iron());
+
+    try!(group.add_member(conn, &member).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `DELETE /groups/:id/members/:user_id` to remove a user from a group that you own, or to leave a
+/// group that you belong to. A group's owner may not leave it.
+pub fn remove_member(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let group_id = try!(params::numeric(req, "id"));
+    let member_id = try!(params::numeric(req, "user_id"));
+
+    debug!("DELETE /groups/{}/members/{} [{}]", group_id, member_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let group = if user.id == Some(member_id) {
+        try!(try!(Group::with_id(conn, group_id).iron())
+            .ok_or(NotFound.to_iron_error(status::NotFound)))
+    } else {
+        try!(owned_group(conn, group_id, &user))
+    };
+
+    let member = try!(User::with_id(conn, member_id).iron());
+
+    try!(group.remove_member(conn, &member).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `GET /stories/:id/groups` to list the groups that have been granted access to a story that you
+/// own.
+pub fn story_grants(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/groups [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let grants = try!(Group::grants_for_story(conn, &story).iron());
+
+    let r = GroupGrantsResponse {
+        grants: grants.iter().map(|grant| GroupGrantDoc{
+            group: GroupDoc::new(&grant.group),
+            access_level: grant.access_level.name()
+        }).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /stories/:id/groups/:group_id` to grant each member of a group an access level on a story
+/// that you own, if you own or belong to the group. Members keep the highest of the levels granted
+/// to them individually and through each of their groups. An access level of `none` removes the
+/// group's access.
+pub fn grant(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let group_id = try!(params::numeric(req, "group_id"));
+    let body = try!(params::body::<GrantBody>(req)).grant;
+
+    debug!("PUT /stories/{}/groups/{} [{}]", story_id, group_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let level = match AccessLevel::from_name(&body.access_level) {
+        Some(level) => level,
+        None => {
+            let errors = vec![FieldError::new(
+                "grant.access_level", "unknown",
+                "Access level must be one of: none, reader, writer, owner."
+            )];
+            return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+        }
+    };
+
+    let group = try!(try!(Group::with_id(conn, group_id).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    // Whoever owns a group controls its membership, so access may only be handed to groups that
+    // the granter owns or belongs to. Any group's access may be removed.
+    if level.grants_read() && ! group.is_owned_by(&user) && ! try!(group.has_member(conn, &user).iron()) {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    try!(group.grant(conn, &story, &level).iron());
+
+    debug!(".. Granted group {} {} access.", group.id, level.name());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// Load the group `id`, ensuring that it's owned by `user`.
+fn owned_group(conn: &GenericConnection, id: i64, user: &User) -> IronResult<Group> {
+    let group = try!(try!(Group::with_id(conn, id).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    if group.is_owned_by(user) {
+        Ok(group)
+    } else {
+        Err(Forbidden.to_iron_error(status::Forbidden))
+    }
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register group routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut create_chain = Chain::new(create);
+    create_chain.link_before(RequireUser);
+    create_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/groups", create_chain);
+
+    let mut list_chain = Chain::new(list);
+    list_chain.link_before(RequireUser);
+    router.get("/groups", list_chain);
+
+    let mut members_chain = Chain::new(members);
+    members_chain.link_before(RequireUser);
+    router.get("/groups/:id/members", members_chain);
+
+    let mut add_member_chain = Chain::new(add_member);
+    add_member_chain.link_before(RequireUser);
+    router.put("/groups/:id/members/:user_id", add_member_chain);
+
+    let mut remove_member_chain = Chain::new(remove_member);
+    remove_member_chain.link_before(RequireUser);
+    router.delete("/groups/:id/members/:user_id", remove_member_chain);
+
+    let mut story_grants_chain = Chain::new(story_grants);
+    story_grants_chain.link_before(RequireUser);
+    router.get("/stories/:id/groups", story_grants_chain);
+
+    let mut grant_chain = Chain::new(grant);
+    grant_chain.link_before(RequireUser);
+    grant_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/stories/:id/groups/:group_id", grant_chain);
+}
@@ -0,0 +1,241 @@
+//! Invite routes.
+//!
+//! * `POST /stories/:id/invites` - Create an invite to the story :id.
+//! * `GET /stories/:id/invites` - List the invites to the story :id.
+//! * `DELETE /stories/:id/invites/:invite_id` - Revoke an invite to the story :id.
+//! * `POST /invites/:token` - Redeem an invite.
+//!
+//! Users who haven't logged in yet may redeem an invite by beginning the OAuth flow with an
+//! `invite` query parameter, like `/auth/github?invite=:token`.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+use chrono::UTC;
+use chrono::duration::Duration;
+
+use model::{Database, Story, Invite, AccessLevel};
+use auth::{AuthUser, RequireUser};
+use error::{IntoIronResult, FieldError};
+use error::FictError::{Forbidden, Invalid};
+use responses::{timestamp, StoryDoc, StoryResponse};
+use params;
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CreationBody {
+    invite: InviteSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct InviteSettingsBody {
+    access_level: String,
+    expires_in_s: Option<i64>,
+    max_uses: Option<i32>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct InviteDoc<'a> {
+    id: i64,
+    story_id: i64,
+    token: &'a str,
+    access_level: &'a str,
+    creation_time: String,
+    expiration: Option<String>,
+    max_uses: Option<i32>,
+    use_count: i32,
+    revoked: bool,
+    usable: bool
+}
+
+impl<'a> InviteDoc<'a> {
+
+    fn new(invite: &'a Invite) -> InviteDoc<'a> {
+        InviteDoc{
+            id: invite.id,
+            story_id: invite.story_id,
+            token: &invite.token,
+            access_level: invite.access_level.name(),
+            creation_time: timestamp(&invite.creation_time),
+            expiration: invite.expiration.as_ref().map(timestamp),
+            max_uses: invite.max_uses,
+            use_count: invite.use_count,
+            revoked: invite.revoked,
+            usable: invite.is_usable(UTC::now())
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct InviteResponse<'a> {
+    invite: InviteDoc<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct InvitesResponse<'a> {
+    invites: Vec<InviteDoc<'a>>
+}
+
+/// `POST /stories/:id/invites` to create a shareable invite that grants a chosen access level on
+/// a story that you own. Invites may expire after `expires_in_s` seconds, or after `max_uses`
+/// distinct users redeem them.
+pub fn create(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<CreationBody>(req)).invite;
+
+    debug!("POST /stories/{}/invites [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let mut errors = Vec::new();
+
+    let level = match AccessLevel::from_name(&body.access_level) {
+        Some(AccessLevel::NoAccess) | None => {
+            errors.push(FieldError::new(
+                "invite.access_level", "unknown",
+                "Access level must be one of: reader, writer, owner."
+            ));
+            None
+        },
+        Some(level) => Some(level)
+    };
+
+    if body.expires_in_s.map(|s| s <= 0).unwrap_or(false) {
+        errors.push(FieldError::new(
+            "invite.expires_in_s", "not_positive", "Expiration must be positive."
+        ));
+    }
+
+    if body.max_uses.map(|n| n <= 0).unwrap_or(false) {
+        errors.push(FieldError::new(
+            "invite.max_uses", "not_positive", "Maximum uses must be positive."
+        ));
+    }
+
+    let level = match level {
+        Some(level) if errors.is_empty() => level,
+        _ => return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity))
+    };
+
+    let expiration = body.expires_in_s.map(|s| UTC::now() + Duration::seconds(s));
+    let invite = try!(Invite::create(conn, &story, &user, level, expiration, body.max_uses).iron());
+
+    debug!(".. Created invite {}.", invite.id);
+
+    let r = InviteResponse {
+        invite: InviteDoc::new(&invite)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `GET /stories/:id/invites` to list every invite to a story that you own, including those that
+/// may no longer be redeemed.
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/invites [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let invites = try!(Invite::for_story(conn, &story).iron());
+
+    let r = InvitesResponse {
+        invites: invites.iter().map(InviteDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `DELETE /stories/:id/invites/:invite_id` to prevent an invite to a story that you own from
+/// being redeemed again. Access that it has already granted is unaffected.
+pub fn revoke(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let invite_id = try!(params::numeric(req, "invite_id"));
+
+    debug!("DELETE /stories/{}/invites/{} [{}]", story_id, invite_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    try!(Invite::revoke(conn, &story, invite_id).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `POST /invites/:token` to redeem an invite that you've been sent, gaining access to its story.
+pub fn redeem(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let token = req.extensions().get::<Router>()
+        .expect("No route parameters")["token"].to_owned();
+
+    debug!("POST /invites/.. [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let story = try!(Invite::redeem(conn, &token, &user).iron());
+
+    debug!(".. Redeemed an invite to story {}.", story.id);
+
+    let r = StoryResponse {
+        story: StoryDoc::new(&story)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register invite routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut create_chain = Chain::new(create);
+    create_chain.link_before(RequireUser);
+    create_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories/:id/invites", create_chain);
+
+    let mut list_chain = Chain::new(list);
+    list_chain.link_before(RequireUser);
+    router.get("/stories/:id/invites", list_chain);
+
+    let mut revoke_chain = Chain::new(revoke);
+    revoke_chain.link_before(RequireUser);
+    router.delete("/stories/:id/invites/:invite_id", revoke_chain);
+
+    let mut redeem_chain = Chain::new(redeem);
+    redeem_chain.link_before(RequireUser);
+    router.post("/invites/:token", redeem_chain);
+}
@@ -0,0 +1,197 @@
+//! Join request routes.
+//!
+//! * `POST /stories/:id/join_requests` - Ask to contribute to the story :id.
+//! * `GET /stories/:id/join_requests` - List the pending requests to join the story :id.
+//! * `PUT /stories/:id/join_requests/:request_id` - Approve or deny a request to join the story :id.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+
+use model::{Database, Story, JoinRequest};
+use auth::{AuthUser, RequireUser};
+use error::{IntoIronResult, FieldError};
+use error::FictError::{Forbidden, Invalid};
+use responses::timestamp;
+use params;
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CreationBody {
+    join_request: JoinRequestBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct JoinRequestBody {
+    message: Option<String>
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct DecisionBody {
+    join_request: DecisionSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct DecisionSettingsBody {
+    decision: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct JoinRequestDoc<'a> {
+    id: i64,
+    story_id: i64,
+    user_id: i64,
+    username: &'a str,
+    message: Option<&'a str>,
+    state: &'a str,
+    creation_time: String,
+    decision_time: Option<String>
+}
+
+impl<'a> JoinRequestDoc<'a> {
+
+    fn new(request: &'a JoinRequest) -> JoinRequestDoc<'a> {
+        JoinRequestDoc{
+            id: request.id,
+            story_id: request.story_id,
+            user_id: request.user_id,
+            username: &request.username,
+            message: request.message.as_ref().map(|m| &m[..]),
+            state: request.state.name(),
+            creation_time: timestamp(&request.creation_time),
+            decision_time: request.decision_time.as_ref().map(timestamp)
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct JoinRequestResponse<'a> {
+    join_request: JoinRequestDoc<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct JoinRequestsResponse<'a> {
+    join_requests: Vec<JoinRequestDoc<'a>>
+}
+
+/// `POST /stories/:id/join_requests` to ask the owners of a story that's open to join requests for
+/// permission to contribute to it. A request that was denied may be made again.
+pub fn create(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<CreationBody>(req)).join_request;
+
+    debug!("POST /stories/{}/join_requests [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let message = body.message
+        .map(|m| m.trim().to_owned())
+        .and_then(|m| if m.is_empty() { None } else { Some(m) });
+
+    let request = try!(JoinRequest::submit(conn, story_id, &user, message).iron());
+
+    debug!(".. Created join request {}.", request.id);
+
+    respond(status::Created, &request)
+}
+
+/// `GET /stories/:id/join_requests` to review the queue of pending requests to join a story that
+/// you own, oldest first.
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/join_requests [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let requests = try!(JoinRequest::pending_for(conn, &story).iron());
+
+    let r = JoinRequestsResponse {
+        join_requests: requests.iter().map(JoinRequestDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /stories/:id/join_requests/:request_id` to `approve` or `deny` a pending request to join a
+/// story that you own. Approved requesters are granted Writer access.
+pub fn decide(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let request_id = try!(params::numeric(req, "request_id"));
+    let body = try!(params::body::<DecisionBody>(req)).join_request;
+
+    debug!("PUT /stories/{}/join_requests/{} [{}]", story_id, request_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let approve = match &body.decision[..] {
+        "approve" => true,
+        "deny" => false,
+        _ => {
+            let errors = vec![FieldError::new(
+                "join_request.decision", "unknown", "Decision must be one of: approve, deny."
+            )];
+            return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+        }
+    };
+
+    let request = try!(JoinRequest::decide(conn, &story, request_id, &user, approve).iron());
+
+    debug!(".. Join request {} is now {}.", request.id, request.state.name());
+
+    respond(status::Ok, &request)
+}
+
+/// Respond with a JSON document describing a `JoinRequest`.
+fn respond(st: status::Status, request: &JoinRequest) -> IronResult<Response> {
+    let r = JoinRequestResponse {
+        join_request: JoinRequestDoc::new(request)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((st, encoded)))
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register join request routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut create_chain = Chain::new(create);
+    create_chain.link_before(RequireUser);
+    create_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories/:id/join_requests", create_chain);
+
+    let mut list_chain = Chain::new(list);
+    list_chain.link_before(RequireUser);
+    router.get("/stories/:id/join_requests", list_chain);
+
+    let mut decide_chain = Chain::new(decide);
+    decide_chain.link_before(RequireUser);
+    decide_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/stories/:id/join_requests/:request_id", decide_chain);
+}
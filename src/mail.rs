@@ -0,0 +1,167 @@
+//! Outgoing email, sent through a transport chosen by `FICTION_MAIL_TRANSPORT`:
+//!
+//! * `smtp` - Send through the SMTP server at `FICTION_SMTP_HOST` and `FICTION_SMTP_PORT`,
+//!   authenticating with `FICTION_SMTP_USERNAME` and `FICTION_SMTP_PASSWORD` if they're set.
+//! * `file` - Append each message to the file at `FICTION_MAIL_FILE`.
+//! * `log` - Log each message instead of sending it. The default.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use lettre::email::EmailBuilder;
+use lettre::transport::EmailTransport;
+use lettre::transport::smtp::{SmtpTransport as LettreSmtp, SmtpTransportBuilder, SecurityLevel};
+
+use config::env_opt;
+use error::{FictResult, fict_err};
+
+/// Address that messages are sent from when `FICTION_MAIL_FROM` is unset.
+const DEFAULT_FROM: &'static str = "fiction@localhost";
+
+/// SMTP port to connect to when `FICTION_SMTP_PORT` is unset.
+const DEFAULT_SMTP_PORT: u16 = 25;
+
+/// Value of the `List-Unsubscribe-Post` header, which marks unsubscribe links as accepting
+/// one-click `POST` requests.
+const ONE_CLICK: &'static str = "List-Unsubscribe=One-Click";
+
+/// A message to a single recipient. `unsubscribe_url`, if given, is advertised with a
+/// `List-Unsubscribe` header that accepts one-click `POST` requests (RFC 8058), and is expected to
+/// appear within `body` as well.
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub unsubscribe_url: Option<String>
+}
+
+/// Means of delivering a `Mail`.
+pub trait Transport: Send {
+    fn send(&mut self, mail: &Mail) -> FictResult<()>;
+}
+
+/// Create the transport chosen by `FICTION_MAIL_TRANSPORT`.
+pub fn transport() -> FictResult<Box<Transport>> {
+    let kind = try!(env_opt::<String>("FICTION_MAIL_TRANSPORT")).unwrap_or("log".to_owned());
+    let from = try!(env_opt::<String>("FICTION_MAIL_FROM")).unwrap_or(DEFAULT_FROM.to_owned());
+
+    match &kind[..] {
+        "smtp" => {
+            let host = try!(try!(env_opt::<String>("FICTION_SMTP_HOST"))
+                .ok_or(fict_err("FICTION_SMTP_HOST is required to send mail with SMTP")));
+            let port = try!(env_opt::<u16>("FICTION_SMTP_PORT")).unwrap_or(DEFAULT_SMTP_PORT);
+            let username = try!(env_opt::<String>("FICTION_SMTP_USERNAME"));
+            let password = try!(env_opt::<String>("FICTION_SMTP_PASSWORD"));
+            let tls = try!(env_opt::<bool>("FICTION_SMTP_TLS")).unwrap_or(false);
+
+            let mut builder = try!(SmtpTransportBuilder::new((&host[..], port)))
+                .connection_reuse(true);
+
+            if let (Some(username), Some(password)) = (username, password) {
+                builder = builder.credentials(&username, &password);
+            }
+
+            if tls {
+                builder = builder.security_level(SecurityLevel::AlwaysEncrypt);
+            }
+
+            info!("Sending mail through SMTP at {}:{}.", host, port);
+
+            Ok(Box::new(SmtpTransport{ mailer: builder.build(), from: from }))
+        },
+        "file" => {
+            let path = try!(try!(env_opt::<String>("FICTION_MAIL_FILE"))
+                .ok_or(fict_err("FICTION_MAIL_FILE is required to write mail to a file")));
+
+            info!("Writing mail to {}.", path);
+
+            Ok(Box::new(FileTransport{ path: path, from: from }))
+        },
+        "log" => {
+            info!("Logging mail instead of sending it.");
+
+            Ok(Box::new(LogTransport{ from: from }))
+        },
+        _ => Err(fict_err(format!("Unknown FICTION_MAIL_TRANSPORT: [{}]", kind)))
+    }
+}
+
+/// Render a `Mail` as the text of an RFC 822 message, as written by the `file` and `log`
+/// transports.
+fn render(mail: &Mail, from: &str) -> String {
+    let mut text = format!("From: {}\nTo: {}\nSubject: {}\n", from, mail.to, mail.subject);
+
+    if let Some(ref url) = mail.unsubscribe_url {
+        text.push_str(&format!("List-Unsubscribe: <{}>\n", url));
+        text.push_str(&format!("List-Unsubscribe-Post: {}\n", ONE_CLICK));
+    }
+
+    text.push_str("\n");
+    text.push_str(&mail.body);
+    text.push_str("\n");
+
+    text
+}
+
+/// Sends each `Mail` through an SMTP server.
+struct SmtpTransport {
+    mailer: LettreSmtp,
+    from: String
+}
+
+impl Transport for SmtpTransport {
+
+    fn send(&mut self, mail: &Mail) -> FictResult<()> {
+        let mut builder = EmailBuilder::new()
+            .to(&mail.to[..])
+            .from(&self.from[..])
+            .subject(&mail.subject)
+            .body(&mail.body);
+
+        if let Some(ref url) = mail.unsubscribe_url {
+            builder = builder.header(("List-Unsubscribe", &format!("<{}>", url)[..]));
+            builder = builder.header(("List-Unsubscribe-Post", ONE_CLICK));
+        }
+
+        let email = try!(builder.build().map_err(fict_err));
+
+        try!(self.mailer.send(email));
+
+        Ok(())
+    }
+
+}
+
+/// Appends each `Mail` to a file, separated by blank lines. Useful for inspecting the mail that
+/// would have been sent.
+struct FileTransport {
+    path: String,
+    from: String
+}
+
+impl Transport for FileTransport {
+
+    fn send(&mut self, mail: &Mail) -> FictResult<()> {
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+
+        try!(write!(file, "{}\n", render(mail, &self.from)));
+
+        Ok(())
+    }
+
+}
+
+/// Logs each `Mail` at the info level.
+struct LogTransport {
+    from: String
+}
+
+impl Transport for LogTransport {
+
+    fn send(&mut self, mail: &Mail) -> FictResult<()> {
+        info!("Mail:\n{}", render(mail, &self.from));
+
+        Ok(())
+    }
+
+}
@@ -17,6 +17,10 @@ extern crate r2d2;
 extern crate r2d2_postgres;
 extern crate plugin;
 extern crate chrono;
+extern crate unicode_normalization;
+extern crate websocket;
+extern crate crypto;
+extern crate lettre;
 
 use std::env;
 use std::process;
@@ -30,14 +34,31 @@ use model::Database;
 use error::FictResult;
 
 mod error;
+mod config;
 mod oauth;
 mod model;
 
 mod auth;
+mod responses;
+mod params;
+mod scheduler;
 
 mod whoami;
 mod snippets;
 mod stories;
+mod rounds;
+mod invites;
+mod joins;
+mod groups;
+mod events;
+mod collab;
+mod webhooks;
+mod mail;
+mod notify;
+mod preferences;
+mod digest;
+mod notifications;
+mod export;
 
 /// Respond with a simple string on `/` to be able to quickly check if it's up.
 fn health_check(_: &mut Request) -> IronResult<Response> {
@@ -67,9 +88,22 @@ fn launch() -> FictResult<()> {
     whoami::route(&mut router);
     snippets::route(&mut router);
     stories::route(&mut router);
+    rounds::route(&mut router);
+    invites::route(&mut router);
+    joins::route(&mut router);
+    groups::route(&mut router);
+    webhooks::route(&mut router);
+    preferences::route(&mut router);
+    digest::route(&mut router);
+    notifications::route(&mut router);
+    export::route(&mut router);
 
     let mut chain = Chain::new(router);
-    try!(Database::link(&mut chain));
+    let pool = try!(Database::link(&mut chain));
+    try!(scheduler::start(pool.clone()));
+    try!(webhooks::start(pool.clone()));
+    try!(events::start(pool.clone()));
+    try!(collab::start(pool));
     github.link(&mut chain);
 
     info!("Launching collaborative fiction API server on localhost:3000.");
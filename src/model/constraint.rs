@@ -0,0 +1,453 @@
+//! Rules of constrained writing games that each snippet of a story must obey.
+
+use postgres::GenericConnection;
+
+use model::{count_words, normalize, Story, Snippet};
+use error::{FictResult, FieldError};
+use error::FictError::Invalid;
+
+/// A rule chosen by a `Story`'s owner that each `Snippet` contributed to the story must obey.
+/// Letters and words are compared without regard to case.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// Content may not contain any of these letters, as in a lipogram.
+    ForbiddenLetters(String),
+
+    /// Content may not contain any of these words.
+    BannedWords(Vec<String>),
+
+    /// Content must contain each of these words.
+    RequiredWords(Vec<String>),
+
+    /// No sentence may contain more than this many words.
+    MaxSentenceWords(i32),
+
+    /// Content must begin with the final word of the previous snippet.
+    ChainLastWord
+}
+
+/// External representation of a `Constraint` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ConstraintSettings {
+    pub kind: String,
+    pub letters: Option<String>,
+    pub words: Option<Vec<String>>,
+    pub max_words: Option<i32>
+}
+
+impl Constraint {
+
+    /// Initialize database tables and indices used to store `Constraint` objects.
+    ///
+    /// Depends on `Story::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_constraints (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                kind_code INT NOT NULL,
+                argument VARCHAR NOT NULL DEFAULT '',
+                count INT NOT NULL DEFAULT 0
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_constraints_story_id_index
+            ON story_constraints (story_id)
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Convert a Constraint into the `(kind_code, argument, count)` columns used to store it
+    /// within the story_constraints table. Lists of words are stored separated by spaces.
+    fn encode(&self) -> (i32, String, i32) {
+        match *self {
+            Constraint::ForbiddenLetters(ref letters) => (0, letters.clone(), 0),
+            Constraint::BannedWords(ref words) => (1, words.join(" "), 0),
+            Constraint::RequiredWords(ref words) => (2, words.join(" "), 0),
+            Constraint::MaxSentenceWords(count) => (3, String::new(), count),
+            Constraint::ChainLastWord => (4, String::new(), 0)
+        }
+    }
+
+    /// Create a Constraint from columns previously encoded with `::encode()`. Produce `None` and
+    /// log a warning if the code is unrecognized.
+    fn decode(code: i32, argument: String, count: i32) -> Option<Constraint> {
+        let words = || argument.split_whitespace().map(|w| w.to_owned()).collect();
+
+        match code {
+            0 => Some(Constraint::ForbiddenLetters(argument.clone())),
+            1 => Some(Constraint::BannedWords(words())),
+            2 => Some(Constraint::RequiredWords(words())),
+            3 => Some(Constraint::MaxSentenceWords(count)),
+            4 => Some(Constraint::ChainLastWord),
+            _ => {
+                warn!("Invalid encoded constraint [{}]. Ignoring it.", code);
+                None
+            }
+        }
+    }
+
+    /// Produce the external representation of this constraint.
+    pub fn settings(&self) -> ConstraintSettings {
+        let mut settings = ConstraintSettings{
+            kind: String::new(), letters: None, words: None, max_words: None
+        };
+
+        match *self {
+            Constraint::ForbiddenLetters(ref letters) => {
+                settings.kind = "forbidden_letters".to_owned();
+                settings.letters = Some(letters.clone());
+            },
+            Constraint::BannedWords(ref words) => {
+                settings.kind = "banned_words".to_owned();
+                settings.words = Some(words.clone());
+            },
+            Constraint::RequiredWords(ref words) => {
+                settings.kind = "required_words".to_owned();
+                settings.words = Some(words.clone());
+            },
+            Constraint::MaxSentenceWords(count) => {
+                settings.kind = "max_sentence_words".to_owned();
+                settings.max_words = Some(count);
+            },
+            Constraint::ChainLastWord => {
+                settings.kind = "chain_last_word".to_owned();
+            }
+        }
+
+        settings
+    }
+
+    /// Interpret and validate an external representation of a constraint. Letters and words are
+    /// normalized and lowercased. Report any problems as errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &ConstraintSettings, prefix: &str) -> Result<Constraint, Vec<FieldError>> {
+        let words = || -> Result<Vec<String>, Vec<FieldError>> {
+            let mut words: Vec<String> = Vec::new();
+
+            for word in settings.words.iter().flat_map(|ws| ws.iter()).flat_map(|w| tokens(w)) {
+                if ! words.contains(&word) {
+                    words.push(word);
+                }
+            }
+
+            if words.is_empty() {
+                Err(vec![FieldError::new(
+                    format!("{}.words", prefix), "blank", "At least one word is required."
+                )])
+            } else {
+                Ok(words)
+            }
+        };
+
+        match &settings.kind[..] {
+            "forbidden_letters" => {
+                let mut letters = String::new();
+                for c in settings.letters.as_ref().map(|l| normalize(l)).unwrap_or(String::new()).chars() {
+                    for lower in c.to_lowercase() {
+                        if lower.is_alphanumeric() && ! letters.contains(lower) {
+                            letters.push(lower);
+                        }
+                    }
+                }
+
+                if letters.is_empty() {
+                    Err(vec![FieldError::new(
+                        format!("{}.letters", prefix), "blank", "At least one letter is required."
+                    )])
+                } else {
+                    Ok(Constraint::ForbiddenLetters(letters))
+                }
+            },
+            "banned_words" => words().map(Constraint::BannedWords),
+            "required_words" => words().map(Constraint::RequiredWords),
+            "max_sentence_words" => match settings.max_words {
+                Some(count) if count > 0 => Ok(Constraint::MaxSentenceWords(count)),
+                _ => Err(vec![FieldError::new(
+                    format!("{}.max_words", prefix), "not_positive",
+                    "A positive maximum number of words per sentence is required."
+                )])
+            },
+            "chain_last_word" => Ok(Constraint::ChainLastWord),
+            _ => Err(vec![FieldError::new(
+                format!("{}.kind", prefix), "unknown",
+                "Constraint must be one of: forbidden_letters, banned_words, required_words, \
+                max_sentence_words, chain_last_word."
+            )])
+        }
+    }
+
+    /// Retrieve each constraint of a `Story`, in the order that they were chosen.
+    pub fn for_story(conn: &GenericConnection, story: &Story) -> FictResult<Vec<Constraint>> {
+        let selection = try!(conn.prepare("
+            SELECT kind_code, argument, count
+            FROM story_constraints
+            WHERE story_id = $1
+            ORDER BY id ASC
+        "));
+
+        let rows = try!(selection.query(&[&story.id]));
+
+        Ok(rows.iter()
+            .filter_map(|row| Constraint::decode(row.get(0), row.get(1), row.get(2)))
+            .collect())
+    }
+
+    /// Persist `constraints` as the complete set of constraints for a `Story`, replacing any
+    /// that it already had.
+    pub fn replace_all(conn: &GenericConnection, story: &Story, constraints: &[Constraint]) -> FictResult<()> {
+        let transaction = try!(conn.transaction());
+
+        try!(transaction.execute("
+            DELETE FROM story_constraints WHERE story_id = $1
+        ", &[&story.id]));
+
+        let insertion = try!(transaction.prepare("
+            INSERT INTO story_constraints (story_id, kind_code, argument, count)
+            VALUES ($1, $2, $3, $4)
+        "));
+
+        for constraint in constraints.iter() {
+            let (kind_code, argument, count) = constraint.encode();
+            try!(insertion.execute(&[&story.id, &kind_code, &argument, &count]));
+        }
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Verify that content about to be contributed to a `Story` obeys each of its constraints.
+    /// Produce `Err(FictError::Invalid)` describing each violated rule otherwise. The caller should
+    /// hold the story's row lock, so that the snippet it continues can't change meanwhile.
+    pub fn verify(conn: &GenericConnection, story: &Story, content: &str) -> FictResult<()> {
+        let constraints = try!(Constraint::for_story(conn, story));
+        if constraints.is_empty() {
+            return Ok(());
+        }
+
+        let previous = try!(Snippet::most_recent(conn, story));
+
+        Constraint::verify_all(&constraints, content, previous.as_ref())
+    }
+
+    /// Verify that the revised content of a `Snippet` within a `Story` obeys each of the story's
+    /// constraints, as though it were being contributed again after the snippet that it
+    /// continues. The caller should hold the story's row lock.
+    pub fn verify_revision(conn: &GenericConnection, story: &Story, snippet: &Snippet, content: &str) -> FictResult<()> {
+        let constraints = try!(Constraint::for_story(conn, story));
+        if constraints.is_empty() {
+            return Ok(());
+        }
+
+        let previous = try!(snippet.previous(conn));
+
+        Constraint::verify_all(&constraints, content, previous.as_ref())
+    }
+
+    /// Check `content` against each of `constraints`, continuing from the snippet `previous`.
+    fn verify_all(constraints: &[Constraint], content: &str, previous: Option<&Snippet>) -> FictResult<()> {
+        let content = normalize(content);
+
+        let errors: Vec<FieldError> = constraints.iter()
+            .filter_map(|constraint| {
+                constraint.violation(&content, previous.as_ref().map(|s| &s.content[..]))
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Invalid(errors))
+        }
+    }
+
+    /// Describe how `content` violates this constraint, if it does. `previous` is the content of
+    /// the snippet that it continues, if any.
+    fn violation(&self, content: &str, previous: Option<&str>) -> Option<FieldError> {
+        match *self {
+            Constraint::ForbiddenLetters(ref letters) => {
+                let mut found = String::new();
+                for c in content.chars().flat_map(|c| c.to_lowercase()) {
+                    if letters.contains(c) && ! found.contains(c) {
+                        found.push(c);
+                    }
+                }
+
+                if found.is_empty() {
+                    None
+                } else {
+                    Some(FieldError::new(
+                        "snippet.content", "forbidden_letters",
+                        format!("Content may not contain the letters: {}", found)
+                    ))
+                }
+            },
+            Constraint::BannedWords(ref words) => {
+                let used = tokens(content);
+                let found: Vec<&str> = words.iter()
+                    .filter(|w| used.contains(w))
+                    .map(|w| &w[..])
+                    .collect();
+
+                if found.is_empty() {
+                    None
+                } else {
+                    Some(FieldError::new(
+                        "snippet.content", "banned_words",
+                        format!("Content may not contain the words: {}", found.join(", "))
+                    ))
+                }
+            },
+            Constraint::RequiredWords(ref words) => {
+                let used = tokens(content);
+                let missing: Vec<&str> = words.iter()
+                    .filter(|w| ! used.contains(w))
+                    .map(|w| &w[..])
+                    .collect();
+
+                if missing.is_empty() {
+                    None
+                } else {
+                    Some(FieldError::new(
+                        "snippet.content", "required_words",
+                        format!("Content must contain the words: {}", missing.join(", "))
+                    ))
+                }
+            },
+            Constraint::MaxSentenceWords(max) => {
+                let longest = content
+                    .split(|c| c == '.' || c == '!' || c == '?')
+                    .map(count_words)
+                    .max()
+                    .unwrap_or(0);
+
+                if longest <= max {
+                    None
+                } else {
+                    Some(FieldError::new(
+                        "snippet.content", "sentence_too_long",
+                        format!("Sentences may contain no more than {} words.", max)
+                    ))
+                }
+            },
+            Constraint::ChainLastWord => {
+                let last = match previous.and_then(|p| tokens(p).pop()) {
+                    Some(word) => word,
+                    None => return None
+                };
+
+                if tokens(content).first() == Some(&last) {
+                    None
+                } else {
+                    Some(FieldError::new(
+                        "snippet.content", "chain_last_word",
+                        format!("Content must begin with the word: {}", last)
+                    ))
+                }
+            }
+        }
+    }
+
+}
+
+/// Split content into lowercase words, ignoring punctuation other than apostrophes.
+fn tokens(content: &str) -> Vec<String> {
+    content
+        .split(|c: char| ! c.is_alphanumeric() && c != '\'')
+        .filter(|w| ! w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Constraint, ConstraintSettings, tokens};
+
+    /// Code of the error produced by checking `content` against a constraint, if any.
+    fn violation_code(constraint: &Constraint, content: &str, previous: Option<&str>) -> Option<String> {
+        constraint.violation(content, previous).map(|e| e.code)
+    }
+
+    fn settings(kind: &str, letters: Option<&str>, words: Option<Vec<&str>>, max_words: Option<i32>) -> ConstraintSettings {
+        ConstraintSettings {
+            kind: kind.to_owned(),
+            letters: letters.map(|l| l.to_owned()),
+            words: words.map(|ws| ws.into_iter().map(|w| w.to_owned()).collect()),
+            max_words: max_words
+        }
+    }
+
+    #[test]
+    fn tokens_are_lowercase_words() {
+        assert_eq!(tokens("Don't STOP, believing!"), vec!["don't", "stop", "believing"]);
+        assert!(tokens(" ... ").is_empty());
+    }
+
+    #[test]
+    fn forbidden_letters_ignore_case() {
+        let c = Constraint::ForbiddenLetters("e".to_owned());
+
+        assert_eq!(violation_code(&c, "A dog ran far.", None), None);
+        assert_eq!(violation_code(&c, "EVERY dog ran.", None), Some("forbidden_letters".to_owned()));
+    }
+
+    #[test]
+    fn banned_words_match_whole_words() {
+        let c = Constraint::BannedWords(vec!["cat".to_owned()]);
+
+        assert_eq!(violation_code(&c, "A catalog of dogs.", None), None);
+        assert_eq!(violation_code(&c, "The Cat sat.", None), Some("banned_words".to_owned()));
+    }
+
+    #[test]
+    fn required_words_must_each_appear() {
+        let c = Constraint::RequiredWords(vec!["moon".to_owned(), "tide".to_owned()]);
+
+        assert_eq!(violation_code(&c, "The moon pulled the tide.", None), None);
+        assert_eq!(violation_code(&c, "The moon rose.", None), Some("required_words".to_owned()));
+    }
+
+    #[test]
+    fn max_sentence_words_checks_each_sentence() {
+        let c = Constraint::MaxSentenceWords(3);
+
+        assert_eq!(violation_code(&c, "One two three. Four five! Six?", None), None);
+        assert_eq!(violation_code(&c, "One. Two three four five.", None), Some("sentence_too_long".to_owned()));
+    }
+
+    #[test]
+    fn chain_last_word_continues_the_previous_snippet() {
+        let c = Constraint::ChainLastWord;
+
+        assert_eq!(violation_code(&c, "Anything at all.", None), None);
+        assert_eq!(violation_code(&c, "Night fell quickly.", Some("Then came the night.")), None);
+        assert_eq!(
+            violation_code(&c, "Morning came.", Some("Then came the night.")),
+            Some("chain_last_word".to_owned())
+        );
+    }
+
+    #[test]
+    fn settings_are_normalized() {
+        let letters = Constraint::from_settings(&settings("forbidden_letters", Some("EeX!"), None, None), "c");
+        assert_eq!(letters.ok(), Some(Constraint::ForbiddenLetters("ex".to_owned())));
+
+        let words = Constraint::from_settings(&settings("banned_words", None, Some(vec!["Cat", "cat dog"]), None), "c");
+        assert_eq!(words.ok(), Some(Constraint::BannedWords(vec!["cat".to_owned(), "dog".to_owned()])));
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        let errors = |s: ConstraintSettings| -> Vec<(String, String)> {
+            Constraint::from_settings(&s, "c").unwrap_err().into_iter().map(|e| (e.field, e.code)).collect()
+        };
+
+        assert_eq!(errors(settings("palindrome", None, None, None)), vec![("c.kind".to_owned(), "unknown".to_owned())]);
+        assert_eq!(errors(settings("banned_words", None, Some(vec![]), None)), vec![("c.words".to_owned(), "blank".to_owned())]);
+        assert_eq!(errors(settings("max_sentence_words", None, None, Some(0))), vec![("c.max_words".to_owned(), "not_positive".to_owned())]);
+    }
+}
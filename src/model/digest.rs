@@ -0,0 +1,100 @@
+//! Summaries of activity across each story that a user can access.
+
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+
+use model::{Story, AccessLevel, User};
+use error::FictResult;
+
+/// Activity within a single story during a `Digest`'s period.
+pub struct DigestEntry {
+    pub story_id: i64,
+    pub title: Option<String>,
+    pub snippet_count: i64
+}
+
+/// Activity between `since` and `until` across each story that a `User` may read: the snippets
+/// contributed to each story, the stories published, and the stories that are waiting on the user
+/// to write next.
+pub struct Digest {
+    pub since: DateTime<UTC>,
+    pub until: DateTime<UTC>,
+    pub contributions: Vec<DigestEntry>,
+    pub publications: Vec<DigestEntry>,
+    pub awaiting_turn: Vec<Story>
+}
+
+impl Digest {
+
+    /// Compile the digest of a `User` covering the period between `since` and `until`. Stories
+    /// awaiting the user's turn are determined as of `until`.
+    pub fn compile(conn: &GenericConnection, user: &User, since: DateTime<UTC>, until: DateTime<UTC>) -> FictResult<Digest> {
+        let contribution_selection = try!(conn.prepare("
+            SELECT stories.id, stories.title, COUNT(snippets.id)
+            FROM snippets
+            INNER JOIN stories ON stories.id = snippets.story_id
+            WHERE
+                snippets.creation_time > $2 AND snippets.creation_time <= $3 AND
+                stories.id IN (
+                    SELECT story_id
+                    FROM story_grants
+                    WHERE user_id = $1 AND access_level_code >= $4
+                )
+            GROUP BY stories.id, stories.title
+            ORDER BY MAX(snippets.creation_time) DESC
+        "));
+
+        let rows = try!(contribution_selection.query(&[
+            &user.id, &since, &until, &AccessLevel::Reader.encode()
+        ]));
+
+        let contributions = rows.iter().map(|row| DigestEntry{
+            story_id: row.get(0),
+            title: row.get(1),
+            snippet_count: row.get(2)
+        }).collect();
+
+        let publication_selection = try!(conn.prepare("
+            SELECT stories.id, stories.title, stories.contribution_count
+            FROM stories
+            WHERE
+                published AND publish_time > $2 AND publish_time <= $3 AND
+                stories.id IN (
+                    SELECT story_id
+                    FROM story_grants
+                    WHERE user_id = $1 AND access_level_code >= $4
+                )
+            ORDER BY publish_time DESC
+        "));
+
+        let rows = try!(publication_selection.query(&[
+            &user.id, &since, &until, &AccessLevel::Reader.encode()
+        ]));
+
+        let publications = rows.iter().map(|row| {
+            let count: i32 = row.get(2);
+
+            DigestEntry{
+                story_id: row.get(0),
+                title: row.get(1),
+                snippet_count: count as i64
+            }
+        }).collect();
+
+        let awaiting_turn = try!(Story::awaiting_turn(conn, user, until));
+
+        Ok(Digest{
+            since: since,
+            until: until,
+            contributions: contributions,
+            publications: publications,
+            awaiting_turn: awaiting_turn
+        })
+    }
+
+    /// Determine whether or not anything happened, or is waiting, during this digest's period.
+    pub fn is_empty(&self) -> bool {
+        self.contributions.is_empty() && self.publications.is_empty() && self.awaiting_turn.is_empty()
+    }
+
+}
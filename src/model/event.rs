@@ -0,0 +1,406 @@
+//! A durable log of activity within each story, replayed to clients that follow it.
+//!
+//! Event IDs are assigned when each event is inserted, but an event only becomes visible once its
+//! transaction commits, so a reader that simply follows the highest ID it has seen could skip an
+//! event committed late by a long-running transaction. Instead, readers follow an `EventPosition`
+//! that orders events by the transaction that recorded them, and only read the events of
+//! transactions that have finished: those older than the oldest transaction still in progress.
+
+use std::fmt;
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+
+use model::{first, first_opt, ensure_column, AccessLevel, User, WebhookDelivery};
+use error::FictResult;
+
+/// Columns selected by each query that produces a `StoryEvent`, in the order expected by
+/// `StoryEvent::from_row`.
+const EVENT_COLUMNS: &'static str = "
+    story_events.id, story_events.story_id, story_events.kind_code, story_events.actor_id,
+    story_events.subject_id, story_events.detail, story_events.creation_time,
+    story_events.txid
+";
+
+/// Condition that limits a query to events whose transactions are older than every transaction
+/// still in progress, and so won't be followed by any more events from before their position.
+const SETTLED: &'static str = "story_events.txid < txid_snapshot_xmin(txid_current_snapshot())";
+
+/// Kinds of activity that may be recorded as a `StoryEvent`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    LockAcquired,
+    LockReleased,
+    LockExpired,
+    SnippetContributed,
+    StoryPublished,
+    AccessChanged,
+    InviteRedeemed
+}
+
+impl EventKind {
+
+    /// Convert an EventKind into an integer for serialization within a database table.
+    pub fn encode(&self) -> i32 {
+        match *self {
+            EventKind::LockAcquired => 0,
+            EventKind::LockReleased => 1,
+            EventKind::LockExpired => 2,
+            EventKind::SnippetContributed => 3,
+            EventKind::StoryPublished => 4,
+            EventKind::AccessChanged => 5,
+            EventKind::InviteRedeemed => 6
+        }
+    }
+
+    /// Create an EventKind from an integer previously encoded with `::encode()`. Produce `None`
+    /// and log a warning if the code is unrecognized.
+    pub fn decode(code: i32) -> Option<EventKind> {
+        match code {
+            0 => Some(EventKind::LockAcquired),
+            1 => Some(EventKind::LockReleased),
+            2 => Some(EventKind::LockExpired),
+            3 => Some(EventKind::SnippetContributed),
+            4 => Some(EventKind::StoryPublished),
+            5 => Some(EventKind::AccessChanged),
+            6 => Some(EventKind::InviteRedeemed),
+            _ => {
+                warn!("Invalid encoded event kind [{}]. Ignoring it.", code);
+                None
+            }
+        }
+    }
+
+    /// Name used for this kind of event within event streams.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventKind::LockAcquired => "lock_acquired",
+            EventKind::LockReleased => "lock_released",
+            EventKind::LockExpired => "lock_expired",
+            EventKind::SnippetContributed => "snippet_contributed",
+            EventKind::StoryPublished => "story_published",
+            EventKind::AccessChanged => "access_changed",
+            EventKind::InviteRedeemed => "invite_redeemed"
+        }
+    }
+
+    /// Find the EventKind with a name produced by `::name()`, if any.
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        EventKind::all().into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Every kind of event, in the order of their codes.
+    pub fn all() -> Vec<EventKind> {
+        vec![
+            EventKind::LockAcquired,
+            EventKind::LockReleased,
+            EventKind::LockExpired,
+            EventKind::SnippetContributed,
+            EventKind::StoryPublished,
+            EventKind::AccessChanged,
+            EventKind::InviteRedeemed
+        ]
+    }
+
+    /// Combine a set of EventKinds into a bit mask for storage within a single database column,
+    /// with one bit set for each kind's code.
+    pub fn mask(kinds: &[EventKind]) -> i32 {
+        kinds.iter().fold(0, |mask, kind| mask | (1 << kind.encode()))
+    }
+
+    /// Expand a bit mask produced by `::mask()` back into the EventKinds it contains.
+    pub fn from_mask(mask: i32) -> Vec<EventKind> {
+        EventKind::all().into_iter().filter(|kind| mask & (1 << kind.encode()) != 0).collect()
+    }
+
+}
+
+/// Something that happened within a `Story`. `actor_id` is the user who caused it, if any, and
+/// `subject_id` is the user it happened to, if that's someone else: the writer whose lock expired,
+/// the writer a sprint passed the lock to, the user whose access changed, or the creator of a
+/// redeemed invite. `detail` carries a short, kind-specific description, like the new access
+/// level's name, the contributed snippet's ID, or the redeemed invite's ID.
+pub struct StoryEvent {
+    pub id: i64,
+    pub story_id: i64,
+    pub kind: EventKind,
+    pub actor_id: Option<i64>,
+    pub subject_id: Option<i64>,
+    pub detail: Option<String>,
+    pub creation_time: DateTime<UTC>,
+    pub txid: i64
+}
+
+/// Place of an event within the log: the ID of the transaction that recorded it, then its own ID.
+/// Readers resume from the position of the last event they've processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventPosition {
+    pub txid: i64,
+    pub id: i64
+}
+
+impl EventPosition {
+
+    /// Position before every event in the log.
+    pub fn start() -> EventPosition {
+        EventPosition { txid: 0, id: 0 }
+    }
+
+    /// Parse a position written with `Display`, like `1234-56`.
+    pub fn parse(s: &str) -> Option<EventPosition> {
+        let mut parts = s.trim().splitn(2, '-');
+
+        match (parts.next().and_then(|t| t.parse().ok()), parts.next().and_then(|i| i.parse().ok())) {
+            (Some(txid), Some(id)) => Some(EventPosition { txid: txid, id: id }),
+            _ => None
+        }
+    }
+
+}
+
+impl fmt::Display for EventPosition {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.txid, self.id)
+    }
+
+}
+
+impl StoryEvent {
+
+    /// Initialize database tables and indices used to store `StoryEvent` objects.
+    ///
+    /// Depends on `Story::initialize` and `User::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_events (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                kind_code INT NOT NULL,
+                actor_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                subject_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                detail VARCHAR,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc')
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_events_story_id_index
+            ON story_events (story_id, id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_events_subject_id_index
+            ON story_events (subject_id, id)
+        ", &[]));
+
+        // Events recorded before transaction IDs were tracked all share the ID of the transaction
+        // that adds the column, so they keep their order.
+        try!(ensure_column(conn, "story_events", "txid", "BIGINT NOT NULL DEFAULT txid_current()"));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_events_position_index
+            ON story_events (txid, id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_events_story_position_index
+            ON story_events (story_id, txid, id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS event_cursors (
+                name VARCHAR PRIMARY KEY,
+                last_event_id BIGINT NOT NULL
+            )
+        ", &[]));
+
+        if try!(ensure_column(conn, "event_cursors", "last_txid", "BIGINT NOT NULL DEFAULT 0")) {
+            try!(conn.execute("
+                UPDATE event_cursors
+                SET last_txid = COALESCE(
+                    (SELECT txid FROM story_events WHERE story_events.id = event_cursors.last_event_id),
+                    0
+                )
+            ", &[]));
+        }
+
+        Ok(())
+    }
+
+    /// Append an event to the log of a story, and queue its delivery to each webhook that's
+    /// subscribed to it.
+    pub fn record(conn: &GenericConnection, story_id: i64, kind: EventKind, actor_id: Option<i64>, subject_id: Option<i64>, detail: Option<String>) -> FictResult<()> {
+        let insertion = try!(conn.prepare("
+            INSERT INTO story_events (story_id, kind_code, actor_id, subject_id, detail)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id
+        "));
+
+        let rows = try!(insertion.query(&[&story_id, &kind.encode(), &actor_id, &subject_id, &detail]));
+        let id: i64 = try!(first(&rows)).get(0);
+
+        try!(WebhookDelivery::enqueue(conn, id, story_id, &kind, subject_id));
+
+        Ok(())
+    }
+
+    /// Search for an existing `StoryEvent` by ID.
+    pub fn with_id(conn: &GenericConnection, id: i64) -> FictResult<Option<StoryEvent>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_events
+            WHERE id = $1
+        ", EVENT_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.and_then(StoryEvent::from_row))
+    }
+
+    /// Position of this event within the log.
+    pub fn position(&self) -> EventPosition {
+        EventPosition { txid: self.txid, id: self.id }
+    }
+
+    /// Produce the position of the most recent event whose transaction has finished, or
+    /// `EventPosition::start()` if there are none yet. Readers that begin here see every event
+    /// committed from now on.
+    pub fn latest_position(conn: &GenericConnection) -> FictResult<EventPosition> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT story_events.txid, story_events.id
+            FROM story_events
+            WHERE {}
+            ORDER BY story_events.txid DESC, story_events.id DESC
+            LIMIT 1
+        ", SETTLED)));
+
+        let rows = try!(selection.query(&[]));
+
+        Ok(try!(first_opt(&rows))
+            .map(|row| EventPosition { txid: row.get(0), id: row.get(1) })
+            .unwrap_or(EventPosition::start()))
+    }
+
+    /// Produce the position of the last event processed by the background job `name`. A job that
+    /// hasn't run before begins after the most recently recorded event, rather than working
+    /// through the entire log.
+    pub fn cursor(conn: &GenericConnection, name: &str) -> FictResult<EventPosition> {
+        let selection = try!(conn.prepare("
+            SELECT last_txid, last_event_id
+            FROM event_cursors
+            WHERE name = $1
+        "));
+
+        let rows = try!(selection.query(&[&name]));
+
+        match try!(first_opt(&rows)) {
+            Some(row) => Ok(EventPosition { txid: row.get(0), id: row.get(1) }),
+            None => {
+                let latest = try!(StoryEvent::latest_position(conn));
+
+                try!(conn.execute("
+                    INSERT INTO event_cursors (name, last_txid, last_event_id)
+                    VALUES ($1, $2, $3)
+                ", &[&name, &latest.txid, &latest.id]));
+
+                Ok(latest)
+            }
+        }
+    }
+
+    /// Record that the background job `name` has processed each event up to and including the
+    /// one at `position`.
+    pub fn advance_cursor(conn: &GenericConnection, name: &str, position: &EventPosition) -> FictResult<()> {
+        try!(conn.execute("
+            UPDATE event_cursors
+            SET last_txid = $2, last_event_id = $3
+            WHERE name = $1
+        ", &[&name, &position.txid, &position.id]));
+
+        Ok(())
+    }
+
+    /// Retrieve up to `limit` events recorded after the position `after`, from the logs of every
+    /// story, oldest first. Events of transactions that are still in progress, or that began
+    /// after one that is, wait for a later call.
+    pub fn after(conn: &GenericConnection, after: &EventPosition, limit: i64) -> FictResult<Vec<StoryEvent>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_events
+            WHERE (story_events.txid, story_events.id) > ($1, $2) AND {}
+            ORDER BY story_events.txid ASC, story_events.id ASC
+            LIMIT $3
+        ", EVENT_COLUMNS, SETTLED)));
+
+        let rows = try!(selection.query(&[&after.txid, &after.id, &limit]));
+
+        Ok(rows.iter().filter_map(StoryEvent::from_row).collect())
+    }
+
+    /// Retrieve up to `limit` events from the log of a story that were recorded after the
+    /// position `after`, oldest first. Like `::after()`, only finished transactions are read.
+    pub fn for_story(conn: &GenericConnection, story_id: i64, after: &EventPosition, limit: i64) -> FictResult<Vec<StoryEvent>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_events
+            WHERE story_events.story_id = $1 AND (story_events.txid, story_events.id) > ($2, $3) AND {}
+            ORDER BY story_events.txid ASC, story_events.id ASC
+            LIMIT $4
+        ", EVENT_COLUMNS, SETTLED)));
+
+        let rows = try!(selection.query(&[&story_id, &after.txid, &after.id, &limit]));
+
+        Ok(rows.iter().filter_map(StoryEvent::from_row).collect())
+    }
+
+    /// Retrieve up to `limit` events recorded after the position `after` that concern a `User`:
+    /// those from every story they've been granted access to, and changes to their own access,
+    /// oldest first. Like `::after()`, only finished transactions are read.
+    pub fn for_user(conn: &GenericConnection, user: &User, after: &EventPosition, limit: i64) -> FictResult<Vec<StoryEvent>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_events
+            WHERE
+                (story_events.txid, story_events.id) > ($2, $3) AND {} AND (
+                    story_events.subject_id = $1 OR
+                    story_events.story_id IN (
+                        SELECT story_id
+                        FROM story_grants
+                        WHERE user_id = $1 AND access_level_code >= $4
+                    )
+                )
+            ORDER BY story_events.txid ASC, story_events.id ASC
+            LIMIT $5
+        ", EVENT_COLUMNS, SETTLED)));
+
+        let rows = try!(selection.query(&[&user.id, &after.txid, &after.id, &AccessLevel::Reader.encode(), &limit]));
+
+        Ok(rows.iter().filter_map(StoryEvent::from_row).collect())
+    }
+
+    /// Construct a `StoryEvent` from a row containing each of the `EVENT_COLUMNS`. Produce `None`
+    /// if the event's kind is unrecognized.
+    fn from_row(row: Row) -> Option<StoryEvent> {
+        EventKind::decode(row.get(2)).map(|kind| StoryEvent{
+            id: row.get(0),
+            story_id: row.get(1),
+            kind: kind,
+            actor_id: row.get(3),
+            subject_id: row.get(4),
+            detail: row.get(5),
+            creation_time: row.get(6),
+            txid: row.get(7)
+        })
+    }
+
+}
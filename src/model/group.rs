@@ -0,0 +1,292 @@
+//! Groups of users who may be granted access to stories together.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+
+use model::{first, first_opt, Story, AccessLevel, User, StoryEvent, EventKind};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `Group`, in the order expected by
+/// `Group::from_row`.
+const GROUP_COLUMNS: &'static str = "
+    user_groups.id, user_groups.name, user_groups.owner_id, user_groups.creation_time
+";
+
+/// Named circle of users, managed by its owner, that may be granted an `AccessLevel` on a `Story`
+/// in place of granting each of its members access individually.
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub creation_time: DateTime<UTC>
+}
+
+/// Level of access to a `Story` that's been granted to each member of a `Group`.
+pub struct GroupGrant {
+    pub group: Group,
+    pub access_level: AccessLevel
+}
+
+impl Group {
+
+    /// Initialize database tables, indices, and views used to store `Group` objects, their
+    /// members, and their access to stories. The `story_grants` view combines individual and
+    /// group grants, listing one row per user for each grant that applies to them.
+    ///
+    /// Depends on `User::initialize`, `Story::initialize`, and `StoryAccess::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS user_groups (
+                id BIGSERIAL PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                owner_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc')
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS group_members (
+                id BIGSERIAL PRIMARY KEY,
+                group_id BIGINT NOT NULL REFERENCES user_groups (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                UNIQUE (group_id, user_id)
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS group_members_user_id_index
+            ON group_members (user_id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS group_story_access (
+                id BIGSERIAL PRIMARY KEY,
+                group_id BIGINT NOT NULL REFERENCES user_groups (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                access_level_code INT NOT NULL,
+                UNIQUE (group_id, story_id)
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS group_story_access_story_id_index
+            ON group_story_access (story_id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE OR REPLACE VIEW story_grants AS
+                SELECT story_id, user_id, access_level_code, id AS grant_id, false AS via_group
+                FROM story_access
+            UNION ALL
+                SELECT
+                    group_story_access.story_id, group_members.user_id,
+                    group_story_access.access_level_code, group_story_access.id AS grant_id,
+                    true AS via_group
+                FROM group_story_access
+                INNER JOIN group_members ON group_members.group_id = group_story_access.group_id
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Create and persist a new `Group` owned by `owner`, who becomes its first member.
+    pub fn create(conn: &GenericConnection, owner: &User, name: String) -> FictResult<Group> {
+        let transaction = try!(conn.transaction());
+
+        let insertion = try!(transaction.prepare(&format!("
+            INSERT INTO user_groups (name, owner_id)
+            VALUES ($1, $2)
+            RETURNING {}
+        ", GROUP_COLUMNS)));
+
+        let rows = try!(insertion.query(&[&name, &owner.id]));
+        let group = Group::from_row(try!(first(&rows)));
+
+        try!(transaction.execute("
+            INSERT INTO group_members (group_id, user_id)
+            VALUES ($1, $2)
+        ", &[&group.id, &owner.id]));
+
+        try!(transaction.commit());
+
+        Ok(group)
+    }
+
+    /// Search for an existing `Group` by ID.
+    pub fn with_id(conn: &GenericConnection, id: i64) -> FictResult<Option<Group>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM user_groups
+            WHERE id = $1
+        ", GROUP_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Group::from_row))
+    }
+
+    /// Retrieve each `Group` that a `User` belongs to, in the order that they were created.
+    pub fn for_user(conn: &GenericConnection, user: &User) -> FictResult<Vec<Group>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM user_groups
+            INNER JOIN group_members ON group_members.group_id = user_groups.id
+            WHERE group_members.user_id = $1
+            ORDER BY user_groups.id ASC
+        ", GROUP_COLUMNS)));
+
+        let rows = try!(selection.query(&[&user.id]));
+
+        Ok(rows.iter().map(Group::from_row).collect())
+    }
+
+    /// List each member of this group, in the order that they joined.
+    pub fn members(&self, conn: &GenericConnection) -> FictResult<Vec<User>> {
+        let selection = try!(conn.prepare("
+            SELECT users.id, users.name, users.email
+            FROM group_members
+            INNER JOIN users ON users.id = group_members.user_id
+            WHERE group_members.group_id = $1
+            ORDER BY group_members.id ASC
+        "));
+
+        let rows = try!(selection.query(&[&self.id]));
+
+        Ok(rows.iter().map(|row| User{
+            id: Some(row.get(0)),
+            name: row.get(1),
+            email: row.get(2)
+        }).collect())
+    }
+
+    /// Determine whether or not a `User` belongs to this group.
+    pub fn has_member(&self, conn: &GenericConnection, user: &User) -> FictResult<bool> {
+        let selection = try!(conn.prepare("
+            SELECT 1
+            FROM group_members
+            WHERE group_id = $1 AND user_id = $2
+        "));
+
+        let rows = try!(selection.query(&[&self.id, &user.id]));
+
+        Ok(!rows.is_empty())
+    }
+
+    /// Determine whether or not a `User` owns this group.
+    pub fn is_owned_by(&self, user: &User) -> bool {
+        user.id == Some(self.owner_id)
+    }
+
+    /// Add a `User` to this group. Adding an existing member has no effect.
+    pub fn add_member(&self, conn: &GenericConnection, user: &User) -> FictResult<()> {
+        let transaction = try!(conn.transaction());
+
+        if ! try!(self.has_member(&transaction, user)) {
+            try!(transaction.execute("
+                INSERT INTO group_members (group_id, user_id)
+                VALUES ($1, $2)
+            ", &[&self.id, &user.id]));
+        }
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Remove a `User` from this group. The group's owner may not be removed; produce
+    /// `Err(FictError::Forbidden)` instead. If the user isn't a member, produce
+    /// `Err(FictError::NotFound)`.
+    pub fn remove_member(&self, conn: &GenericConnection, user: &User) -> FictResult<()> {
+        if self.is_owned_by(user) {
+            return Err(FictError::Forbidden);
+        }
+
+        let count = try!(conn.execute("
+            DELETE FROM group_members
+            WHERE group_id = $1 AND user_id = $2
+        ", &[&self.id, &user.id]));
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// Grant each member of this group access to a `Story` at a specified level, replacing any
+    /// level that the group was granted before. If level is `NoAccess`, the group's access will be
+    /// removed. Access granted to members individually is unaffected. An
+    /// `EventKind::AccessChanged` event is recorded, describing the group and its new level.
+    pub fn grant(&self, conn: &GenericConnection, story: &Story, level: &AccessLevel) -> FictResult<()> {
+        let transaction = try!(conn.transaction());
+
+        let detail = format!("group {} {}", self.id, level.name());
+        try!(StoryEvent::record(&transaction, story.id, EventKind::AccessChanged, None, None, Some(detail)));
+
+        try!(transaction.execute("
+            DELETE FROM group_story_access
+            WHERE group_id = $1 AND story_id = $2
+        ", &[&self.id, &story.id]));
+
+        match *level {
+            AccessLevel::NoAccess => (),
+            _ => {
+                try!(transaction.execute("
+                    INSERT INTO group_story_access (group_id, story_id, access_level_code)
+                    VALUES ($1, $2, $3)
+                ", &[&self.id, &story.id, &level.encode()]));
+            }
+        }
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Retrieve each `Group` that has been granted access to a `Story`, in the order that they
+    /// were granted access.
+    pub fn grants_for_story(conn: &GenericConnection, story: &Story) -> FictResult<Vec<GroupGrant>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}, group_story_access.access_level_code
+            FROM group_story_access
+            INNER JOIN user_groups ON user_groups.id = group_story_access.group_id
+            WHERE group_story_access.story_id = $1
+            ORDER BY group_story_access.id ASC
+        ", GROUP_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id]));
+
+        rows.iter().map(|row| {
+            let access_level = try!(AccessLevel::decode(row.get(4)));
+
+            Ok(GroupGrant{
+                group: Group::from_row(row),
+                access_level: access_level
+            })
+        }).collect()
+    }
+
+    /// Construct a `Group` from a row beginning with each of the `GROUP_COLUMNS`.
+    fn from_row(row: Row) -> Group {
+        Group{
+            id: row.get(0),
+            name: row.get(1),
+            owner_id: row.get(2),
+            creation_time: row.get(3)
+        }
+    }
+
+}
@@ -0,0 +1,226 @@
+//! Shareable invitations that grant access to a story.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+use rand::{OsRng, Rng};
+
+use model::{first, first_opt, Story, StoryAccess, AccessLevel, User, StoryEvent, EventKind};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces an `Invite`, in the order expected by
+/// `Invite::from_row`.
+const INVITE_COLUMNS: &'static str = "
+    id, story_id, token, access_level_code, creator_id, creation_time, expiration,
+    max_uses, use_count, revoked
+";
+
+/// Length of each randomly generated invite token.
+const TOKEN_LEN: usize = 24;
+
+/// Token that grants a chosen `AccessLevel` on a `Story` to any `User` who redeems it, whether or
+/// not they've logged in before the invite was created.
+pub struct Invite {
+    pub id: i64,
+    pub story_id: i64,
+    pub token: String,
+    pub access_level: AccessLevel,
+    pub creator_id: Option<i64>,
+    pub creation_time: DateTime<UTC>,
+    pub expiration: Option<DateTime<UTC>>,
+    pub max_uses: Option<i32>,
+    pub use_count: i32,
+    pub revoked: bool
+}
+
+impl Invite {
+
+    /// Initialize database tables and indices used to store `Invite` objects and their
+    /// redemptions.
+    ///
+    /// Depends on `Story::initialize` and `User::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_invites (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                token VARCHAR NOT NULL UNIQUE,
+                access_level_code INT NOT NULL,
+                creator_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                expiration TIMESTAMP WITH TIME ZONE,
+                max_uses INT,
+                use_count INT NOT NULL DEFAULT 0,
+                revoked BOOLEAN NOT NULL DEFAULT false
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS story_invites_story_id_index
+            ON story_invites (story_id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS invite_redemptions (
+                id BIGSERIAL PRIMARY KEY,
+                invite_id BIGINT NOT NULL REFERENCES story_invites (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                redemption_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                UNIQUE (invite_id, user_id)
+            )
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Create and persist a new `Invite` to a `Story` with a randomly generated token. The invite
+    /// may be redeemed until `expiration`, if given, by up to `max_uses` distinct users, if given.
+    pub fn create(conn: &GenericConnection, story: &Story, creator: &User, level: AccessLevel, expiration: Option<DateTime<UTC>>, max_uses: Option<i32>) -> FictResult<Invite> {
+        let mut rng = try!(OsRng::new());
+        let token: String = rng.gen_ascii_chars().take(TOKEN_LEN).collect();
+
+        let insertion = try!(conn.prepare(&format!("
+            INSERT INTO story_invites (story_id, token, access_level_code, creator_id, expiration, max_uses)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING {}
+        ", INVITE_COLUMNS)));
+
+        let rows = try!(insertion.query(&[
+            &story.id, &token, &level.encode(), &creator.id, &expiration, &max_uses
+        ]));
+
+        Invite::from_row(try!(first(&rows)))
+    }
+
+    /// Retrieve each `Invite` to a story, most recent first.
+    pub fn for_story(conn: &GenericConnection, story: &Story) -> FictResult<Vec<Invite>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_invites
+            WHERE story_id = $1
+            ORDER BY creation_time DESC, id DESC
+        ", INVITE_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id]));
+
+        rows.iter().map(Invite::from_row).collect()
+    }
+
+    /// Prevent any further redemptions of the invite `id` to a story. Access that it has already
+    /// granted is unaffected. If the story has no such invite, return `Err(FictError::NotFound)`.
+    pub fn revoke(conn: &GenericConnection, story: &Story, id: i64) -> FictResult<()> {
+        let count = try!(conn.execute("
+            UPDATE story_invites
+            SET revoked = true
+            WHERE id = $1 AND story_id = $2
+        ", &[&id, &story.id]));
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// Redeem an invite `token` on behalf of an authenticated `User`, granting them the invite's
+    /// access level on its `Story`. Access that the user already has is never reduced, and a user
+    /// who redeems the same invite again does not use it up further.
+    ///
+    /// If no invite has this token, return `Err(FictError::NotFound)`. If the invite has expired,
+    /// been revoked, or been used up, return `Err(FictError::InviteUnusable)`, even to users who
+    /// redeemed it while it was usable.
+    pub fn redeem(conn: &GenericConnection, token: &str, user: &User) -> FictResult<Story> {
+        let now = UTC::now();
+        let transaction = try!(conn.transaction());
+
+        let selection = try!(transaction.prepare(&format!("
+            SELECT {}
+            FROM story_invites
+            WHERE token = $1
+            FOR UPDATE
+        ", INVITE_COLUMNS)));
+
+        let rows = try!(selection.query(&[&token]));
+        let invite = match try!(first_opt(&rows)) {
+            Some(row) => try!(Invite::from_row(row)),
+            None => return Err(FictError::NotFound)
+        };
+
+        let story = try!(try!(Story::with_id(&transaction, invite.story_id)).ok_or(FictError::NotFound));
+
+        let existing = try!(transaction.prepare("
+            SELECT 1
+            FROM invite_redemptions
+            WHERE invite_id = $1 AND user_id = $2
+        "));
+
+        if try!(existing.query(&[&invite.id, &user.id])).is_empty() {
+            if ! invite.is_usable(now) {
+                return Err(FictError::InviteUnusable);
+            }
+
+            try!(transaction.execute("
+                INSERT INTO invite_redemptions (invite_id, user_id, redemption_time)
+                VALUES ($1, $2, $3)
+            ", &[&invite.id, &user.id, &now]));
+
+            try!(transaction.execute("
+                UPDATE story_invites
+                SET use_count = use_count + 1
+                WHERE id = $1
+            ", &[&invite.id]));
+
+            try!(StoryEvent::record(
+                &transaction, story.id, EventKind::InviteRedeemed, user.id, invite.creator_id,
+                Some(invite.id.to_string())
+            ));
+        } else if ! invite.is_usable(now) {
+            // Users who've since lost access may not restore it with an invite that's no longer
+            // usable.
+            return Err(FictError::InviteUnusable);
+        }
+
+        let current = try!(story.access_for(&transaction, user));
+        if invite.access_level.encode() > current.encode() {
+            try!(StoryAccess::grant(&transaction, &story, user, &invite.access_level));
+        }
+
+        try!(transaction.commit());
+
+        Ok(story)
+    }
+
+    /// Determine whether or not this invite may be redeemed by a new user at `now`.
+    pub fn is_usable(&self, now: DateTime<UTC>) -> bool {
+        ! self.revoked &&
+            self.expiration.map(|e| e > now).unwrap_or(true) &&
+            self.max_uses.map(|max| self.use_count < max).unwrap_or(true)
+    }
+
+    /// Construct an `Invite` from a row containing each of the `INVITE_COLUMNS`.
+    fn from_row(row: Row) -> FictResult<Invite> {
+        Ok(Invite{
+            id: row.get(0),
+            story_id: row.get(1),
+            token: row.get(2),
+            access_level: try!(AccessLevel::decode(row.get(3))),
+            creator_id: row.get(4),
+            creation_time: row.get(5),
+            expiration: row.get(6),
+            max_uses: row.get(7),
+            use_count: row.get(8),
+            revoked: row.get(9)
+        })
+    }
+
+}
@@ -0,0 +1,256 @@
+//! Requests from users who would like to contribute to a story that's open to join requests.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+
+use model::{first, first_opt, Story, StoryAccess, AccessLevel, User};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `JoinRequest`, in the order expected by
+/// `JoinRequest::from_row`.
+const JOIN_REQUEST_COLUMNS: &'static str = "
+    join_requests.id, join_requests.story_id, join_requests.user_id, users.name,
+    join_requests.message, join_requests.state_code, join_requests.creation_time,
+    join_requests.decision_time, join_requests.decider_id
+";
+
+/// Progress of a `JoinRequest` through its story owner's queue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinState {
+    Pending,
+    Approved,
+    Denied
+}
+
+impl JoinState {
+
+    /// Convert a JoinState into an integer for serialization within a database table.
+    fn encode(&self) -> i32 {
+        match *self {
+            JoinState::Pending => 0,
+            JoinState::Approved => 1,
+            JoinState::Denied => 2
+        }
+    }
+
+    /// Create a JoinState from an integer previously encoded with `::encode()`. Produce a default
+    /// that neither grants access nor reopens the request, and log a warning, if the code is
+    /// unrecognized.
+    fn decode(value: i32) -> JoinState {
+        match value {
+            0 => JoinState::Pending,
+            1 => JoinState::Approved,
+            2 => JoinState::Denied,
+            _ => {
+                warn!("Invalid encoded join request state [{}]. Defaulting to denied.", value);
+                JoinState::Denied
+            }
+        }
+    }
+
+    /// Name used for this state within response documents.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JoinState::Pending => "pending",
+            JoinState::Approved => "approved",
+            JoinState::Denied => "denied"
+        }
+    }
+
+}
+
+/// Request by a `User` for Writer access to a `Story`, awaiting a decision by one of its owners.
+pub struct JoinRequest {
+    pub id: i64,
+    pub story_id: i64,
+    pub user_id: i64,
+    pub username: String,
+    pub message: Option<String>,
+    pub state: JoinState,
+    pub creation_time: DateTime<UTC>,
+    pub decision_time: Option<DateTime<UTC>>,
+    pub decider_id: Option<i64>
+}
+
+impl JoinRequest {
+
+    /// Initialize database tables and indices used to store `JoinRequest` objects.
+    ///
+    /// Depends on `Story::initialize` and `User::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS join_requests (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                message VARCHAR,
+                state_code INT NOT NULL DEFAULT 0,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                decision_time TIMESTAMP WITH TIME ZONE,
+                decider_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                UNIQUE (story_id, user_id)
+            )
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Ask for Writer access to a `Story` that's open to join requests, on behalf of `user`.
+    ///
+    /// If the story does not exist, or is neither open to join requests nor readable by `user`,
+    /// return `Err(FictError::NotFound)`. If it's readable but closed to join requests, return
+    /// `Err(FictError::Forbidden)`. If `user` may already contribute to the story, or already
+    /// has a request pending or approved, return `Err(FictError::Duplicate)`. Users whose earlier
+    /// request was denied may ask again.
+    pub fn submit(conn: &GenericConnection, story_id: i64, user: &User, message: Option<String>) -> FictResult<JoinRequest> {
+        let transaction = try!(conn.transaction());
+
+        let story = try!(try!(Story::with_id(&transaction, story_id)).ok_or(FictError::NotFound));
+        let access = try!(story.access_for(&transaction, user));
+
+        if ! story.open_to_join {
+            return Err(if access.grants_read() { FictError::Forbidden } else { FictError::NotFound });
+        }
+
+        if access.grants_write() {
+            return Err(FictError::Duplicate);
+        }
+
+        let existing = try!(transaction.prepare("
+            SELECT state_code
+            FROM join_requests
+            WHERE story_id = $1 AND user_id = $2
+            FOR UPDATE
+        "));
+
+        let rows = try!(existing.query(&[&story.id, &user.id]));
+        let id: i64 = match try!(first_opt(&rows)).map(|row| JoinState::decode(row.get(0))) {
+            Some(JoinState::Denied) => {
+                let update = try!(transaction.prepare("
+                    UPDATE join_requests
+                    SET
+                        message = $3,
+                        state_code = $4,
+                        creation_time = (now() AT TIME ZONE 'utc'),
+                        decision_time = NULL,
+                        decider_id = NULL
+                    WHERE story_id = $1 AND user_id = $2
+                    RETURNING id
+                "));
+
+                let rows = try!(update.query(&[&story.id, &user.id, &message, &JoinState::Pending.encode()]));
+                try!(first(&rows)).get(0)
+            },
+            Some(_) => return Err(FictError::Duplicate),
+            None => {
+                let insertion = try!(transaction.prepare("
+                    INSERT INTO join_requests (story_id, user_id, message)
+                    VALUES ($1, $2, $3)
+                    RETURNING id
+                "));
+
+                let rows = try!(insertion.query(&[&story.id, &user.id, &message]));
+                try!(first(&rows)).get(0)
+            }
+        };
+
+        let request = try!(try!(JoinRequest::with_id(&transaction, story.id, id)).ok_or(FictError::NotFound));
+
+        try!(transaction.commit());
+
+        Ok(request)
+    }
+
+    /// Retrieve each pending `JoinRequest` for a `Story`, oldest first.
+    pub fn pending_for(conn: &GenericConnection, story: &Story) -> FictResult<Vec<JoinRequest>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM join_requests
+            INNER JOIN users ON users.id = join_requests.user_id
+            WHERE join_requests.story_id = $1 AND join_requests.state_code = $2
+            ORDER BY join_requests.creation_time ASC, join_requests.id ASC
+        ", JOIN_REQUEST_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id, &JoinState::Pending.encode()]));
+
+        Ok(rows.iter().map(JoinRequest::from_row).collect())
+    }
+
+    /// Approve or deny the pending request `id` to join a `Story` on behalf of one of its owners.
+    /// Approval grants the requester Writer access.
+    ///
+    /// If the story has no such pending request, return `Err(FictError::NotFound)`.
+    pub fn decide(conn: &GenericConnection, story: &Story, id: i64, decider: &User, approve: bool) -> FictResult<JoinRequest> {
+        let now = UTC::now();
+        let transaction = try!(conn.transaction());
+
+        let state = if approve { JoinState::Approved } else { JoinState::Denied };
+
+        let count = try!(transaction.execute("
+            UPDATE join_requests
+            SET
+                state_code = $3,
+                decision_time = $4,
+                decider_id = $5
+            WHERE id = $1 AND story_id = $2 AND state_code = $6
+        ", &[&id, &story.id, &state.encode(), &now, &decider.id, &JoinState::Pending.encode()]));
+
+        if count != 1 {
+            return Err(FictError::NotFound);
+        }
+
+        let request = try!(try!(JoinRequest::with_id(&transaction, story.id, id)).ok_or(FictError::NotFound));
+
+        if approve {
+            let requester = try!(User::with_id(&transaction, request.user_id));
+
+            // Approval never reduces access that the requester was granted some other way.
+            if ! try!(story.access_for(&transaction, &requester)).grants_write() {
+                try!(StoryAccess::grant(&transaction, story, &requester, &AccessLevel::Writer));
+            }
+        }
+
+        try!(transaction.commit());
+
+        Ok(request)
+    }
+
+    /// Search for an existing `JoinRequest` to a story by ID.
+    fn with_id(conn: &GenericConnection, story_id: i64, id: i64) -> FictResult<Option<JoinRequest>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM join_requests
+            INNER JOIN users ON users.id = join_requests.user_id
+            WHERE join_requests.id = $1 AND join_requests.story_id = $2
+        ", JOIN_REQUEST_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id, &story_id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(JoinRequest::from_row))
+    }
+
+    /// Construct a `JoinRequest` from a row containing each of the `JOIN_REQUEST_COLUMNS`.
+    fn from_row(row: Row) -> JoinRequest {
+        JoinRequest{
+            id: row.get(0),
+            story_id: row.get(1),
+            user_id: row.get(2),
+            username: row.get(3),
+            message: row.get(4),
+            state: JoinState::decode(row.get(5)),
+            creation_time: row.get(6),
+            decision_time: row.get(7),
+            decider_id: row.get(8)
+        }
+    }
+
+}
@@ -0,0 +1,62 @@
+//! Log of email notifications that couldn't be sent.
+
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+
+use model::{User, NotificationKind};
+use error::FictResult;
+
+/// Email notification of `kind` that couldn't be sent to a `User`, and why. `event_id` is the
+/// event it would have reported, if it was sent in response to one rather than as a digest.
+/// Failed notifications aren't retried, so that one undeliverable address never holds up anyone
+/// else's.
+pub struct MailFailure {
+    pub id: i64,
+    pub user_id: i64,
+    pub kind: NotificationKind,
+    pub event_id: Option<i64>,
+    pub error: String,
+    pub creation_time: DateTime<UTC>
+}
+
+impl MailFailure {
+
+    /// Initialize database tables and indices used to store `MailFailure` objects.
+    ///
+    /// Depends on `User::initialize` and `StoryEvent::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS mail_failures (
+                id BIGSERIAL PRIMARY KEY,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                kind VARCHAR NOT NULL,
+                event_id BIGINT REFERENCES story_events (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                error VARCHAR NOT NULL,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc')
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS mail_failures_user_id_index
+            ON mail_failures (user_id, id)
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Record that a notification couldn't be sent to `recipient`.
+    pub fn record(conn: &GenericConnection, recipient: &User, kind: &NotificationKind, event_id: Option<i64>, error: &str) -> FictResult<()> {
+        try!(conn.execute("
+            INSERT INTO mail_failures (user_id, kind, event_id, error)
+            VALUES ($1, $2, $3, $4)
+        ", &[&recipient.id, &kind.name(), &event_id, &error]));
+
+        Ok(())
+    }
+
+}
@@ -1,51 +1,125 @@
 //! Data model and PostgreSQL storage abstraction.
 
 use std::env;
-use std::default::Default;
+use std::sync::Arc;
 
-use iron::Chain;
+use iron::{Chain, Request, IronResult};
 use iron::typemap::Key;
-use persistent::Write;
+use persistent::Read;
+use postgres::GenericConnection;
 use postgres::rows::{Rows, Row};
-use r2d2::Pool;
+use r2d2::{Pool, Config, PooledConnection};
 use r2d2_postgres::{PostgresConnectionManager, SslMode};
 
-use error::FictResult;
-use error::FictError::{NotFound};
+use config::env_opt;
+use error::{FictResult, IntoIronResult};
+use error::FictError::{NotFound, Unavailable};
 
 mod user;
 mod session;
 mod story;
 mod snippet;
+mod revision;
+mod retraction;
+mod validation;
+mod policy;
+mod prompt;
+mod round;
+mod constraint;
+mod invite;
+mod join_request;
+mod group;
+mod event;
+mod webhook;
+mod preference;
+mod digest;
+mod notification;
+mod mail_failure;
 
 pub use self::user::User;
 pub use self::session::Session;
-pub use self::story::{Story, StoryAccess, AccessLevel, ContributionAttempt};
+pub use self::story::{Story, StoryAccess, AccessLevel, ContributionAttempt, SprintTurn, LockGrant};
 pub use self::snippet::Snippet;
+pub use self::revision::SnippetRevision;
+pub use self::retraction::Retraction;
+pub use self::validation::{ContentLimits, normalize, normalize_title, count_words};
+pub use self::policy::{TurnPolicy, TurnPolicySettings, Turn};
+pub use self::policy::{VisibilityPolicy, VisibilitySettings, Excerpt};
+pub use self::policy::{CompletionRules, CompletionSettings, StoryMode, StoryModeSettings};
+pub use self::policy::{RevealPolicy, RevealSettings};
+pub use self::prompt::{StoryPrompt, PromptSettings};
+pub use self::round::{VotingRound, Candidate};
+pub use self::constraint::{Constraint, ConstraintSettings};
+pub use self::invite::Invite;
+pub use self::join_request::{JoinRequest, JoinState};
+pub use self::group::{Group, GroupGrant};
+pub use self::event::{StoryEvent, EventKind, EventPosition};
+pub use self::webhook::{Webhook, WebhookDelivery, DeliveryState};
+pub use self::preference::{NotificationPreferences, NotificationKind, LockWaiter};
+pub use self::digest::{Digest, DigestEntry};
+pub use self::notification::Notification;
+pub use self::mail_failure::MailFailure;
 
 /// Database is the type key used to access the connection pool.
 pub struct Database;
 
 pub type PostgresPool = Pool<PostgresConnectionManager>;
 
+pub type PostgresConnection = PooledConnection<PostgresConnectionManager>;
+
 impl Key for Database {
     type Value = PostgresPool;
 }
 
 impl Database {
-    pub fn link(chain: &mut Chain) -> FictResult<()> {
+    /// Create the connection pool, initialize the database, and make the pool available to each
+    /// request handled by `chain`. Produce the pool for use outside of requests, too.
+    pub fn link(chain: &mut Chain) -> FictResult<PostgresPool> {
         let pg_address = try!(env::var("FICTION_PG"));
 
-        let config = Default::default();
+        let mut config = Config::builder();
+
+        if let Some(size) = try!(env_opt::<u32>("FICTION_PG_POOL_SIZE")) {
+            config = config.pool_size(size);
+        }
+
+        if let Some(timeout_ms) = try!(env_opt::<u32>("FICTION_PG_TIMEOUT_MS")) {
+            config = config.connection_timeout_ms(timeout_ms);
+        }
+
         let manager = try!(PostgresConnectionManager::new(&*pg_address, SslMode::None));
-        let pool = try!(Pool::new(config, manager));
+        let pool = try!(Pool::new(config.build(), manager));
 
         try!(Database::initialize(&pool));
 
-        let w = Write::<Database>::one(pool);
-        chain.link_before(w);
+        let r = Read::<Database>::one(pool.clone());
+        chain.link_before(r);
 
-        Ok(())
+        Ok(pool)
+    }
+
+    /// Check out a connection from the pool for the duration of a request. If no connection
+    /// becomes available before the pool's timeout elapses, fail with a 503 rather than tying up
+    /// the request thread.
+    ///
+    /// Panics if `Database::link` has not been called on the request's chain.
+    pub fn connection(req: &Request) -> IronResult<PostgresConnection> {
+        Database::pool(req).get()
+            .map_err(|e| {
+                warn!("Unable to acquire a database connection: [{}]", e);
+                Unavailable
+            })
+            .iron()
+    }
+
+    /// Share the connection pool with work that outlives the request's own handling, like a
+    /// response body that's streamed to the client over time.
+    ///
+    /// Panics if `Database::link` has not been called on the request's chain.
+    pub fn pool(req: &Request) -> Arc<PostgresPool> {
+        req.extensions.get::<Read<Database>>()
+            .cloned()
+            .expect("No database connection available")
     }
 
     fn initialize(pool: &PostgresPool) -> FictResult<()> {
@@ -57,8 +131,21 @@ impl Database {
         try!(Session::initialize(&*conn));
         try!(Story::initialize(&*conn));
         try!(StoryAccess::initialize(&*conn));
+        try!(Group::initialize(&*conn));
+        try!(StoryPrompt::initialize(&*conn));
+        try!(Constraint::initialize(&*conn));
+        try!(Invite::initialize(&*conn));
+        try!(JoinRequest::initialize(&*conn));
         try!(ContributionAttempt::initialize(&*conn));
         try!(Snippet::initialize(&*conn));
+        try!(SnippetRevision::initialize(&*conn));
+        try!(Retraction::initialize(&*conn));
+        try!(VotingRound::initialize(&*conn));
+        try!(StoryEvent::initialize(&*conn));
+        try!(Webhook::initialize(&*conn));
+        try!(NotificationPreferences::initialize(&*conn));
+        try!(Notification::initialize(&*conn));
+        try!(MailFailure::initialize(&*conn));
 
         Ok(())
     }
@@ -83,3 +170,24 @@ fn first<'a>(results: &'a Rows) -> FictResult<Row<'a>> {
     first_opt(results)
         .and_then(|r| r.ok_or(NotFound))
 }
+
+/// Add a column to a table created by an earlier version of its `initialize` method, if it isn't
+/// already present. Return `true` if the column was added, so that the caller can backfill
+/// existing rows.
+fn ensure_column(conn: &GenericConnection, table: &str, column: &str, definition: &str) -> FictResult<bool> {
+    let selection = try!(conn.prepare("
+        SELECT 1
+        FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = $1 AND column_name = $2
+    "));
+
+    let rows = try!(selection.query(&[&table, &column]));
+    if !rows.is_empty() {
+        return Ok(false);
+    }
+
+    info!("Adding column {}.{}.", table, column);
+    try!(conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), &[]));
+
+    Ok(true)
+}
@@ -0,0 +1,223 @@
+//! In-app notifications of story activity, and the stories that each user follows.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+
+use model::{first, Story, StoryEvent, EventKind, User};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `Notification`, in the order expected by
+/// `Notification::from_row`.
+const NOTIFICATION_COLUMNS: &'static str = "
+    notifications.id, notifications.user_id, notifications.read_time,
+    notifications.creation_time,
+    story_events.id, story_events.story_id, story_events.kind_code, story_events.actor_id,
+    story_events.subject_id, story_events.detail, story_events.creation_time,
+    story_events.txid
+";
+
+/// `StoryEvent` delivered to the inbox of a single `User`, who may mark it as read.
+pub struct Notification {
+    pub id: i64,
+    pub user_id: i64,
+    pub read_time: Option<DateTime<UTC>>,
+    pub creation_time: DateTime<UTC>,
+    pub event: StoryEvent
+}
+
+impl Notification {
+
+    /// Initialize database tables and indices used to store `Notification` objects and story
+    /// follows.
+    ///
+    /// Depends on `User::initialize`, `Story::initialize`, and `StoryEvent::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS notifications (
+                id BIGSERIAL PRIMARY KEY,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                event_id BIGINT NOT NULL REFERENCES story_events (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                read_time TIMESTAMP WITH TIME ZONE,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                UNIQUE (user_id, event_id)
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS notifications_unread_index
+            ON notifications (user_id, id)
+            WHERE read_time IS NULL
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_follows (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                UNIQUE (story_id, user_id)
+            )
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Deliver an event to the inbox of each of a set of users. Users who have already received
+    /// it are skipped.
+    pub fn deliver(conn: &GenericConnection, event: &StoryEvent, recipients: &[User]) -> FictResult<()> {
+        let insertion = try!(conn.prepare("
+            INSERT INTO notifications (user_id, event_id)
+            SELECT $1, $2
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM notifications
+                WHERE user_id = $1 AND event_id = $2
+            )
+        "));
+
+        for recipient in recipients.iter() {
+            try!(insertion.execute(&[&recipient.id, &event.id]));
+        }
+
+        Ok(())
+    }
+
+    /// Retrieve up to `limit` notifications from the inbox of a `User`, most recent first. If
+    /// `unread_only` is set, notifications that have been read are skipped.
+    pub fn for_user(conn: &GenericConnection, user: &User, unread_only: bool, limit: i64) -> FictResult<Vec<Notification>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM notifications
+            INNER JOIN story_events ON story_events.id = notifications.event_id
+            WHERE notifications.user_id = $1 AND (NOT $2 OR notifications.read_time IS NULL)
+            ORDER BY notifications.id DESC
+            LIMIT $3
+        ", NOTIFICATION_COLUMNS)));
+
+        let rows = try!(selection.query(&[&user.id, &unread_only, &limit]));
+
+        Ok(rows.iter().filter_map(Notification::from_row).collect())
+    }
+
+    /// Count the notifications in the inbox of a `User` that they haven't read yet.
+    pub fn unread_count(conn: &GenericConnection, user: &User) -> FictResult<i64> {
+        let selection = try!(conn.prepare("
+            SELECT COUNT(*)
+            FROM notifications
+            WHERE user_id = $1 AND read_time IS NULL
+        "));
+
+        let rows = try!(selection.query(&[&user.id]));
+
+        Ok(try!(first(&rows)).get(0))
+    }
+
+    /// Mark the notification `id` in the inbox of a `User` as read at `now`. Marking it again has
+    /// no effect. If the user has no such notification, return `Err(FictError::NotFound)`.
+    pub fn mark_read(conn: &GenericConnection, user: &User, id: i64, now: DateTime<UTC>) -> FictResult<()> {
+        let count = try!(conn.execute("
+            UPDATE notifications
+            SET read_time = COALESCE(read_time, $3)
+            WHERE id = $1 AND user_id = $2
+        ", &[&id, &user.id, &now]));
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// Mark each unread notification in the inbox of a `User` as read at `now`. Produce the
+    /// number of notifications marked.
+    pub fn mark_all_read(conn: &GenericConnection, user: &User, now: DateTime<UTC>) -> FictResult<u64> {
+        let count = try!(conn.execute("
+            UPDATE notifications
+            SET read_time = $2
+            WHERE user_id = $1 AND read_time IS NULL
+        ", &[&user.id, &now]));
+
+        Ok(count)
+    }
+
+    /// Start following a `Story`, so that each new snippet contributed to it is delivered to your
+    /// inbox. Following it again has no effect.
+    pub fn follow(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<()> {
+        try!(conn.execute("
+            INSERT INTO story_follows (story_id, user_id)
+            SELECT $1, $2
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM story_follows
+                WHERE story_id = $1 AND user_id = $2
+            )
+        ", &[&story.id, &user.id]));
+
+        Ok(())
+    }
+
+    /// Stop following a `Story`. If the user wasn't following it, return
+    /// `Err(FictError::NotFound)`.
+    pub fn unfollow(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<()> {
+        let count = try!(conn.execute("
+            DELETE FROM story_follows
+            WHERE story_id = $1 AND user_id = $2
+        ", &[&story.id, &user.id]));
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// List each `User` who follows a `Story`, in the order that they began to follow it.
+    pub fn followers(conn: &GenericConnection, story: &Story) -> FictResult<Vec<User>> {
+        let selection = try!(conn.prepare("
+            SELECT users.id, users.name, users.email
+            FROM story_follows
+            INNER JOIN users ON users.id = story_follows.user_id
+            WHERE story_follows.story_id = $1
+            ORDER BY story_follows.id ASC
+        "));
+
+        let rows = try!(selection.query(&[&story.id]));
+
+        Ok(rows.iter().map(|row| User{
+            id: Some(row.get(0)),
+            name: row.get(1),
+            email: row.get(2)
+        }).collect())
+    }
+
+    /// Construct a `Notification` from a row containing each of the `NOTIFICATION_COLUMNS`.
+    /// Produce `None` if its event's kind is unrecognized.
+    fn from_row(row: Row) -> Option<Notification> {
+        EventKind::decode(row.get(6)).map(|kind| Notification{
+            id: row.get(0),
+            user_id: row.get(1),
+            read_time: row.get(2),
+            creation_time: row.get(3),
+            event: StoryEvent{
+                id: row.get(4),
+                story_id: row.get(5),
+                kind: kind,
+                actor_id: row.get(7),
+                subject_id: row.get(8),
+                detail: row.get(9),
+                creation_time: row.get(10),
+                txid: row.get(11)
+            }
+        })
+    }
+
+}
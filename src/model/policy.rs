@@ -0,0 +1,741 @@
+//! Per-story rules that govern how writers take turns and what they see when they do.
+
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+
+use model::{first_opt, Story, StoryAccess, AccessLevel, User, ContributionAttempt, Snippet};
+use error::{FictResult, FieldError, fict_err};
+
+/// Rule used by `Story::locked_for_write` to decide whether or not a `User` may take the next
+/// turn in a `Story`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TurnPolicy {
+    /// At least this many `Snippets` must be contributed by others between any two contributions
+    /// by the same user. `OthersBetween(1)` is the default.
+    OthersBetween(i32),
+
+    /// Writers take turns in the order that they were granted access to the story.
+    RoundRobin,
+
+    /// Users must wait this many seconds after contributing before they may contribute again.
+    Cooldown(i64)
+}
+
+/// Outcome of evaluating a `TurnPolicy` for a specific applicant.
+pub enum Turn {
+    Permitted,
+    TooSoon,
+    Awaiting(User)
+}
+
+impl Default for TurnPolicy {
+    fn default() -> TurnPolicy {
+        TurnPolicy::OthersBetween(1)
+    }
+}
+
+/// External representation of a `TurnPolicy` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct TurnPolicySettings {
+    pub kind: String,
+    pub count: Option<i32>,
+    pub cooldown_s: Option<i64>
+}
+
+impl TurnPolicy {
+
+    /// Convert a TurnPolicy into the `(turn_policy_code, turn_policy_count,
+    /// turn_policy_cooldown_s)` columns used to store it within the stories table.
+    pub fn encode(&self) -> (i32, i32, i64) {
+        match *self {
+            TurnPolicy::OthersBetween(count) => (0, count, 0),
+            TurnPolicy::RoundRobin => (1, 0, 0),
+            TurnPolicy::Cooldown(seconds) => (2, 0, seconds)
+        }
+    }
+
+    /// Create a TurnPolicy from columns previously encoded with `::encode()`. Fall back to the
+    /// default policy if the code is unrecognized.
+    pub fn decode(code: i32, count: i32, cooldown_s: i64) -> TurnPolicy {
+        match code {
+            0 => TurnPolicy::OthersBetween(count),
+            1 => TurnPolicy::RoundRobin,
+            2 => TurnPolicy::Cooldown(cooldown_s),
+            _ => {
+                warn!("Invalid encoded turn policy [{}]. Using the default.", code);
+                Default::default()
+            }
+        }
+    }
+
+    /// Produce the external representation of this policy.
+    pub fn settings(&self) -> TurnPolicySettings {
+        match *self {
+            TurnPolicy::OthersBetween(count) => TurnPolicySettings{
+                kind: "others_between".to_owned(), count: Some(count), cooldown_s: None
+            },
+            TurnPolicy::RoundRobin => TurnPolicySettings{
+                kind: "round_robin".to_owned(), count: None, cooldown_s: None
+            },
+            TurnPolicy::Cooldown(seconds) => TurnPolicySettings{
+                kind: "cooldown".to_owned(), count: None, cooldown_s: Some(seconds)
+            }
+        }
+    }
+
+    /// Interpret and validate an external representation of a policy. Report any problems as
+    /// errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &TurnPolicySettings, prefix: &str) -> Result<TurnPolicy, Vec<FieldError>> {
+        match &settings.kind[..] {
+            "others_between" => match settings.count {
+                Some(count) if count >= 0 => Ok(TurnPolicy::OthersBetween(count)),
+                _ => Err(vec![FieldError::new(
+                    format!("{}.count", prefix), "invalid",
+                    "A non-negative count of intervening contributions is required."
+                )])
+            },
+            "round_robin" => Ok(TurnPolicy::RoundRobin),
+            "cooldown" => match settings.cooldown_s {
+                Some(seconds) if seconds >= 0 => Ok(TurnPolicy::Cooldown(seconds)),
+                _ => Err(vec![FieldError::new(
+                    format!("{}.cooldown_s", prefix), "invalid",
+                    "A non-negative cooldown in seconds is required."
+                )])
+            },
+            _ => Err(vec![FieldError::new(
+                format!("{}.kind", prefix), "unknown",
+                "Turn policy must be one of: others_between, round_robin, cooldown."
+            )])
+        }
+    }
+
+    /// Decide whether or not `applicant` may lock `story` to take the next turn at `now`.
+    pub fn evaluate(&self, conn: &GenericConnection, story: &Story, applicant: &User, now: DateTime<UTC>) -> FictResult<Turn> {
+        let attempt = try!(ContributionAttempt::most_recent_attempt(conn, story, applicant));
+
+        // An applicant who has already seen the most recent Snippet may always lock the story
+        // again, for example after letting an earlier lock expire.
+        if attempt == Some(story.contribution_count) {
+            return Ok(Turn::Permitted);
+        }
+
+        match *self {
+            TurnPolicy::OthersBetween(count) => {
+                // Permit the lock to continue if:
+                // 1. at least `count` other Snippets have been contributed since the last attempt
+                //    (attempt + 1 + count <= contribution count)
+                // OR
+                // 2. applicant has *never* locked the story (None)
+                let permitted = attempt
+                    .map(|a| a + 1 + count <= story.contribution_count)
+                    .unwrap_or(true);
+
+                Ok(if permitted { Turn::Permitted } else { Turn::TooSoon })
+            },
+            TurnPolicy::RoundRobin => {
+                let next = try!(next_in_rotation(conn, story));
+
+                if next.id == applicant.id {
+                    Ok(Turn::Permitted)
+                } else {
+                    Ok(Turn::Awaiting(next))
+                }
+            },
+            TurnPolicy::Cooldown(seconds) => {
+                let last = try!(last_contribution_time(conn, story, applicant));
+                let permitted = last
+                    .map(|t| t + Duration::seconds(seconds) <= now)
+                    .unwrap_or(true);
+
+                Ok(if permitted { Turn::Permitted } else { Turn::TooSoon })
+            }
+        }
+    }
+
+}
+
+/// Identify the writer whose turn follows that of the most recent contributor, in the order that
+/// writers were granted access to the story. If the most recent contributor is no longer a writer,
+/// or nobody has contributed yet, the first writer is next.
+fn next_in_rotation(conn: &GenericConnection, story: &Story) -> FictResult<User> {
+    let writers = try!(StoryAccess::writers(conn, story));
+
+    let selection = try!(conn.prepare("
+        SELECT user_id
+        FROM story_snippets($1)
+        ORDER BY position DESC
+        LIMIT 1
+    "));
+
+    let rows = try!(selection.query(&[&story.id]));
+    let last_author: Option<i64> = try!(first_opt(&rows)).and_then(|row| row.get(0));
+
+    let next_index = last_author
+        .and_then(|author_id| writers.iter().position(|w| w.id == Some(author_id)))
+        .map(|i| (i + 1) % writers.len())
+        .unwrap_or(0);
+
+    writers.into_iter().nth(next_index)
+        .ok_or(fict_err(format!("Story {} has no writers", story.id)))
+}
+
+/// Find the time of the most recent `Snippet` contributed to a story by a specific `User`.
+fn last_contribution_time(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<Option<DateTime<UTC>>> {
+    let selection = try!(conn.prepare("
+        SELECT MAX(creation_time)
+        FROM story_snippets($1)
+        WHERE user_id = $2
+    "));
+
+    let rows = try!(selection.query(&[&story.id, &user.id]));
+
+    Ok(try!(first_opt(&rows)).and_then(|row| row.get(0)))
+}
+
+/// Portion of a `Story`'s prior content that's revealed to a writer when they lock it to take
+/// the next turn.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VisibilityPolicy {
+    /// The full content of this many of the most recent `Snippets`. `LastSnippets(1)` is the
+    /// default.
+    LastSnippets(i32),
+
+    /// Only this many words from the end of the most recent `Snippet`.
+    LastWords(i32),
+
+    /// Only this many sentences from the end of the most recent `Snippet`.
+    LastSentences(i32),
+
+    /// Nothing at all.
+    Hidden
+}
+
+impl Default for VisibilityPolicy {
+    fn default() -> VisibilityPolicy {
+        VisibilityPolicy::LastSnippets(1)
+    }
+}
+
+/// External representation of a `VisibilityPolicy` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VisibilitySettings {
+    pub kind: String,
+    pub count: Option<i32>
+}
+
+/// Content from a prior `Snippet` that a `VisibilityPolicy` reveals. `truncated` is `true` if
+/// some of the snippet's content has been withheld.
+pub struct Excerpt {
+    pub position: i32,
+    pub content: String,
+    pub truncated: bool
+}
+
+impl VisibilityPolicy {
+
+    /// Convert a VisibilityPolicy into the `(visibility_code, visibility_count)` columns used to
+    /// store it within the stories table.
+    pub fn encode(&self) -> (i32, i32) {
+        match *self {
+            VisibilityPolicy::LastSnippets(count) => (0, count),
+            VisibilityPolicy::LastWords(count) => (1, count),
+            VisibilityPolicy::LastSentences(count) => (2, count),
+            VisibilityPolicy::Hidden => (3, 0)
+        }
+    }
+
+    /// Create a VisibilityPolicy from columns previously encoded with `::encode()`. Fall back to
+    /// the default policy if the code is unrecognized.
+    pub fn decode(code: i32, count: i32) -> VisibilityPolicy {
+        match code {
+            0 => VisibilityPolicy::LastSnippets(count),
+            1 => VisibilityPolicy::LastWords(count),
+            2 => VisibilityPolicy::LastSentences(count),
+            3 => VisibilityPolicy::Hidden,
+            _ => {
+                warn!("Invalid encoded visibility policy [{}]. Using the default.", code);
+                Default::default()
+            }
+        }
+    }
+
+    /// Produce the external representation of this policy.
+    pub fn settings(&self) -> VisibilitySettings {
+        let (kind, count) = match *self {
+            VisibilityPolicy::LastSnippets(count) => ("snippets", Some(count)),
+            VisibilityPolicy::LastWords(count) => ("words", Some(count)),
+            VisibilityPolicy::LastSentences(count) => ("sentences", Some(count)),
+            VisibilityPolicy::Hidden => ("none", None)
+        };
+
+        VisibilitySettings{ kind: kind.to_owned(), count: count }
+    }
+
+    /// Interpret and validate an external representation of a policy. Report any problems as
+    /// errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &VisibilitySettings, prefix: &str) -> Result<VisibilityPolicy, Vec<FieldError>> {
+        let count_error = || vec![FieldError::new(
+            format!("{}.count", prefix), "invalid", "A positive count is required."
+        )];
+
+        match (&settings.kind[..], settings.count) {
+            ("none", _) => Ok(VisibilityPolicy::Hidden),
+            ("snippets", Some(count)) if count > 0 => Ok(VisibilityPolicy::LastSnippets(count)),
+            ("words", Some(count)) if count > 0 => Ok(VisibilityPolicy::LastWords(count)),
+            ("sentences", Some(count)) if count > 0 => Ok(VisibilityPolicy::LastSentences(count)),
+            ("snippets", _) | ("words", _) | ("sentences", _) => Err(count_error()),
+            _ => Err(vec![FieldError::new(
+                format!("{}.kind", prefix), "unknown",
+                "Visibility policy must be one of: snippets, words, sentences, none."
+            )])
+        }
+    }
+
+    /// Gather the prior content of `story` that this policy reveals to the next writer, oldest
+    /// first.
+    pub fn excerpts(&self, conn: &GenericConnection, story: &Story) -> FictResult<Vec<Excerpt>> {
+        let snippet_count = match *self {
+            VisibilityPolicy::LastSnippets(count) => count,
+            VisibilityPolicy::LastWords(..) | VisibilityPolicy::LastSentences(..) => 1,
+            VisibilityPolicy::Hidden => return Ok(Vec::new())
+        };
+
+        let snippets = try!(Snippet::most_recent_n(conn, story, snippet_count as i64));
+
+        Ok(snippets.into_iter().map(|snippet| {
+            let (content, truncated) = match *self {
+                VisibilityPolicy::LastWords(count) => tail_words(&snippet.content, count as usize),
+                VisibilityPolicy::LastSentences(count) => tail_sentences(&snippet.content, count as usize),
+                _ => (&snippet.content[..], false)
+            };
+
+            Excerpt{
+                position: snippet.position,
+                content: content.to_owned(),
+                truncated: truncated
+            }
+        }).collect())
+    }
+
+}
+
+/// Slice the final `count` whitespace-separated words from `content`, preserving the whitespace
+/// between them. Report whether or not anything was omitted.
+fn tail_words(content: &str, count: usize) -> (&str, bool) {
+    let mut starts = Vec::new();
+    let mut in_word = false;
+
+    for (i, c) in content.char_indices() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if ! in_word {
+            in_word = true;
+            starts.push(i);
+        }
+    }
+
+    tail_from(content, &starts, count)
+}
+
+/// Slice the final `count` sentences from `content`. A sentence ends with `.`, `!` or `?`
+/// followed by whitespace. Report whether or not anything was omitted.
+fn tail_sentences(content: &str, count: usize) -> (&str, bool) {
+    let mut starts = Vec::new();
+    let mut ended = true;
+
+    for (i, c) in content.char_indices() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        if ended {
+            starts.push(i);
+        }
+
+        ended = match content[i..].chars().nth(1) {
+            Some(next) => (c == '.' || c == '!' || c == '?') && next.is_whitespace(),
+            None => false
+        };
+    }
+
+    tail_from(content, &starts, count)
+}
+
+/// Slice `content` from the `count`th-to-last of a sequence of starting offsets.
+fn tail_from<'a>(content: &'a str, starts: &[usize], count: usize) -> (&'a str, bool) {
+    if starts.len() <= count {
+        (content, false)
+    } else {
+        (&content[starts[starts.len() - count]..], true)
+    }
+}
+
+/// Conditions under which a `Story` is considered finished. A condition of `None` is not
+/// evaluated.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionRules {
+    pub max_snippets: Option<i32>,
+    pub word_target: Option<i32>,
+    pub deadline: Option<DateTime<UTC>>,
+    pub auto_publish: bool
+}
+
+/// External representation of `CompletionRules` within request and response documents. The
+/// deadline uses the API's RFC 2822 timestamp format.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct CompletionSettings {
+    pub max_snippets: Option<i32>,
+    pub word_target: Option<i32>,
+    pub deadline: Option<String>,
+    pub auto_publish: Option<bool>
+}
+
+impl CompletionRules {
+
+    /// Produce the external representation of these rules.
+    pub fn settings(&self) -> CompletionSettings {
+        CompletionSettings{
+            max_snippets: self.max_snippets,
+            word_target: self.word_target,
+            deadline: self.deadline.as_ref().map(|d| d.to_rfc2822()),
+            auto_publish: Some(self.auto_publish)
+        }
+    }
+
+    /// Interpret and validate an external representation of completion rules. Report any problems
+    /// as errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &CompletionSettings, prefix: &str) -> Result<CompletionRules, Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if settings.max_snippets.map(|n| n <= 0).unwrap_or(false) {
+            errors.push(FieldError::new(
+                format!("{}.max_snippets", prefix), "not_positive", "Maximum snippet count must be positive."
+            ));
+        }
+
+        if settings.word_target.map(|n| n <= 0).unwrap_or(false) {
+            errors.push(FieldError::new(
+                format!("{}.word_target", prefix), "not_positive", "Word target must be positive."
+            ));
+        }
+
+        let deadline = match settings.deadline {
+            Some(ref text) => match DateTime::parse_from_rfc2822(text) {
+                Ok(d) => Some(d.with_timezone(&UTC)),
+                Err(_) => {
+                    errors.push(FieldError::new(
+                        format!("{}.deadline", prefix), "unparseable",
+                        "Deadline must be formatted like: Fri, 10 May 2015 17:58:28 +0000"
+                    ));
+                    None
+                }
+            },
+            None => None
+        };
+
+        if ! errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(CompletionRules{
+            max_snippets: settings.max_snippets,
+            word_target: settings.word_target,
+            deadline: deadline,
+            auto_publish: settings.auto_publish.unwrap_or(false)
+        })
+    }
+
+    /// Determine whether or not the deadline, if any, has passed at `now`.
+    pub fn deadline_passed(&self, now: DateTime<UTC>) -> bool {
+        self.deadline.map(|d| d <= now).unwrap_or(false)
+    }
+
+    /// Determine whether or not `story` has met any of these conditions at `now`.
+    pub fn satisfied_by(&self, conn: &GenericConnection, story: &Story, now: DateTime<UTC>) -> FictResult<bool> {
+        if self.deadline_passed(now) {
+            return Ok(true);
+        }
+
+        if let Some(max) = self.max_snippets {
+            if story.contribution_count >= max {
+                return Ok(true);
+            }
+        }
+
+        if let Some(target) = self.word_target {
+            if try!(Snippet::word_count(conn, story)) >= target as i64 {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+}
+
+/// How a `Story` chooses each of its `Snippets`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoryMode {
+    /// Writers take turns locking the story to contribute the next snippet. The default.
+    Locking,
+
+    /// Writers submit candidate continuations during a round lasting this many seconds. When the
+    /// round closes, the candidate with the most votes becomes the next snippet.
+    Voting(i64),
+
+    /// The lock rotates among the story's writers in turns lasting this many seconds. Writers who
+    /// let their turn expire are skipped.
+    Sprint(i64)
+}
+
+impl Default for StoryMode {
+    fn default() -> StoryMode {
+        StoryMode::Locking
+    }
+}
+
+/// External representation of a `StoryMode` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct StoryModeSettings {
+    pub kind: String,
+    pub round_duration_s: Option<i64>,
+    pub turn_duration_s: Option<i64>
+}
+
+impl StoryMode {
+
+    /// Convert a StoryMode into the `(mode_code, round_duration_s)` columns used to store it
+    /// within the stories table. Sprints store the duration of each turn as `round_duration_s`.
+    pub fn encode(&self) -> (i32, i64) {
+        match *self {
+            StoryMode::Locking => (0, 0),
+            StoryMode::Voting(seconds) => (1, seconds),
+            StoryMode::Sprint(seconds) => (2, seconds)
+        }
+    }
+
+    /// Create a StoryMode from columns previously encoded with `::encode()`. Fall back to the
+    /// default mode if the code is unrecognized.
+    pub fn decode(code: i32, round_duration_s: i64) -> StoryMode {
+        match code {
+            0 => StoryMode::Locking,
+            1 => StoryMode::Voting(round_duration_s),
+            2 => StoryMode::Sprint(round_duration_s),
+            _ => {
+                warn!("Invalid encoded story mode [{}]. Using the default.", code);
+                Default::default()
+            }
+        }
+    }
+
+    /// Produce the external representation of this mode.
+    pub fn settings(&self) -> StoryModeSettings {
+        match *self {
+            StoryMode::Locking => StoryModeSettings{
+                kind: "locking".to_owned(), round_duration_s: None, turn_duration_s: None
+            },
+            StoryMode::Voting(seconds) => StoryModeSettings{
+                kind: "voting".to_owned(), round_duration_s: Some(seconds), turn_duration_s: None
+            },
+            StoryMode::Sprint(seconds) => StoryModeSettings{
+                kind: "sprint".to_owned(), round_duration_s: None, turn_duration_s: Some(seconds)
+            }
+        }
+    }
+
+    /// Interpret and validate an external representation of a mode. Report any problems as
+    /// errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &StoryModeSettings, prefix: &str) -> Result<StoryMode, Vec<FieldError>> {
+        match &settings.kind[..] {
+            "locking" => Ok(StoryMode::Locking),
+            "voting" => match settings.round_duration_s {
+                Some(seconds) if seconds > 0 => Ok(StoryMode::Voting(seconds)),
+                _ => Err(vec![FieldError::new(
+                    format!("{}.round_duration_s", prefix), "not_positive",
+                    "A positive round duration in seconds is required."
+                )])
+            },
+            "sprint" => match settings.turn_duration_s {
+                Some(seconds) if seconds > 0 => Ok(StoryMode::Sprint(seconds)),
+                _ => Err(vec![FieldError::new(
+                    format!("{}.turn_duration_s", prefix), "not_positive",
+                    "A positive turn duration in seconds is required."
+                )])
+            },
+            _ => Err(vec![FieldError::new(
+                format!("{}.kind", prefix), "unknown",
+                "Story mode must be one of: locking, voting, sprint."
+            )])
+        }
+    }
+
+    /// Return true if this mode chooses snippets by vote.
+    pub fn is_voting(&self) -> bool {
+        match *self {
+            StoryMode::Voting(..) => true,
+            _ => false
+        }
+    }
+
+    /// Return true if this mode rotates the lock among writers on a schedule.
+    pub fn is_sprint(&self) -> bool {
+        match *self {
+            StoryMode::Sprint(..) => true,
+            _ => false
+        }
+    }
+
+}
+
+/// When the full text of a `Story` is revealed to spectators: users who may read it but not
+/// contribute to it. Spectators may always follow its lock state and contribution count.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RevealPolicy {
+    /// Snippet text is visible to readers as soon as it's contributed.
+    Immediately,
+
+    /// Snippet text is withheld from readers until the story is finished.
+    OnCompletion,
+
+    /// Snippet text is withheld from readers until the story is published. The default.
+    OnPublish
+}
+
+impl Default for RevealPolicy {
+    fn default() -> RevealPolicy {
+        RevealPolicy::OnPublish
+    }
+}
+
+/// External representation of a `RevealPolicy` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct RevealSettings {
+    pub kind: String
+}
+
+impl RevealPolicy {
+
+    /// Convert a RevealPolicy into the `reveal_code` column used to store it within the stories
+    /// table.
+    pub fn encode(&self) -> i32 {
+        match *self {
+            RevealPolicy::Immediately => 0,
+            RevealPolicy::OnCompletion => 1,
+            RevealPolicy::OnPublish => 2
+        }
+    }
+
+    /// Create a RevealPolicy from a code previously encoded with `::encode()`. Fall back to the
+    /// default policy if the code is unrecognized.
+    pub fn decode(code: i32) -> RevealPolicy {
+        match code {
+            0 => RevealPolicy::Immediately,
+            1 => RevealPolicy::OnCompletion,
+            2 => RevealPolicy::OnPublish,
+            _ => {
+                warn!("Invalid encoded reveal policy [{}]. Using the default.", code);
+                Default::default()
+            }
+        }
+    }
+
+    /// Produce the external representation of this policy.
+    pub fn settings(&self) -> RevealSettings {
+        let kind = match *self {
+            RevealPolicy::Immediately => "immediately",
+            RevealPolicy::OnCompletion => "on_completion",
+            RevealPolicy::OnPublish => "on_publish"
+        };
+
+        RevealSettings{ kind: kind.to_owned() }
+    }
+
+    /// Interpret and validate an external representation of a policy. Report any problems as
+    /// errors on fields beneath `prefix`.
+    pub fn from_settings(settings: &RevealSettings, prefix: &str) -> Result<RevealPolicy, Vec<FieldError>> {
+        match &settings.kind[..] {
+            "immediately" => Ok(RevealPolicy::Immediately),
+            "on_completion" => Ok(RevealPolicy::OnCompletion),
+            "on_publish" => Ok(RevealPolicy::OnPublish),
+            _ => Err(vec![FieldError::new(
+                format!("{}.kind", prefix), "unknown",
+                "Reveal policy must be one of: immediately, on_completion, on_publish."
+            )])
+        }
+    }
+
+    /// Determine whether or not the full text of `story` is revealed to a user with `access`.
+    /// Only spectators wait for it: writers and owners may always read everything.
+    pub fn reveals(&self, story: &Story, access: &AccessLevel) -> bool {
+        if access.grants_write() {
+            return true;
+        }
+
+        access.grants_read() && match *self {
+            RevealPolicy::Immediately => true,
+            RevealPolicy::OnCompletion => story.finished || story.published,
+            RevealPolicy::OnPublish => story.published
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use model::{Story, AccessLevel};
+    use super::RevealPolicy;
+
+    /// Which of a reader, a writer, and an owner the full text of `story` is revealed to.
+    fn revealed_to(policy: RevealPolicy, story: &Story) -> (bool, bool, bool) {
+        (
+            policy.reveals(story, &AccessLevel::Reader),
+            policy.reveals(story, &AccessLevel::Writer),
+            policy.reveals(story, &AccessLevel::Owner)
+        )
+    }
+
+    #[test]
+    fn writers_always_see_everything() {
+        let story = Story::sample(1, None);
+
+        assert!(RevealPolicy::OnPublish.reveals(&story, &AccessLevel::Writer));
+        assert!(RevealPolicy::OnCompletion.reveals(&story, &AccessLevel::Writer));
+        assert!(RevealPolicy::OnPublish.reveals(&story, &AccessLevel::Owner));
+    }
+
+    #[test]
+    fn nothing_is_revealed_without_read_access() {
+        let mut story = Story::sample(1, None);
+        story.published = true;
+
+        assert!(! RevealPolicy::Immediately.reveals(&story, &AccessLevel::NoAccess));
+        assert!(! RevealPolicy::OnPublish.reveals(&story, &AccessLevel::NoAccess));
+    }
+
+    #[test]
+    fn immediately_reveals_to_readers_in_progress() {
+        let story = Story::sample(1, None);
+
+        assert_eq!(revealed_to(RevealPolicy::Immediately, &story), (true, true, true));
+    }
+
+    #[test]
+    fn on_completion_waits_for_the_story_to_finish() {
+        let mut story = Story::sample(1, None);
+        assert_eq!(revealed_to(RevealPolicy::OnCompletion, &story), (false, true, true));
+
+        story.finished = true;
+        assert_eq!(revealed_to(RevealPolicy::OnCompletion, &story), (true, true, true));
+    }
+
+    #[test]
+    fn on_publish_waits_for_publication() {
+        let mut story = Story::sample(1, None);
+        story.finished = true;
+        assert_eq!(revealed_to(RevealPolicy::OnPublish, &story), (false, true, true));
+
+        story.published = true;
+        assert_eq!(revealed_to(RevealPolicy::OnPublish, &story), (true, true, true));
+    }
+}
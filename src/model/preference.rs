@@ -0,0 +1,365 @@
+//! Each user's choice of which notifications to receive, and the stories they're waiting to
+//! write in.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+use rand::{OsRng, Rng};
+
+use model::{first, first_opt, ensure_column, User};
+use error::FictResult;
+
+/// Columns selected by each query that produces a `NotificationPreferences`, in the order expected
+/// by `NotificationPreferences::from_row`.
+const PREFERENCE_COLUMNS: &'static str = "
+    user_id, lock_available, story_published, access_granted, unsubscribe_token, digest,
+    last_digest_time
+";
+
+/// Length of each randomly generated unsubscribe token.
+const TOKEN_LEN: usize = 32;
+
+/// Kinds of email notification that a user may choose to receive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotificationKind {
+    /// A story that you tried to lock while someone else held it is available again.
+    LockAvailable,
+
+    /// A story that you contributed to has been published.
+    StoryPublished,
+
+    /// You've been granted access to a story.
+    AccessGranted,
+
+    /// A daily summary of activity across the stories you can access, sent in place of each of
+    /// the other kinds.
+    Digest
+}
+
+impl NotificationKind {
+
+    /// Name used for this kind of notification within API requests, responses, and unsubscribe
+    /// links.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NotificationKind::LockAvailable => "lock_available",
+            NotificationKind::StoryPublished => "story_published",
+            NotificationKind::AccessGranted => "access_granted",
+            NotificationKind::Digest => "digest"
+        }
+    }
+
+    /// Find the NotificationKind with a name produced by `::name()`, if any.
+    pub fn from_name(name: &str) -> Option<NotificationKind> {
+        match name {
+            "lock_available" => Some(NotificationKind::LockAvailable),
+            "story_published" => Some(NotificationKind::StoryPublished),
+            "access_granted" => Some(NotificationKind::AccessGranted),
+            "digest" => Some(NotificationKind::Digest),
+            _ => None
+        }
+    }
+
+}
+
+/// Which kinds of email notification a `User` receives. Every kind but `digest` is enabled until
+/// the user chooses otherwise. While `digest` is enabled, the other kinds are summarized once a day
+/// instead of being sent as they happen. `unsubscribe_token` identifies the user within the
+/// unsubscribe link of each email, so that they may opt out without logging in.
+#[derive(Debug, Clone)]
+pub struct NotificationPreferences {
+    pub user_id: i64,
+    pub lock_available: bool,
+    pub story_published: bool,
+    pub access_granted: bool,
+    pub unsubscribe_token: String,
+    pub digest: bool,
+    pub last_digest_time: Option<DateTime<UTC>>
+}
+
+impl NotificationPreferences {
+
+    /// Initialize database tables used to store `NotificationPreferences` and `LockWaiter`
+    /// objects.
+    ///
+    /// Depends on `User::initialize` and `Story::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS notification_preferences (
+                user_id BIGINT PRIMARY KEY REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                lock_available BOOLEAN NOT NULL DEFAULT true,
+                story_published BOOLEAN NOT NULL DEFAULT true,
+                access_granted BOOLEAN NOT NULL DEFAULT true,
+                unsubscribe_token VARCHAR NOT NULL UNIQUE,
+                digest BOOLEAN NOT NULL DEFAULT false,
+                last_digest_time TIMESTAMP WITH TIME ZONE
+            )
+        ", &[]));
+
+        try!(ensure_column(conn, "notification_preferences", "digest", "BOOLEAN NOT NULL DEFAULT false"));
+        try!(ensure_column(conn, "notification_preferences", "last_digest_time", "TIMESTAMP WITH TIME ZONE"));
+        try!(ensure_column(conn, "notification_preferences", "digest_retry_time", "TIMESTAMP WITH TIME ZONE"));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS lock_waiters (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                wait_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                UNIQUE (story_id, user_id)
+            )
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Retrieve the preferences of a `User`, creating the defaults if they've never been set.
+    pub fn for_user(conn: &GenericConnection, user: &User) -> FictResult<NotificationPreferences> {
+        let transaction = try!(conn.transaction());
+
+        let selection = try!(transaction.prepare(&format!("
+            SELECT {}
+            FROM notification_preferences
+            WHERE user_id = $1
+            FOR UPDATE
+        ", PREFERENCE_COLUMNS)));
+
+        let rows = try!(selection.query(&[&user.id]));
+
+        let preferences = match try!(first_opt(&rows)) {
+            Some(row) => NotificationPreferences::from_row(row),
+            None => {
+                let mut rng = try!(OsRng::new());
+                let token: String = rng.gen_ascii_chars().take(TOKEN_LEN).collect();
+
+                let insertion = try!(transaction.prepare(&format!("
+                    INSERT INTO notification_preferences (user_id, unsubscribe_token)
+                    VALUES ($1, $2)
+                    RETURNING {}
+                ", PREFERENCE_COLUMNS)));
+
+                let rows = try!(insertion.query(&[&user.id, &token]));
+
+                NotificationPreferences::from_row(try!(first(&rows)))
+            }
+        };
+
+        try!(transaction.commit());
+
+        Ok(preferences)
+    }
+
+    /// Search for the preferences identified by an unsubscribe token.
+    pub fn with_token(conn: &GenericConnection, token: &str) -> FictResult<Option<NotificationPreferences>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM notification_preferences
+            WHERE unsubscribe_token = $1
+        ", PREFERENCE_COLUMNS)));
+
+        let rows = try!(selection.query(&[&token]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(NotificationPreferences::from_row))
+    }
+
+    /// Determine whether or not notifications of a kind are enabled.
+    pub fn wants(&self, kind: &NotificationKind) -> bool {
+        match *kind {
+            NotificationKind::LockAvailable => self.lock_available,
+            NotificationKind::StoryPublished => self.story_published,
+            NotificationKind::AccessGranted => self.access_granted,
+            NotificationKind::Digest => self.digest
+        }
+    }
+
+    /// Determine whether or not a notification of a kind should be sent as soon as it happens,
+    /// rather than waiting for the next digest.
+    pub fn wants_immediately(&self, kind: &NotificationKind) -> bool {
+        ! self.digest && self.wants(kind)
+    }
+
+    /// Enable or disable notifications of a kind.
+    pub fn set(&mut self, kind: &NotificationKind, enabled: bool) {
+        match *kind {
+            NotificationKind::LockAvailable => self.lock_available = enabled,
+            NotificationKind::StoryPublished => self.story_published = enabled,
+            NotificationKind::AccessGranted => self.access_granted = enabled,
+            NotificationKind::Digest => self.digest = enabled
+        }
+    }
+
+    /// Stop sending notifications of a kind, as requested through an unsubscribe link. The digest
+    /// summarizes each of the other kinds, so unsubscribing from it stops those too, rather than
+    /// sending each of them as it happens instead.
+    pub fn unsubscribe(&mut self, kind: &NotificationKind) {
+        if *kind == NotificationKind::Digest {
+            self.lock_available = false;
+            self.story_published = false;
+            self.access_granted = false;
+        }
+
+        self.set(kind, false);
+    }
+
+    /// Persist any changes made to these preferences.
+    pub fn save(&self, conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            UPDATE notification_preferences
+            SET lock_available = $2, story_published = $3, access_granted = $4, digest = $5
+            WHERE user_id = $1
+        ", &[
+            &self.user_id, &self.lock_available, &self.story_published, &self.access_granted,
+            &self.digest
+        ]));
+
+        Ok(())
+    }
+
+    /// Retrieve up to `limit` users who receive digests and haven't been sent one within a day
+    /// of `now`, along with their preferences. Users whose digest has been deferred past `now`
+    /// are skipped.
+    pub fn digests_due(conn: &GenericConnection, now: DateTime<UTC>, limit: i64) -> FictResult<Vec<(User, NotificationPreferences)>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT users.id, users.name, users.email, {}
+            FROM notification_preferences
+            INNER JOIN users ON users.id = notification_preferences.user_id
+            WHERE
+                digest AND
+                (last_digest_time IS NULL OR last_digest_time <= $1) AND
+                (digest_retry_time IS NULL OR digest_retry_time <= $2)
+            ORDER BY last_digest_time ASC NULLS FIRST, user_id ASC
+            LIMIT $3
+        ", PREFERENCE_COLUMNS)));
+
+        let rows = try!(selection.query(&[&(now - Duration::days(1)), &now, &limit]));
+
+        Ok(rows.iter().map(|row| {
+            let user = User{
+                id: Some(row.get(0)),
+                name: row.get(1),
+                email: row.get(2)
+            };
+            let preferences = NotificationPreferences{
+                user_id: row.get(3),
+                lock_available: row.get(4),
+                story_published: row.get(5),
+                access_granted: row.get(6),
+                unsubscribe_token: row.get(7),
+                digest: row.get(8),
+                last_digest_time: row.get(9)
+            };
+
+            (user, preferences)
+        }).collect())
+    }
+
+    /// Record that a digest covering activity up to `now` has been sent.
+    pub fn record_digest(&mut self, conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<()> {
+        self.last_digest_time = Some(now);
+
+        try!(conn.execute("
+            UPDATE notification_preferences
+            SET last_digest_time = $2, digest_retry_time = NULL
+            WHERE user_id = $1
+        ", &[&self.user_id, &self.last_digest_time]));
+
+        Ok(())
+    }
+
+    /// Hold off on the next attempt to send this user's digest until `retry_time`, after one
+    /// couldn't be sent. The digest still covers everything since the last one that was sent.
+    pub fn defer_digest(&self, conn: &GenericConnection, retry_time: DateTime<UTC>) -> FictResult<()> {
+        try!(conn.execute("
+            UPDATE notification_preferences
+            SET digest_retry_time = $2
+            WHERE user_id = $1
+        ", &[&self.user_id, &retry_time]));
+
+        Ok(())
+    }
+
+    /// Construct a `NotificationPreferences` from a row containing each of the
+    /// `PREFERENCE_COLUMNS`.
+    fn from_row(row: Row) -> NotificationPreferences {
+        NotificationPreferences{
+            user_id: row.get(0),
+            lock_available: row.get(1),
+            story_published: row.get(2),
+            access_granted: row.get(3),
+            unsubscribe_token: row.get(4),
+            digest: row.get(5),
+            last_digest_time: row.get(6)
+        }
+    }
+
+}
+
+/// `User` who tried to lock a `Story` while someone else held it, and who's notified once it's
+/// available again.
+pub struct LockWaiter {
+    pub user: User,
+    pub wait_time: DateTime<UTC>
+}
+
+impl LockWaiter {
+
+    /// Record that a `User` is waiting for the lock of the story `story_id`. Waiting again has no
+    /// effect.
+    pub fn wait(conn: &GenericConnection, story_id: i64, user: &User) -> FictResult<()> {
+        let transaction = try!(conn.transaction());
+
+        let existing = try!(transaction.prepare("
+            SELECT 1
+            FROM lock_waiters
+            WHERE story_id = $1 AND user_id = $2
+        "));
+
+        if try!(existing.query(&[&story_id, &user.id])).is_empty() {
+            try!(transaction.execute("
+                INSERT INTO lock_waiters (story_id, user_id)
+                VALUES ($1, $2)
+            ", &[&story_id, &user.id]));
+        }
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Remove and produce each `LockWaiter` of the story `story_id`, in the order that they began
+    /// to wait.
+    pub fn release(conn: &GenericConnection, story_id: i64) -> FictResult<Vec<LockWaiter>> {
+        let deletion = try!(conn.prepare("
+            WITH released AS (
+                DELETE FROM lock_waiters
+                WHERE story_id = $1
+                RETURNING id, user_id, wait_time
+            )
+            SELECT users.id, users.name, users.email, released.wait_time
+            FROM released
+            INNER JOIN users ON users.id = released.user_id
+            ORDER BY released.id ASC
+        "));
+
+        let rows = try!(deletion.query(&[&story_id]));
+
+        Ok(rows.iter().map(|row| LockWaiter{
+            user: User{
+                id: Some(row.get(0)),
+                name: row.get(1),
+                email: row.get(2)
+            },
+            wait_time: row.get(3)
+        }).collect())
+    }
+
+}
@@ -0,0 +1,177 @@
+//! Prompts that seed a new story before anyone has written its opening.
+
+use postgres::GenericConnection;
+
+use model::{first_opt, normalize, normalize_title, Story, User};
+use error::{FictResult, FieldError};
+use error::FictError::Invalid;
+
+/// Premise, genre tags, and constraints chosen by a `Story`'s owner to inspire its first writer.
+pub struct StoryPrompt {
+    pub story_id: i64,
+    pub premise: String,
+    pub constraints: Option<String>,
+    pub tags: Vec<String>
+}
+
+/// External representation of a `StoryPrompt` within request and response documents.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PromptSettings {
+    pub premise: String,
+    pub constraints: Option<String>,
+    pub tags: Option<Vec<String>>
+}
+
+impl StoryPrompt {
+
+    /// Initialize database tables and indices used to store `StoryPrompt` objects.
+    ///
+    /// Depends on `Story::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_prompts (
+                story_id BIGINT PRIMARY KEY REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                premise VARCHAR NOT NULL,
+                constraints VARCHAR
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS story_tags (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                tag VARCHAR NOT NULL,
+                UNIQUE (story_id, tag)
+            )
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Create and persist a new `Story` owned by `owner` that begins from a prompt, rather than
+    /// from an opening `Snippet`. The first writer to lock the story is shown the prompt instead.
+    pub fn begin(conn: &GenericConnection, owner: &User, title: Option<String>, settings: &PromptSettings) -> FictResult<(Story, StoryPrompt)> {
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(Story::begin(&transaction, owner));
+        let prompt = try!(StoryPrompt::from_settings(&story, settings, "story.prompt"));
+
+        story.title = normalize_title(title);
+
+        try!(story.save(&transaction));
+        try!(prompt.save(&transaction));
+
+        try!(transaction.commit());
+
+        Ok((story, prompt))
+    }
+
+    /// Interpret and validate an external representation of a prompt for `story`. Normalize its
+    /// text, discard blank or duplicate tags, and report any problems as errors on fields beneath
+    /// `prefix`.
+    pub fn from_settings(story: &Story, settings: &PromptSettings, prefix: &str) -> FictResult<StoryPrompt> {
+        let premise = normalize(&settings.premise);
+
+        if premise.trim().is_empty() {
+            return Err(Invalid(vec![FieldError::new(
+                format!("{}.premise", prefix), "blank", "Premise may not be empty."
+            )]));
+        }
+
+        let constraints = settings.constraints.as_ref()
+            .map(|c| normalize(c))
+            .and_then(|c| if c.trim().is_empty() { None } else { Some(c) });
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in settings.tags.iter().flat_map(|ts| ts.iter()) {
+            let tag = normalize(tag.trim()).to_lowercase();
+
+            if ! tag.is_empty() && ! tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        Ok(StoryPrompt{
+            story_id: story.id,
+            premise: premise,
+            constraints: constraints,
+            tags: tags
+        })
+    }
+
+    /// Produce the external representation of this prompt.
+    pub fn settings(&self) -> PromptSettings {
+        PromptSettings{
+            premise: self.premise.clone(),
+            constraints: self.constraints.clone(),
+            tags: Some(self.tags.clone())
+        }
+    }
+
+    /// Persist this prompt, replacing any prompt that its `Story` already had.
+    pub fn save(&self, conn: &GenericConnection) -> FictResult<()> {
+        let transaction = try!(conn.transaction());
+
+        try!(transaction.execute("
+            DELETE FROM story_prompts WHERE story_id = $1
+        ", &[&self.story_id]));
+
+        try!(transaction.execute("
+            DELETE FROM story_tags WHERE story_id = $1
+        ", &[&self.story_id]));
+
+        try!(transaction.execute("
+            INSERT INTO story_prompts (story_id, premise, constraints)
+            VALUES ($1, $2, $3)
+        ", &[&self.story_id, &self.premise, &self.constraints]));
+
+        let tag_insertion = try!(transaction.prepare("
+            INSERT INTO story_tags (story_id, tag)
+            VALUES ($1, $2)
+        "));
+
+        for tag in self.tags.iter() {
+            try!(tag_insertion.execute(&[&self.story_id, tag]));
+        }
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Retrieve the prompt associated with a `Story`, if it has one.
+    pub fn for_story(conn: &GenericConnection, story: &Story) -> FictResult<Option<StoryPrompt>> {
+        let selection = try!(conn.prepare("
+            SELECT premise, constraints
+            FROM story_prompts
+            WHERE story_id = $1
+        "));
+
+        let rows = try!(selection.query(&[&story.id]));
+        let row = match try!(first_opt(&rows)) {
+            Some(row) => row,
+            None => return Ok(None)
+        };
+
+        let tag_selection = try!(conn.prepare("
+            SELECT tag
+            FROM story_tags
+            WHERE story_id = $1
+            ORDER BY id ASC
+        "));
+
+        let tag_rows = try!(tag_selection.query(&[&story.id]));
+
+        Ok(Some(StoryPrompt{
+            story_id: story.id,
+            premise: row.get(0),
+            constraints: row.get(1),
+            tags: tag_rows.iter().map(|r| r.get(0)).collect()
+        }))
+    }
+
+}
@@ -0,0 +1,100 @@
+//! Audit trail of retracted snippets.
+
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+
+use model::{Snippet, User};
+use error::FictResult;
+
+/// Record of a `Snippet` that was withdrawn from its `Story` by its author or a story owner.
+pub struct Retraction {
+    pub id: i64,
+    pub story_id: i64,
+    pub snippet_id: i64,
+    pub position: i32,
+    pub author_id: Option<i64>,
+    pub retractor_id: Option<i64>,
+    pub content: String,
+    pub creation_time: DateTime<UTC>,
+    pub retraction_time: DateTime<UTC>
+}
+
+impl Retraction {
+
+    /// Initialize database tables and indices used to store `Retraction` objects.
+    ///
+    /// Depends on `Story::initialize` and `User::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS snippet_retractions (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                snippet_id BIGINT NOT NULL,
+                position INT NOT NULL,
+                author_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                retractor_id BIGINT REFERENCES users (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE,
+                content VARCHAR NOT NULL,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL,
+                retraction_time TIMESTAMP WITH TIME ZONE NOT NULL
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS snippet_retractions_story_id_index
+            ON snippet_retractions (story_id)
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Preserve a `Snippet` that `retractor` is about to withdraw.
+    pub fn record(conn: &GenericConnection, snippet: &Snippet, retractor: &User, retraction_time: DateTime<UTC>) -> FictResult<()> {
+        let insertion = try!(conn.prepare("
+            INSERT INTO snippet_retractions (
+                story_id, snippet_id, position, author_id, retractor_id, content,
+                creation_time, retraction_time
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "));
+
+        try!(insertion.execute(&[
+            &snippet.story_id, &snippet.id, &snippet.position, &snippet.user_id, &retractor.id,
+            &snippet.content, &snippet.creation_time, &retraction_time
+        ]));
+
+        Ok(())
+    }
+
+    /// Retrieve each `Retraction` from a story, most recent first.
+    pub fn for_story(conn: &GenericConnection, story_id: i64) -> FictResult<Vec<Retraction>> {
+        let selection = try!(conn.prepare("
+            SELECT
+                id, story_id, snippet_id, position, author_id, retractor_id, content,
+                creation_time, retraction_time
+            FROM snippet_retractions
+            WHERE story_id = $1
+            ORDER BY retraction_time DESC, id DESC
+        "));
+
+        let rows = try!(selection.query(&[&story_id]));
+
+        Ok(rows.iter().map(|row| Retraction{
+            id: row.get(0),
+            story_id: row.get(1),
+            snippet_id: row.get(2),
+            position: row.get(3),
+            author_id: row.get(4),
+            retractor_id: row.get(5),
+            content: row.get(6),
+            creation_time: row.get(7),
+            retraction_time: row.get(8)
+        }).collect())
+    }
+
+}
@@ -0,0 +1,78 @@
+//! Prior versions of edited snippets.
+
+use postgres::GenericConnection;
+use chrono::{DateTime, UTC};
+
+use model::Snippet;
+use error::FictResult;
+
+/// Content that a `Snippet` held before its author edited it.
+pub struct SnippetRevision {
+    pub id: i64,
+    pub snippet_id: i64,
+    pub content: String,
+    pub creation_time: DateTime<UTC>,
+    pub replacement_time: DateTime<UTC>
+}
+
+impl SnippetRevision {
+
+    /// Initialize database tables and indices used to store `SnippetRevision` objects.
+    ///
+    /// Depends on `Snippet::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS snippet_revisions (
+                id BIGSERIAL PRIMARY KEY,
+                snippet_id BIGINT NOT NULL REFERENCES snippets (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                content VARCHAR NOT NULL,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL,
+                replacement_time TIMESTAMP WITH TIME ZONE NOT NULL
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS snippet_revisions_snippet_id_index
+            ON snippet_revisions (snippet_id)
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Preserve the current content of a `Snippet` that is about to be replaced at
+    /// `replacement_time`.
+    pub fn record(conn: &GenericConnection, snippet: &Snippet, replacement_time: DateTime<UTC>) -> FictResult<()> {
+        let creation_time = snippet.edit_time.unwrap_or(snippet.creation_time);
+
+        let insertion = try!(conn.prepare("
+            INSERT INTO snippet_revisions (snippet_id, content, creation_time, replacement_time)
+            VALUES ($1, $2, $3, $4)
+        "));
+        try!(insertion.execute(&[&snippet.id, &snippet.content, &creation_time, &replacement_time]));
+
+        Ok(())
+    }
+
+    /// Retrieve each prior version of a `Snippet`, oldest first.
+    pub fn for_snippet(conn: &GenericConnection, snippet: &Snippet) -> FictResult<Vec<SnippetRevision>> {
+        let selection = try!(conn.prepare("
+            SELECT id, snippet_id, content, creation_time, replacement_time
+            FROM snippet_revisions
+            WHERE snippet_id = $1
+            ORDER BY replacement_time ASC, id ASC
+        "));
+
+        let rows = try!(selection.query(&[&snippet.id]));
+
+        Ok(rows.iter().map(|row| SnippetRevision{
+            id: row.get(0),
+            snippet_id: row.get(1),
+            content: row.get(2),
+            creation_time: row.get(3),
+            replacement_time: row.get(4)
+        }).collect())
+    }
+
+}
@@ -0,0 +1,443 @@
+//! Rounds in which writers compete to contribute the next snippet of a story.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+
+use model::{first, first_opt, Story, Snippet, User, StoryMode, Constraint};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `VotingRound`, in the order expected by
+/// `VotingRound::from_row`.
+const ROUND_COLUMNS: &'static str = "
+    id, story_id, position, open_time, close_time, closed, snippet_id
+";
+
+/// Period during which the writers of a `Story` in `StoryMode::Voting` submit candidates for its
+/// next `Snippet`, and its readers vote among them.
+pub struct VotingRound {
+    pub id: i64,
+    pub story_id: i64,
+    pub position: i32,
+    pub open_time: DateTime<UTC>,
+    pub close_time: DateTime<UTC>,
+    pub closed: bool,
+    pub snippet_id: Option<i64>
+}
+
+/// Continuation submitted by a writer during a `VotingRound`, along with the votes it has
+/// received so far.
+pub struct Candidate {
+    pub id: i64,
+    pub round_id: i64,
+    pub user_id: i64,
+    pub content: String,
+    pub creation_time: DateTime<UTC>,
+    pub votes: i64
+}
+
+impl VotingRound {
+
+    /// Initialize database tables and indices used to store `VotingRound` objects, their
+    /// candidates, and their votes.
+    ///
+    /// Depends on `Story::initialize`, `User::initialize`, and `Snippet::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS voting_rounds (
+                id BIGSERIAL PRIMARY KEY,
+                story_id BIGINT NOT NULL REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                position INT NOT NULL,
+                open_time TIMESTAMP WITH TIME ZONE NOT NULL,
+                close_time TIMESTAMP WITH TIME ZONE NOT NULL,
+                closed BOOLEAN NOT NULL DEFAULT false,
+                snippet_id BIGINT REFERENCES snippets (id)
+                    ON DELETE SET NULL
+                    ON UPDATE CASCADE
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS voting_rounds_story_id_index
+            ON voting_rounds (story_id, closed)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS snippet_candidates (
+                id BIGSERIAL PRIMARY KEY,
+                round_id BIGINT NOT NULL REFERENCES voting_rounds (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                content VARCHAR NOT NULL,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                UNIQUE (round_id, user_id)
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS candidate_votes (
+                id BIGSERIAL PRIMARY KEY,
+                round_id BIGINT NOT NULL REFERENCES voting_rounds (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                candidate_id BIGINT NOT NULL REFERENCES snippet_candidates (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                user_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                UNIQUE (round_id, user_id)
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS candidate_votes_candidate_id_index
+            ON candidate_votes (candidate_id)
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Retrieve the open round of a `Story` that `viewer` may read, along with its candidates. If
+    /// the round's time has elapsed, close it first and produce `None`.
+    pub fn open_for(conn: &GenericConnection, story_id: i64, viewer: &User) -> FictResult<Option<(VotingRound, Vec<Candidate>)>> {
+        let now = UTC::now();
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(VotingRound::story_for_update(&transaction, story_id));
+        if ! try!(story.access_for(&transaction, viewer)).grants_read() {
+            return Err(FictError::NotFound);
+        }
+
+        let result = match try!(VotingRound::current(&transaction, &mut story, now)) {
+            Some(round) => {
+                let candidates = try!(round.candidates(&transaction));
+                Some((round, candidates))
+            },
+            None => None
+        };
+
+        try!(transaction.commit());
+
+        Ok(result)
+    }
+
+    /// Submit a candidate for the next `Snippet` of a `Story` on behalf of one of its writers,
+    /// opening a new round if none is in progress.
+    ///
+    /// If the story does not exist or `writer` may not contribute to it, return
+    /// `Err(FictError::NotFound)`. If the story has finished, return `Err(FictError::Finished)`.
+    /// Each writer may submit one candidate per round; otherwise, return
+    /// `Err(FictError::Duplicate)`.
+    pub fn submit(conn: &GenericConnection, story_id: i64, writer: &User, content: String) -> FictResult<(VotingRound, Candidate)> {
+        let now = UTC::now();
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(VotingRound::story_for_update(&transaction, story_id));
+        if ! try!(story.access_for(&transaction, writer)).grants_write() {
+            return Err(FictError::NotFound);
+        }
+
+        let content = try!(story.limits.accept(content));
+        try!(Constraint::verify(&transaction, &story, &content));
+
+        let current = try!(VotingRound::current(&transaction, &mut story, now));
+
+        if ! story.finished && story.completion.deadline_passed(now) {
+            try!(story.finish(&transaction, now));
+        }
+
+        if story.finished {
+            // Persist the outcome of any round that closed in the meantime.
+            try!(transaction.commit());
+
+            return Err(FictError::Finished);
+        }
+
+        let round = match current {
+            Some(round) => round,
+            None => try!(VotingRound::open(&transaction, &story, now))
+        };
+
+        let existing = try!(transaction.prepare("
+            SELECT 1
+            FROM snippet_candidates
+            WHERE round_id = $1 AND user_id = $2
+        "));
+
+        if ! try!(existing.query(&[&round.id, &writer.id])).is_empty() {
+            return Err(FictError::Duplicate);
+        }
+
+        let insertion = try!(transaction.prepare("
+            INSERT INTO snippet_candidates (round_id, user_id, content)
+            VALUES ($1, $2, $3)
+            RETURNING id, creation_time
+        "));
+
+        let candidate = {
+            let rows = try!(insertion.query(&[&round.id, &writer.id, &content]));
+            let row = try!(first(&rows));
+
+            Candidate{
+                id: row.get(0),
+                round_id: round.id,
+                user_id: writer.id.unwrap(),
+                content: content,
+                creation_time: row.get(1),
+                votes: 0
+            }
+        };
+
+        try!(transaction.commit());
+
+        Ok((round, candidate))
+    }
+
+    /// Cast a vote on behalf of a reader of a `Story` for one of the candidates in its open round.
+    ///
+    /// If the story does not exist or `voter` may not read it, or if the candidate is not part of
+    /// the story's open round, return `Err(FictError::NotFound)`. Voters may not vote for their own
+    /// candidate, which returns `Err(FictError::OwnCandidate)`, and may only vote once per round,
+    /// which returns `Err(FictError::Duplicate)`.
+    pub fn vote(conn: &GenericConnection, story_id: i64, voter: &User, candidate_id: i64) -> FictResult<()> {
+        let now = UTC::now();
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(VotingRound::story_for_update(&transaction, story_id));
+        if ! try!(story.access_for(&transaction, voter)).grants_read() {
+            return Err(FictError::NotFound);
+        }
+
+        let round = match try!(VotingRound::current(&transaction, &mut story, now)) {
+            Some(round) => round,
+            None => {
+                // Persist the outcome of a round that closed in the meantime.
+                try!(transaction.commit());
+
+                return Err(FictError::NotFound);
+            }
+        };
+
+        let selection = try!(transaction.prepare("
+            SELECT user_id
+            FROM snippet_candidates
+            WHERE id = $1 AND round_id = $2
+        "));
+
+        let rows = try!(selection.query(&[&candidate_id, &round.id]));
+        let author_id: i64 = match try!(first_opt(&rows)) {
+            Some(row) => row.get(0),
+            None => return Err(FictError::NotFound)
+        };
+
+        if Some(author_id) == voter.id {
+            return Err(FictError::OwnCandidate);
+        }
+
+        let existing = try!(transaction.prepare("
+            SELECT 1
+            FROM candidate_votes
+            WHERE round_id = $1 AND user_id = $2
+        "));
+
+        if ! try!(existing.query(&[&round.id, &voter.id])).is_empty() {
+            return Err(FictError::Duplicate);
+        }
+
+        try!(transaction.execute("
+            INSERT INTO candidate_votes (round_id, candidate_id, user_id)
+            VALUES ($1, $2, $3)
+        ", &[&round.id, &candidate_id, &voter.id]));
+
+        try!(transaction.commit());
+
+        Ok(())
+    }
+
+    /// Close each open round whose time has elapsed, or whose story's deadline has passed, electing
+    /// its winner. Produce the number of rounds closed.
+    pub fn close_due(conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<usize> {
+        let selection = try!(conn.prepare("
+            SELECT DISTINCT voting_rounds.story_id
+            FROM voting_rounds
+            INNER JOIN stories ON stories.id = voting_rounds.story_id
+            WHERE
+                NOT voting_rounds.closed AND
+                (voting_rounds.close_time <= $1 OR stories.deadline <= $1)
+        "));
+
+        let rows = try!(selection.query(&[&now]));
+
+        let mut count = 0;
+        for row in rows.iter() {
+            // Each story is closed in its own transaction, so that one failure doesn't hold back
+            // the others.
+            let transaction = try!(conn.transaction());
+
+            let mut story = match VotingRound::story_for_update(&transaction, row.get(0)) {
+                Ok(story) => story,
+                Err(FictError::NotFound) | Err(FictError::NoVoting) => continue,
+                Err(e) => return Err(e)
+            };
+
+            // Closes the round as a side effect.
+            if try!(VotingRound::current(&transaction, &mut story, now)).is_none() {
+                count += 1;
+            }
+
+            try!(transaction.commit());
+        }
+
+        Ok(count)
+    }
+
+    /// Close any open round of a `Story` without electing a winner, such as when the story stops
+    /// choosing its snippets by vote.
+    pub fn abandon(conn: &GenericConnection, story: &Story) -> FictResult<()> {
+        try!(conn.execute("
+            UPDATE voting_rounds
+            SET closed = true
+            WHERE story_id = $1 AND NOT closed
+        ", &[&story.id]));
+
+        Ok(())
+    }
+
+    /// Retrieve each candidate submitted during this round along with its vote count, in the
+    /// order that they were submitted.
+    pub fn candidates(&self, conn: &GenericConnection) -> FictResult<Vec<Candidate>> {
+        let selection = try!(conn.prepare("
+            SELECT
+                snippet_candidates.id, snippet_candidates.user_id, snippet_candidates.content,
+                snippet_candidates.creation_time, COUNT(candidate_votes.id)
+            FROM snippet_candidates
+            LEFT OUTER JOIN candidate_votes
+                ON candidate_votes.candidate_id = snippet_candidates.id
+            WHERE snippet_candidates.round_id = $1
+            GROUP BY snippet_candidates.id
+            ORDER BY snippet_candidates.id ASC
+        "));
+
+        let rows = try!(selection.query(&[&self.id]));
+
+        Ok(rows.iter().map(|row| Candidate{
+            id: row.get(0),
+            round_id: self.id,
+            user_id: row.get(1),
+            content: row.get(2),
+            creation_time: row.get(3),
+            votes: row.get(4)
+        }).collect())
+    }
+
+    /// Lock the row of a `Story` that chooses its snippets by vote until the current transaction
+    /// completes. If the story does not exist, return `Err(FictError::NotFound)`. If it does not
+    /// choose its snippets by vote, return `Err(FictError::NoVoting)`.
+    fn story_for_update(conn: &GenericConnection, story_id: i64) -> FictResult<Story> {
+        let story = try!(try!(Story::with_id_for_update(conn, story_id)).ok_or(FictError::NotFound));
+
+        if story.mode.is_voting() {
+            Ok(story)
+        } else {
+            Err(FictError::NoVoting)
+        }
+    }
+
+    /// Find the open round of a `Story`, if there is one. If the round's time has elapsed or the
+    /// story's deadline has passed, close it and produce `None` instead. The caller must hold the
+    /// story's row lock.
+    fn current(conn: &GenericConnection, story: &mut Story, now: DateTime<UTC>) -> FictResult<Option<VotingRound>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM voting_rounds
+            WHERE story_id = $1 AND NOT closed
+        ", ROUND_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id]));
+        let mut round = match try!(first_opt(&rows)) {
+            Some(row) => VotingRound::from_row(row),
+            None => return Ok(None)
+        };
+
+        if round.close_time <= now || story.completion.deadline_passed(now) {
+            try!(round.close(conn, story, now));
+            return Ok(None);
+        }
+
+        Ok(Some(round))
+    }
+
+    /// Begin a new round of a `Story` at `now`, lasting as long as its `StoryMode` specifies.
+    fn open(conn: &GenericConnection, story: &Story, now: DateTime<UTC>) -> FictResult<VotingRound> {
+        let duration_s = match story.mode {
+            StoryMode::Voting(seconds) => seconds,
+            _ => 0
+        };
+
+        let insertion = try!(conn.prepare(&format!("
+            INSERT INTO voting_rounds (story_id, position, open_time, close_time)
+            VALUES ($1, $2, $3, $4)
+            RETURNING {}
+        ", ROUND_COLUMNS)));
+
+        let position = story.contribution_count + 1;
+        let close_time = now + Duration::seconds(duration_s);
+
+        let rows = try!(insertion.query(&[&story.id, &position, &now, &close_time]));
+
+        Ok(VotingRound::from_row(try!(first(&rows))))
+    }
+
+    /// Close this round. The candidate with the most votes, or the earliest submitted among those
+    /// tied, becomes the next `Snippet` of its `Story`. A round without candidates closes without
+    /// contributing anything.
+    fn close(&mut self, conn: &GenericConnection, story: &mut Story, now: DateTime<UTC>) -> FictResult<()> {
+        let mut candidates = try!(self.candidates(conn));
+
+        // Stable sort preserves submission order among tied candidates.
+        candidates.sort_by(|a, b| b.votes.cmp(&a.votes));
+
+        if let Some(winner) = candidates.into_iter().next() {
+            let author = try!(User::with_id(conn, winner.user_id));
+            let snippet = try!(Snippet::elect(conn, story, &author, winner.content, now));
+
+            self.snippet_id = Some(snippet.id);
+        } else if story.completion.deadline_passed(now) && ! story.finished {
+            try!(story.finish(conn, now));
+        }
+
+        self.closed = true;
+
+        try!(conn.execute("
+            UPDATE voting_rounds
+            SET
+                closed = true,
+                snippet_id = $2
+            WHERE id = $1
+        ", &[&self.id, &self.snippet_id]));
+
+        Ok(())
+    }
+
+    /// Construct a `VotingRound` from a row containing each of the `ROUND_COLUMNS`.
+    fn from_row(row: Row) -> VotingRound {
+        VotingRound{
+            id: row.get(0),
+            story_id: row.get(1),
+            position: row.get(2),
+            open_time: row.get(3),
+            close_time: row.get(4),
+            closed: row.get(5),
+            snippet_id: row.get(6)
+        }
+    }
+
+}
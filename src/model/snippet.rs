@@ -1,16 +1,26 @@
 use postgres::GenericConnection;
+use postgres::rows::Row;
 use chrono::{DateTime, UTC};
 
-use model::{first, Story, User};
-use error::FictResult;
+use model::{first, first_opt, ensure_column, count_words, Story, User, ContributionAttempt, ContentLimits};
+use model::{SnippetRevision, Retraction, StoryEvent, EventKind, Constraint, Notification};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `Snippet`, in the order expected by
+/// `Snippet::from_row`.
+const SNIPPET_COLUMNS: &'static str = "
+    id, ordinal, position, user_id, story_id, creation_time, edit_time, content
+";
 
 /// Single submission to an ongoing `Story`.
 pub struct Snippet {
     pub id: i64,
     pub ordinal: i32,
+    pub position: i32,
     pub user_id: i64,
     pub story_id: i64,
     pub creation_time: DateTime<UTC>,
+    pub edit_time: Option<DateTime<UTC>>,
     pub content: String
 }
 
@@ -24,6 +34,7 @@ impl Snippet {
             CREATE TABLE IF NOT EXISTS snippets (
                 id BIGSERIAL PRIMARY KEY,
                 ordinal SERIAL NOT NULL,
+                position INT NOT NULL,
                 user_id BIGINT REFERENCES users (id)
                     ON DELETE SET NULL
                     ON UPDATE CASCADE,
@@ -32,10 +43,55 @@ impl Snippet {
                     ON UPDATE CASCADE,
                 creation_time TIMESTAMP WITH TIME ZONE NOT NULL
                     DEFAULT (now() AT TIME ZONE 'utc'),
-                content VARCHAR NOT NULL
+                edit_time TIMESTAMP WITH TIME ZONE,
+                content VARCHAR NOT NULL,
+                word_count INT NOT NULL
             )
         ", &[]));
 
+        try!(ensure_column(conn, "snippets", "edit_time", "TIMESTAMP WITH TIME ZONE"));
+
+        // Store each snippet's word count, so that word targets can be checked without reading
+        // the content of every snippet in a story.
+        if try!(ensure_column(conn, "snippets", "word_count", "INT")) {
+            let selection = try!(conn.prepare("
+                SELECT id, content
+                FROM snippets
+            "));
+            let update = try!(conn.prepare("
+                UPDATE snippets
+                SET word_count = $2
+                WHERE id = $1
+            "));
+
+            for row in try!(selection.query(&[])).iter() {
+                let id: i64 = row.get(0);
+                let content: String = row.get(1);
+                try!(update.execute(&[&id, &count_words(&content)]));
+            }
+
+            try!(conn.execute("
+                ALTER TABLE snippets ALTER COLUMN word_count SET NOT NULL
+            ", &[]));
+        }
+
+        // Number snippets within each story in the order they were contributed.
+        if try!(ensure_column(conn, "snippets", "position", "INT")) {
+            try!(conn.execute("
+                UPDATE snippets
+                SET position = numbered.position
+                FROM (
+                    SELECT id, row_number() OVER (PARTITION BY story_id ORDER BY ordinal) AS position
+                    FROM snippets
+                ) AS numbered
+                WHERE snippets.id = numbered.id
+            ", &[]));
+
+            try!(conn.execute("
+                ALTER TABLE snippets ALTER COLUMN position SET NOT NULL
+            ", &[]));
+        }
+
         try!(conn.execute("
             CREATE INDEX IF NOT EXISTS snippets_user_id_index ON snippets (user_id)
         ", &[]));
@@ -44,61 +100,413 @@ impl Snippet {
             CREATE INDEX IF NOT EXISTS snippets_story_id_index ON snippets (story_id)
         ", &[]));
 
+        try!(conn.execute("
+            CREATE UNIQUE INDEX IF NOT EXISTS snippets_story_position_index
+            ON snippets (story_id, position)
+        ", &[]));
+
+        // A story's history includes its own snippets and, if it was forked from another story,
+        // each snippet of its parent up to the point where it was forked, recursively.
+        try!(conn.execute("
+            CREATE OR REPLACE FUNCTION story_snippets(target BIGINT)
+            RETURNS SETOF snippets AS $$
+                WITH RECURSIVE lineage (story_id, parent_story_id, fork_position, cap) AS (
+                    SELECT id, parent_story_id, fork_position, NULL::INT
+                    FROM stories
+                    WHERE id = target
+                  UNION ALL
+                    SELECT parent.id, parent.parent_story_id, parent.fork_position,
+                        LEAST(lineage.cap, lineage.fork_position)
+                    FROM stories AS parent
+                    INNER JOIN lineage ON parent.id = lineage.parent_story_id
+                )
+                SELECT snippets.*
+                FROM snippets
+                INNER JOIN lineage ON snippets.story_id = lineage.story_id
+                WHERE lineage.cap IS NULL OR snippets.position <= lineage.cap
+            $$ LANGUAGE SQL STABLE
+        ", &[]));
+
         Ok(())
     }
 
     /// Accept data to construct a `Snippet` that begins a new `Story` in draft status.
+    ///
+    /// The story, its first snippet, and the owner's initial `ContributionAttempt` are created
+    /// within a single transaction, which also has the owner follow the story.
     pub fn begin(conn: &GenericConnection, owner: &User, content: String) -> FictResult<(Snippet, Story)> {
-        let story = try!(Story::begin(conn, owner));
+        let content = try!(ContentLimits::default().accept(content));
 
-        Snippet::contribute(conn, &story, owner, content)
-            .map(|snippet| (snippet, story))
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(Story::begin(&transaction, owner));
+        try!(ContributionAttempt::record(&transaction, &story, owner));
+
+        let snippet = try!(Snippet::insert(&transaction, &story, owner, content));
+        try!(story.record_contribution(&transaction));
+        try!(Notification::follow(&transaction, &story, owner));
+
+        try!(transaction.commit());
+
+        Ok((snippet, story))
     }
 
     /// Continue a `Story` in progress by creating a new `Snippet`.
-    pub fn contribute(conn: &GenericConnection, story: &Story, contributor: &User, content: String) -> FictResult<Snippet> {
+    ///
+    /// Within a single transaction, verify that the contributor holds the story's lock, normalize
+    /// and validate the content against the story's `ContentLimits` and `Constraint`s, persist the
+    /// new snippet, bump the story's contribution count, release the lock, and finish the story if
+    /// it has met any of its `CompletionRules`. Sprints pass the lock to the next writer on their
+    /// schedule instead. The contributor follows the story, and the resulting `StoryEvent`s are
+    /// recorded. If any step fails, none of them take effect.
+    pub fn contribute(conn: &GenericConnection, story_id: i64, contributor: &User, content: String) -> FictResult<(Snippet, Story)> {
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(Story::locked_for_write(&transaction, story_id, contributor, false));
+        let was_published = story.published;
+        let content = try!(story.limits.accept(content));
+        try!(Constraint::verify(&transaction, &story, &content));
+
+        let snippet = try!(Snippet::insert(&transaction, &story, contributor, content));
+        try!(story.record_contribution(&transaction));
+
+        let now = UTC::now();
+        if try!(story.completion.satisfied_by(&transaction, &story, now)) {
+            try!(story.finish(&transaction, now));
+        } else if story.mode.is_sprint() {
+            // Begin the next writer's turn without waiting for this one to expire.
+            try!(story.advance_turn(&transaction, contributor.id, now));
+        }
+
+        // Contributors follow the story, so they hear about the snippets that continue it.
+        try!(Notification::follow(&transaction, &story, contributor));
+
+        // Contributing releases the lock, so followers learn of both from this one event.
+        try!(StoryEvent::record(
+            &transaction, story.id, EventKind::SnippetContributed, contributor.id, None,
+            Some(snippet.id.to_string())
+        ));
+
+        if story.published && ! was_published {
+            try!(StoryEvent::record(&transaction, story.id, EventKind::StoryPublished, contributor.id, None, None));
+        }
+
+        // Sprints pass the lock straight to the next writer.
+        if story.mode.is_sprint() && story.lock_user_id.is_some() {
+            try!(StoryEvent::record(&transaction, story.id, EventKind::LockAcquired, contributor.id, story.lock_user_id, None));
+        }
+
+        try!(transaction.commit());
+
+        Ok((snippet, story))
+    }
+
+    /// Adopt the winning candidate of a `VotingRound` as the next `Snippet` in a `Story`, on behalf
+    /// of its author. Bump the story's contribution count and finish the story if it has met any of
+    /// its `CompletionRules`. Record the resulting `StoryEvent`s. The caller must hold the story's
+    /// row lock.
+    pub fn elect(conn: &GenericConnection, story: &mut Story, author: &User, content: String, now: DateTime<UTC>) -> FictResult<Snippet> {
+        let snippet = try!(Snippet::insert(conn, story, author, content));
+        try!(story.record_contribution(conn));
+
+        try!(StoryEvent::record(
+            conn, story.id, EventKind::SnippetContributed, author.id, None,
+            Some(snippet.id.to_string())
+        ));
+
+        let was_published = story.published;
+        if try!(story.completion.satisfied_by(conn, story, now)) {
+            try!(story.finish(conn, now));
+        }
+
+        if story.published && ! was_published {
+            try!(StoryEvent::record(conn, story.id, EventKind::StoryPublished, None, None, None));
+        }
+
+        Ok(snippet)
+    }
+
+    /// Persist a new `Snippet` as the next entry in a `Story`. The caller must hold the story's
+    /// row lock so that concurrent contributions cannot claim the same position.
+    fn insert(conn: &GenericConnection, story: &Story, contributor: &User, content: String) -> FictResult<Snippet> {
         let contributor_id = contributor.id.unwrap();
 
         let insertion = try!(conn.prepare("
-            INSERT INTO snippets (user_id, story_id, content)
-            VALUES ($1, $2, $3)
-            RETURNING id, ordinal, creation_time
+            INSERT INTO snippets (user_id, story_id, position, content, word_count)
+            VALUES (
+                $1, $2,
+                (SELECT COALESCE(MAX(position), 0) + 1 FROM story_snippets($2)),
+                $3, $4
+            )
+            RETURNING id, ordinal, position, creation_time
         "));
 
-        let rows = try!(insertion.query(&[&contributor_id, &story.id, &content]));
+        let rows = try!(insertion.query(&[&contributor_id, &story.id, &content, &count_words(&content)]));
         let row = try!(first(&rows));
 
         Ok(Snippet{
             id: row.get(0),
             ordinal: row.get(1),
+            position: row.get(2),
             user_id: contributor_id,
             story_id: story.id,
-            creation_time: row.get(2),
+            creation_time: row.get(3),
+            edit_time: None,
             content: content
         })
     }
 
-    /// Return the most recent Snippet associated with a given Story.
-    pub fn most_recent(conn: &GenericConnection, story: &Story) -> FictResult<Snippet> {
+    /// Revise the content of a `Snippet` on behalf of its author. The revised content must obey
+    /// the story's `ContentLimits` and `Constraint`s. The prior content is preserved as a
+    /// `SnippetRevision`.
+    ///
+    /// Authors may only edit the most recent `Snippet` in a `Story`, and only until another `User`
+    /// acquires the story's lock to continue it. Snippets elected by vote are closed to edits as
+    /// soon as they're elected. Otherwise, return `Err(FictError::EditClosed)`. Snippets that a
+    /// fork shares may not be edited: return `Err(FictError::Forked)`.
+    /// If the editor is not the snippet's author, return `Err(FictError::NotFound)`. If the author
+    /// may no longer write to the story, return `Err(FictError::Forbidden)`.
+    pub fn edit(conn: &GenericConnection, id: i64, editor: &User, content: String) -> FictResult<Snippet> {
+        let transaction = try!(conn.transaction());
+
+        let mut snippet = try!(try!(Snippet::with_id(&transaction, id)).ok_or(FictError::NotFound));
+        if Some(snippet.user_id) != editor.id {
+            return Err(FictError::NotFound);
+        }
+
+        // Hold the story's row lock so that no lock can be granted while the edit is in progress.
+        let story = try!(try!(Story::with_id_for_update(&transaction, snippet.story_id))
+            .ok_or(FictError::NotFound));
+
+        // Authors who have since lost write access may no longer revise their snippets.
+        let access = try!(story.access_for(&transaction, editor));
+        if ! access.grants_write() {
+            return Err(if access.grants_read() { FictError::Forbidden } else { FictError::NotFound });
+        }
+
+        if ! try!(snippet.is_most_recent(&transaction)) {
+            return Err(FictError::EditClosed);
+        }
+
+        if try!(snippet.is_shared_with_fork(&transaction)) {
+            return Err(FictError::Forked);
+        }
+
+        // Other writers' candidates continue an elected snippet as soon as the next round opens,
+        // and the vote chose its content as it stood.
+        if story.mode.is_voting() {
+            return Err(FictError::EditClosed);
+        }
+
+        if try!(ContributionAttempt::attempted_by_other(&transaction, &story, editor)) {
+            return Err(FictError::EditClosed);
+        }
+
+        let content = try!(story.limits.accept(content));
+        try!(Constraint::verify_revision(&transaction, &story, &snippet, &content));
+        let now = UTC::now();
+
+        try!(SnippetRevision::record(&transaction, &snippet, now));
+
+        let update = try!(transaction.prepare("
+            UPDATE snippets
+            SET content = $2, edit_time = $3, word_count = $4
+            WHERE id = $1
+        "));
+        try!(update.execute(&[&snippet.id, &content, &now, &count_words(&content)]));
+
+        try!(transaction.commit());
+
+        snippet.content = content;
+        snippet.edit_time = Some(now);
+
+        Ok(snippet)
+    }
+
+    /// Withdraw the most recent `Snippet` from its `Story`, preserving it as a `Retraction`.
+    ///
+    /// Story owners may retract the most recent snippet at any time. Authors may retract their own
+    /// snippet until another `User` locks the story to continue it, and never once it's elected by
+    /// vote, like `Snippet::edit`. Either way, the story's contribution count is decremented, any
+    /// `ContributionAttempt` made since the snippet was contributed is rewound to match, and any
+    /// outstanding lock is released unless it's a sprint's scheduled turn.
+    ///
+    /// Snippets that a fork shares may not be retracted: return `Err(FictError::Forked)`.
+    pub fn retract(conn: &GenericConnection, id: i64, retractor: &User) -> FictResult<Story> {
+        let transaction = try!(conn.transaction());
+
+        let snippet = try!(try!(Snippet::with_id(&transaction, id)).ok_or(FictError::NotFound));
+        let mut story = try!(try!(Story::with_id_for_update(&transaction, snippet.story_id))
+            .ok_or(FictError::NotFound));
+
+        let access = try!(story.access_for(&transaction, retractor));
+        let is_author = Some(snippet.user_id) == retractor.id;
+
+        if ! access.grants_admin() && ! is_author {
+            return Err(if access.grants_read() { FictError::Forbidden } else { FictError::NotFound });
+        }
+
+        if ! try!(snippet.is_most_recent(&transaction)) {
+            return Err(FictError::NotMostRecent);
+        }
+
+        if try!(snippet.is_shared_with_fork(&transaction)) {
+            return Err(FictError::Forked);
+        }
+
+        if ! access.grants_admin() && story.mode.is_voting() {
+            return Err(FictError::EditClosed);
+        }
+
+        if ! access.grants_admin() && try!(ContributionAttempt::attempted_by_other(&transaction, &story, retractor)) {
+            return Err(FictError::EditClosed);
+        }
+
+        try!(Retraction::record(&transaction, &snippet, retractor, UTC::now()));
+
+        let deletion = try!(transaction.prepare("
+            DELETE FROM snippets
+            WHERE id = $1
+        "));
+        try!(deletion.execute(&[&snippet.id]));
+
+        try!(story.record_retraction(&transaction));
+        try!(ContributionAttempt::rewind(&transaction, &story));
+
+        try!(transaction.commit());
+
+        Ok(story)
+    }
+
+    /// Search for an existing `Snippet` by ID.
+    pub fn with_id(conn: &GenericConnection, id: i64) -> FictResult<Option<Snippet>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM snippets
+            WHERE id = $1
+        ", SNIPPET_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Snippet::from_row))
+    }
+
+    /// Determine whether or not this is the most recently contributed `Snippet` in its `Story`.
+    pub fn is_most_recent(&self, conn: &GenericConnection) -> FictResult<bool> {
         let selection = try!(conn.prepare("
-            SELECT id, ordinal, user_id, story_id, creation_time, content
+            SELECT 1
             FROM snippets
-            WHERE story_id = $1
-            ORDER BY ordinal DESC
+            WHERE story_id = $1 AND position > $2
+        "));
+
+        let rows = try!(selection.query(&[&self.story_id, &self.position]));
+
+        Ok(rows.is_empty())
+    }
+
+    /// Return the `Snippet` that this one continues within its `Story`'s history, or `None` if this
+    /// is the first.
+    pub fn previous(&self, conn: &GenericConnection) -> FictResult<Option<Snippet>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_snippets($1) AS snippets
+            WHERE position < $2
+            ORDER BY position DESC
+            LIMIT 1
+        ", SNIPPET_COLUMNS)));
+
+        let rows = try!(selection.query(&[&self.story_id, &self.position]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Snippet::from_row))
+    }
+
+    /// Determine whether or not any `Story` has been forked from this `Snippet` or from a later one
+    /// in its story, so that this snippet is part of the fork's history too.
+    pub fn is_shared_with_fork(&self, conn: &GenericConnection) -> FictResult<bool> {
+        let selection = try!(conn.prepare("
+            SELECT 1
+            FROM stories
+            WHERE parent_story_id = $1 AND fork_position >= $2
+        "));
+
+        let rows = try!(selection.query(&[&self.story_id, &self.position]));
+
+        Ok(!rows.is_empty())
+    }
+
+    /// Return the most recent Snippet associated with a given Story, or `None` if the story
+    /// began from a prompt and nobody has contributed to it yet.
+    pub fn most_recent(conn: &GenericConnection, story: &Story) -> FictResult<Option<Snippet>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_snippets($1) AS snippets
+            ORDER BY position DESC
             LIMIT 1
+        ", SNIPPET_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Snippet::from_row))
+    }
+
+    /// Return up to `count` of the most recent Snippets associated with a given Story, oldest
+    /// first.
+    pub fn most_recent_n(conn: &GenericConnection, story: &Story, count: i64) -> FictResult<Vec<Snippet>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_snippets($1) AS snippets
+            ORDER BY position DESC
+            LIMIT $2
+        ", SNIPPET_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id, &count]));
+        let mut snippets: Vec<Snippet> = rows.iter().map(Snippet::from_row).collect();
+        snippets.reverse();
+
+        Ok(snippets)
+    }
+
+    /// Return every Snippet associated with a given Story, including those inherited from the
+    /// story that it was forked from, oldest first.
+    pub fn for_story(conn: &GenericConnection, story: &Story) -> FictResult<Vec<Snippet>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM story_snippets($1) AS snippets
+            ORDER BY position ASC
+        ", SNIPPET_COLUMNS)));
+
+        let rows = try!(selection.query(&[&story.id]));
+
+        Ok(rows.iter().map(Snippet::from_row).collect())
+    }
+
+    /// Count the words within every Snippet associated with a given Story.
+    pub fn word_count(conn: &GenericConnection, story: &Story) -> FictResult<i64> {
+        let selection = try!(conn.prepare("
+            SELECT COALESCE(SUM(word_count), 0)
+            FROM story_snippets($1) AS snippets
         "));
 
         let rows = try!(selection.query(&[&story.id]));
-        let row = try!(first(&rows));
 
-        Ok(Snippet{
+        Ok(try!(first(&rows)).get(0))
+    }
+
+    /// Construct a `Snippet` from a row containing each of the `SNIPPET_COLUMNS`.
+    fn from_row(row: Row) -> Snippet {
+        Snippet{
             id: row.get(0),
             ordinal: row.get(1),
-            user_id: row.get(2),
-            story_id: row.get(3),
-            creation_time: row.get(4),
-            content: row.get(5)
-        })
+            position: row.get(2),
+            user_id: row.get(3),
+            story_id: row.get(4),
+            creation_time: row.get(5),
+            edit_time: row.get(6),
+            content: row.get(7)
+        }
     }
 
 }
@@ -1,9 +1,27 @@
-use postgres::{Connection, GenericConnection};
+use postgres::GenericConnection;
+use postgres::rows::Row;
 use chrono::{DateTime, UTC};
 use chrono::duration::Duration;
 
-use model::{first, first_opt, User};
+use model::{first, first_opt, ensure_column, User, ContentLimits, Snippet, Constraint};
+use model::{TurnPolicy, Turn, VisibilityPolicy, CompletionRules, StoryMode, RevealPolicy};
+use model::{StoryEvent, EventKind, Excerpt, StoryPrompt, LockWaiter};
 use error::{FictResult, FictError, fict_err};
+use responses::timestamp;
+
+/// Columns selected by each query that produces a `Story`, in the order expected by
+/// `Story::from_row`.
+const STORY_COLUMNS: &'static str = "
+    id, title, published, world_readable, lock_duration_s, contribution_count,
+    creation_time, update_time, publish_time,
+    lock_user_id, lock_expiration,
+    min_chars, max_chars, min_words, max_words,
+    turn_policy_code, turn_policy_count, turn_policy_cooldown_s,
+    visibility_code, visibility_count,
+    max_snippets, word_target, deadline, auto_publish, finished, finish_time,
+    parent_story_id, fork_position,
+    mode_code, round_duration_s, open_to_join, reveal_code
+";
 
 /// An ordered sequence of Snippets that combine to form a (hopefully) hilarious piece of fiction.
 pub struct Story {
@@ -17,7 +35,18 @@ pub struct Story {
     pub update_time: DateTime<UTC>,
     pub publish_time: Option<DateTime<UTC>>,
     pub lock_user_id: Option<i64>,
-    pub lock_expiration: Option<DateTime<UTC>>
+    pub lock_expiration: Option<DateTime<UTC>>,
+    pub limits: ContentLimits,
+    pub turn_policy: TurnPolicy,
+    pub visibility: VisibilityPolicy,
+    pub completion: CompletionRules,
+    pub finished: bool,
+    pub finish_time: Option<DateTime<UTC>>,
+    pub parent_story_id: Option<i64>,
+    pub fork_position: Option<i32>,
+    pub mode: StoryMode,
+    pub open_to_join: bool,
+    pub reveal: RevealPolicy
 }
 
 impl Story {
@@ -42,45 +71,82 @@ impl Story {
                 lock_user_id BIGINT REFERENCES users (id)
                     ON DELETE SET NULL
                     ON UPDATE CASCADE,
-                lock_expiration TIMESTAMP WITH TIME ZONE
+                lock_expiration TIMESTAMP WITH TIME ZONE,
+                min_chars INT,
+                max_chars INT,
+                min_words INT,
+                max_words INT,
+                turn_policy_code INT NOT NULL DEFAULT 0,
+                turn_policy_count INT NOT NULL DEFAULT 1,
+                turn_policy_cooldown_s BIGINT NOT NULL DEFAULT 0,
+                visibility_code INT NOT NULL DEFAULT 0,
+                visibility_count INT NOT NULL DEFAULT 1,
+                max_snippets INT,
+                word_target INT,
+                deadline TIMESTAMP WITH TIME ZONE,
+                auto_publish BOOLEAN NOT NULL DEFAULT false,
+                finished BOOLEAN NOT NULL DEFAULT false,
+                finish_time TIMESTAMP WITH TIME ZONE,
+                parent_story_id BIGINT REFERENCES stories (id)
+                    ON DELETE RESTRICT
+                    ON UPDATE CASCADE,
+                fork_position INT,
+                mode_code INT NOT NULL DEFAULT 0,
+                round_duration_s BIGINT NOT NULL DEFAULT 0,
+                open_to_join BOOLEAN NOT NULL DEFAULT false,
+                reveal_code INT NOT NULL DEFAULT 2
             )
         ", &[]));
 
+        for column in ["min_chars", "max_chars", "min_words", "max_words"].iter() {
+            try!(ensure_column(conn, "stories", column, "INT"));
+        }
+
+        try!(ensure_column(conn, "stories", "turn_policy_code", "INT NOT NULL DEFAULT 0"));
+        try!(ensure_column(conn, "stories", "turn_policy_count", "INT NOT NULL DEFAULT 1"));
+        try!(ensure_column(conn, "stories", "turn_policy_cooldown_s", "BIGINT NOT NULL DEFAULT 0"));
+        try!(ensure_column(conn, "stories", "visibility_code", "INT NOT NULL DEFAULT 0"));
+        try!(ensure_column(conn, "stories", "visibility_count", "INT NOT NULL DEFAULT 1"));
+        try!(ensure_column(conn, "stories", "max_snippets", "INT"));
+        try!(ensure_column(conn, "stories", "word_target", "INT"));
+        try!(ensure_column(conn, "stories", "deadline", "TIMESTAMP WITH TIME ZONE"));
+        try!(ensure_column(conn, "stories", "auto_publish", "BOOLEAN NOT NULL DEFAULT false"));
+        try!(ensure_column(conn, "stories", "finished", "BOOLEAN NOT NULL DEFAULT false"));
+        try!(ensure_column(conn, "stories", "finish_time", "TIMESTAMP WITH TIME ZONE"));
+        try!(ensure_column(conn, "stories", "parent_story_id",
+            "BIGINT REFERENCES stories (id) ON DELETE RESTRICT ON UPDATE CASCADE"));
+        try!(ensure_column(conn, "stories", "fork_position", "INT"));
+        try!(ensure_column(conn, "stories", "mode_code", "INT NOT NULL DEFAULT 0"));
+        try!(ensure_column(conn, "stories", "round_duration_s", "BIGINT NOT NULL DEFAULT 0"));
+        try!(ensure_column(conn, "stories", "open_to_join", "BOOLEAN NOT NULL DEFAULT false"));
+
+        // Stories that predate reveal policies stay readable by their spectators, as they were.
+        if try!(ensure_column(conn, "stories", "reveal_code", "INT NOT NULL DEFAULT 0")) {
+            try!(conn.execute("ALTER TABLE stories ALTER COLUMN reveal_code SET DEFAULT 2", &[]));
+        }
+
         try!(conn.execute("
             CREATE INDEX IF NOT EXISTS stories_lock_index ON stories (lock_user_id)
         ", &[]));
 
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS stories_parent_index ON stories (parent_story_id)
+        ", &[]));
+
         Ok(())
     }
 
     /// Create and persist a new `Story`. The provided `User` will be granted Owner-level access
     /// to the story.
     pub fn begin(conn: &GenericConnection, owner: &User) -> FictResult<Story> {
-        let insertion = try!(conn.prepare("
+        let insertion = try!(conn.prepare(&format!("
             INSERT INTO stories
             DEFAULT VALUES
-            RETURNING
-                id, title, published, world_readable, lock_duration_s, contribution_count,
-                creation_time, update_time,
-                publish_time, lock_user_id, lock_expiration
-        "));
+            RETURNING {}
+        ", STORY_COLUMNS)));
 
         let rows = try!(insertion.query(&[]));
-        let row = try!(first(&rows));
-
-        let story = Story{
-            id: row.get(0),
-            title: row.get(1),
-            published: row.get(2),
-            world_readable: row.get(3),
-            lock_duration_s: row.get(4),
-            contribution_count: row.get(5),
-            creation_time: row.get(6),
-            update_time: row.get(7),
-            publish_time: row.get(8),
-            lock_user_id: row.get(9),
-            lock_expiration: row.get(10)
-        };
+        let story = Story::from_row(try!(first(&rows)));
 
         // Automatically grant Owner access to the creating user.
         try!(StoryAccess::grant(conn, &story, owner, &AccessLevel::Owner));
@@ -88,6 +154,106 @@ impl Story {
         Ok(story)
     }
 
+    /// Create and persist a new `Story` that continues from `snippet_id` within the history of the
+    /// story `parent_id`, sharing each snippet up to and including that one. The fork begins with
+    /// its parent's settings, but has its own access, lock, and contribution count. The provided
+    /// `User` will be granted Owner-level access to the fork.
+    ///
+    /// If the parent does not exist or `owner` may not contribute to it, or if the snippet is not
+    /// part of the parent's history, return `Err(FictError::NotFound)`. If the parent's reveal
+    /// policy doesn't yet let `owner` read its full text, return `Err(FictError::Unrevealed)`.
+    pub fn fork(conn: &GenericConnection, parent_id: i64, snippet_id: i64, owner: &User, title: Option<String>) -> FictResult<Story> {
+        let transaction = try!(conn.transaction());
+
+        let parent = try!(try!(Story::with_id(&transaction, parent_id)).ok_or(FictError::NotFound));
+        let access = try!(parent.access_for(&transaction, owner));
+        if ! access.grants_write() {
+            return Err(FictError::NotFound);
+        }
+
+        // The fork's owner may always read its history, which includes the parent's snippets.
+        if ! parent.reveal.reveals(&parent, &access) {
+            return Err(FictError::Unrevealed);
+        }
+
+        let selection = try!(transaction.prepare("
+            SELECT position
+            FROM story_snippets($1)
+            WHERE id = $2
+        "));
+
+        let rows = try!(selection.query(&[&parent.id, &snippet_id]));
+        let fork_position: i32 = match try!(first_opt(&rows)) {
+            Some(row) => row.get(0),
+            None => return Err(FictError::NotFound)
+        };
+
+        let mut story = try!(Story::begin(&transaction, owner));
+
+        story.title = title.or(parent.title);
+        story.lock_duration_s = parent.lock_duration_s;
+        story.contribution_count = fork_position;
+        story.limits = parent.limits;
+        story.turn_policy = parent.turn_policy;
+        story.visibility = parent.visibility;
+        story.completion = parent.completion;
+        story.mode = parent.mode;
+        story.reveal = parent.reveal;
+        try!(story.save(&transaction));
+
+        try!(transaction.execute("
+            UPDATE stories
+            SET
+                parent_story_id = $2,
+                fork_position = $3
+            WHERE id = $1
+        ", &[&story.id, &parent.id, &fork_position]));
+
+        story.parent_story_id = Some(parent.id);
+        story.fork_position = Some(fork_position);
+
+        let constraints = try!(Constraint::for_story(&transaction, &parent));
+        try!(Constraint::replace_all(&transaction, &story, &constraints));
+
+        try!(transaction.commit());
+
+        Ok(story)
+    }
+
+    /// Retrieve each `Story` in the tree of forks that contains the story `id`, beginning with the
+    /// original story at its root. Each fork follows the story it was forked from.
+    pub fn fork_tree(conn: &GenericConnection, id: i64) -> FictResult<Vec<Story>> {
+        let selection = try!(conn.prepare(&format!("
+            WITH RECURSIVE
+                ancestors (id, parent_story_id) AS (
+                    SELECT id, parent_story_id
+                    FROM stories
+                    WHERE id = $1
+                  UNION ALL
+                    SELECT stories.id, stories.parent_story_id
+                    FROM stories
+                    INNER JOIN ancestors ON stories.id = ancestors.parent_story_id
+                ),
+                tree (id) AS (
+                    SELECT id
+                    FROM ancestors
+                    WHERE parent_story_id IS NULL
+                  UNION ALL
+                    SELECT stories.id
+                    FROM stories
+                    INNER JOIN tree ON stories.parent_story_id = tree.id
+                )
+            SELECT {}
+            FROM stories
+            WHERE id IN (SELECT id FROM tree)
+            ORDER BY id ASC
+        ", STORY_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+
+        Ok(rows.iter().map(Story::from_row).collect())
+    }
+
     /// Search for an existing `Story` by ID and ensure that a User holds an unexpired lock on that
     /// story.
     ///
@@ -97,41 +263,30 @@ impl Story {
     /// If the story is currently locked by someone else, return `Err(FictError::LockFailure)` with
     /// the lock details.
     ///
+    /// If the story has finished, or its deadline has passed, return `Err(FictError::Finished)`.
+    /// Stories whose deadline has passed are marked as finished by `Story::finish_overdue`.
+    ///
+    /// If the story chooses its snippets by vote, return `Err(FictError::VotingOnly)`. If its lock
+    /// rotates on a schedule and `acquire` is `true`, return `Err(FictError::Scheduled)`; turn
+    /// policies do not apply to such stories.
+    ///
     /// If `acquire` is `false` and the story is not locked, return `Err(FictError::Unlocked)`.
     ///
-    /// If the applicant has locked the story for contribution before and no other User has
-    /// contributed an intervening Snippet, return `Err(FictError::Cooldown)`.
+    /// If the story's `TurnPolicy` requires the applicant to wait before contributing again,
+    /// return `Err(FictError::Cooldown)`. If it requires another User to take the next turn,
+    /// return `Err(FictError::NotYourTurn)` naming them.
     ///
-    /// Otherwise, atomically acquire the Story lock on behalf of the applicant User.
-    pub fn locked_for_write(conn: &Connection, id: i64, applicant: &User, acquire: bool) -> FictResult<Story> {
+    /// Otherwise, atomically acquire the Story lock on behalf of the applicant User. If `acquire`
+    /// is `true`, record the applicant's `ContributionAttempt` within the same transaction.
+    ///
+    /// When called with an open transaction, the story row remains locked until that transaction
+    /// completes.
+    pub fn locked_for_write(conn: &GenericConnection, id: i64, applicant: &User, acquire: bool) -> FictResult<Story> {
         let now = UTC::now();
         let transaction = try!(conn.transaction());
 
         // Locate and lock the story row.
-        let selection = try!(transaction.prepare("
-            SELECT
-                id, title, published, world_readable, lock_duration_s, contribution_count,
-                creation_time, update_time, publish_time,
-                lock_user_id, lock_expiration
-            FROM stories
-            WHERE id = $1
-            FOR UPDATE
-        "));
-
-        let selection_rows = try!(selection.query(&[&id]));
-        let story_opt = try!(first_opt(&selection_rows)).map(|row| Story{
-            id: row.get(0),
-            title: row.get(1),
-            published: row.get(2),
-            world_readable: row.get(3),
-            lock_duration_s: row.get(4),
-            contribution_count: row.get(5),
-            creation_time: row.get(6),
-            update_time: row.get(7),
-            publish_time: row.get(8),
-            lock_user_id: row.get(9),
-            lock_expiration: row.get(10)
-        });
+        let story_opt = try!(Story::with_id_for_update(&transaction, id));
 
         // Story ID does not match a known story.
         if story_opt.is_none() {
@@ -140,11 +295,29 @@ impl Story {
         let mut story = story_opt.unwrap();
 
         // Applicant does not have sufficient permission to lock this story.
-        let access = try!(story.access_for(conn, applicant));
+        let access = try!(story.access_for(&transaction, applicant));
         if ! access.grants_write() {
             return Err(FictError::NotFound);
         }
 
+        // Story has finished, or its deadline has passed since the last contribution. Finishing
+        // the story here would be rolled back along with any enclosing transaction, so it's left
+        // to the scheduler.
+        if story.finished || story.completion.deadline_passed(now) {
+            return Err(FictError::Finished);
+        }
+
+        // Story chooses its snippets by vote instead.
+        if story.mode.is_voting() {
+            return Err(FictError::VotingOnly);
+        }
+
+        // Story's lock is assigned by its sprint schedule instead.
+        let scheduled = story.mode.is_sprint();
+        if scheduled && acquire {
+            return Err(FictError::Scheduled);
+        }
+
         // Applicant is not a persisted user. Caller error.
         let applicant_id = try!(applicant.id.ok_or(
             fict_err(format!("User {} must be persisted to lock a story.", applicant.name))
@@ -179,24 +352,23 @@ impl Story {
             };
         }
 
-        // Ensure that at least one Snippet has been contributed since the last time the applicant
-        // locked the Story for contribution.
-        //
-        // Permit the lock to continue if:
-        // 1. applicant has already seen this Snippet
-        //    (attempt == contribution count)
-        // OR
-        // 2. at least one other Snippet has been contributed since the last attempt
-        //    (attempt + 2 <= contribution count)
-        // OR
-        // 3. applicant has *never* locked the story (None)
-        let at_least_one_between =
-            try!(ContributionAttempt::most_recent_attempt(conn, &story, &applicant))
-            .map(|attempt| attempt == story.contribution_count || attempt + 2 <= story.contribution_count)
-            .unwrap_or(true); // No prior contributon attempts.
+        if scheduled {
+            // Writers may only contribute during their own scheduled turn, and hold the lock
+            // until it ends.
+            if ! expiration_is_valid {
+                return Err(FictError::Unlocked);
+            }
 
-        if ! at_least_one_between {
-            return Err(FictError::Cooldown);
+            try!(transaction.commit());
+
+            return Ok(story);
+        }
+
+        // Ensure that the story's turn policy permits the applicant to take the next turn.
+        match try!(story.turn_policy.evaluate(&transaction, &story, applicant, now)) {
+            Turn::Permitted => (),
+            Turn::TooSoon => return Err(FictError::Cooldown),
+            Turn::Awaiting(next) => return Err(FictError::NotYourTurn { username: next.name })
         }
 
         // Acquire the story lock and compute a new expiration.
@@ -214,6 +386,10 @@ impl Story {
         story.lock_user_id = Some(applicant_id);
         story.lock_expiration = Some(lock_expiration);
 
+        if acquire {
+            try!(ContributionAttempt::record(&transaction, &story, applicant));
+        }
+
         try!(transaction.commit());
 
         // Return the locked story.
@@ -222,32 +398,116 @@ impl Story {
 
     /// Search for an existing `Story` by ID.
     pub fn with_id(conn: &GenericConnection, id: i64) -> FictResult<Option<Story>> {
-        let selection = try!(conn.prepare("
-            SELECT
-                id, title, published, world_readable, lock_duration_s, contribution_count,
-                creation_time, update_time, publish_time,
-                lock_user_id, lock_expiration
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
             FROM stories
             WHERE id = $1
-        "));
+        ", STORY_COLUMNS)));
 
         let rows = try!(selection.query(&[&id]));
         let row_opt = try!(first_opt(&rows));
 
-        Ok(row_opt
-            .map(|row| Story{
-                id: row.get(0),
-                title: row.get(1),
-                published: row.get(2),
-                world_readable: row.get(3),
-                lock_duration_s: row.get(4),
-                contribution_count: row.get(5),
-                creation_time: row.get(6),
-                update_time: row.get(7),
-                publish_time: row.get(8),
-                lock_user_id: row.get(9),
-                lock_expiration: row.get(10)
-            }))
+        Ok(row_opt.map(Story::from_row))
+    }
+
+    /// Search for an existing `Story` by ID and lock its row until the current transaction
+    /// completes.
+    pub fn with_id_for_update(conn: &GenericConnection, id: i64) -> FictResult<Option<Story>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM stories
+            WHERE id = $1
+            FOR UPDATE
+        ", STORY_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Story::from_row))
+    }
+
+    /// Search for an existing `Story` by ID that a `User` is permitted to read. Produce the story
+    /// along with the user's access level, or `Err(FictError::NotFound)` if the story does not
+    /// exist or the user may not know of its existence.
+    pub fn visible_to(conn: &GenericConnection, id: i64, user: &User) -> FictResult<(Story, AccessLevel)> {
+        let story = try!(try!(Story::with_id(conn, id)).ok_or(FictError::NotFound));
+        let access = try!(story.access_for(conn, user));
+
+        if access.grants_read() {
+            Ok((story, access))
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// List the stories that a `User` may discover: those they may read, and those that are open
+    /// to join requests. The most recently updated stories are listed first.
+    pub fn listed_for(conn: &GenericConnection, user: &User, limit: i64) -> FictResult<Vec<Story>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM stories
+            WHERE
+                open_to_join OR
+                (published AND world_readable) OR
+                id IN (
+                    SELECT story_id
+                    FROM story_grants
+                    WHERE user_id = $1 AND access_level_code >= $2
+                )
+            ORDER BY update_time DESC, id DESC
+            LIMIT $3
+        ", STORY_COLUMNS)));
+
+        let rows = try!(selection.query(&[&user.id, &AccessLevel::Reader.encode(), &limit]));
+
+        Ok(rows.iter().map(Story::from_row).collect())
+    }
+
+    /// List each unfinished story that's waiting on a `User` at `now`: sprints where it's their
+    /// turn, and stories whose lock is already theirs or free for them to take under the story's
+    /// turn policy. Stories that choose their snippets by vote are never included.
+    pub fn awaiting_turn(conn: &GenericConnection, user: &User, now: DateTime<UTC>) -> FictResult<Vec<Story>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM stories
+            WHERE
+                NOT finished AND
+                id IN (
+                    SELECT story_id
+                    FROM story_grants
+                    WHERE user_id = $1 AND access_level_code >= $2
+                ) AND (
+                    (mode_code = $3 AND lock_user_id = $1) OR
+                    (mode_code = $4 AND (
+                        lock_user_id IS NULL OR lock_user_id = $1 OR lock_expiration < $5
+                    ))
+                )
+            ORDER BY update_time ASC, id ASC
+        ", STORY_COLUMNS)));
+
+        let (sprint_code, _) = StoryMode::Sprint(0).encode();
+        let (locking_code, _) = StoryMode::Locking.encode();
+
+        let rows = try!(selection.query(&[
+            &user.id, &AccessLevel::Writer.encode(), &sprint_code, &locking_code, &now
+        ]));
+
+        let mut stories = Vec::new();
+        for story in rows.iter().map(Story::from_row) {
+            // Writers who've just taken a turn, or who must wait for someone else's, aren't being
+            // waited on until the turn policy permits them to lock the story.
+            let waiting = story.mode != StoryMode::Locking || story.lock_user_id == user.id ||
+                match try!(story.turn_policy.evaluate(conn, &story, user, now)) {
+                    Turn::Permitted => true,
+                    _ => false
+                };
+
+            if waiting {
+                stories.push(story);
+            }
+        }
+
+        Ok(stories)
     }
 
     /// Determine the level of access granted to a given `User`.
@@ -261,6 +521,386 @@ impl Story {
         })
     }
 
+    /// List each `User` who has contributed a snippet to this story, including snippets inherited
+    /// from the story it was forked from, in the order of their first contribution.
+    pub fn contributors(&self, conn: &GenericConnection) -> FictResult<Vec<User>> {
+        let selection = try!(conn.prepare("
+            SELECT users.id, users.name, users.email
+            FROM story_snippets($1) AS snippets
+            INNER JOIN users ON users.id = snippets.user_id
+            GROUP BY users.id, users.name, users.email
+            ORDER BY MIN(snippets.position) ASC
+        "));
+
+        let rows = try!(selection.query(&[&self.id]));
+
+        Ok(rows.iter().map(|row| User{
+            id: Some(row.get(0)),
+            name: row.get(1),
+            email: row.get(2)
+        }).collect())
+    }
+
+    /// Count a newly contributed `Snippet` toward this story and release the lock held by its
+    /// author. This should be called within the same transaction that verified the lock with
+    /// `Story::locked_for_write`.
+    pub fn record_contribution(&mut self, conn: &GenericConnection) -> FictResult<()> {
+        let now = UTC::now();
+
+        let update = try!(conn.prepare("
+            UPDATE stories
+            SET
+                contribution_count = contribution_count + 1,
+                update_time = $2,
+                lock_user_id = NULL,
+                lock_expiration = NULL
+            WHERE id = $1
+            RETURNING contribution_count
+        "));
+
+        let rows = try!(update.query(&[&self.id, &now]));
+        let row = try!(first(&rows));
+
+        self.contribution_count = row.get(0);
+        self.update_time = now;
+        self.lock_user_id = None;
+        self.lock_expiration = None;
+
+        Ok(())
+    }
+
+    /// Discount a `Snippet` that has been retracted from this story and release any outstanding
+    /// lock, because its holder was continuing from the retracted snippet. Sprints keep their lock,
+    /// since it belongs to the writer whose scheduled turn it is. This should be called within the
+    /// same transaction that holds the story's row lock.
+    pub fn record_retraction(&mut self, conn: &GenericConnection) -> FictResult<()> {
+        let now = UTC::now();
+        let keep_lock = self.mode.is_sprint();
+
+        let update = try!(conn.prepare("
+            UPDATE stories
+            SET
+                contribution_count = contribution_count - 1,
+                update_time = $2,
+                lock_user_id = CASE WHEN $3 THEN lock_user_id END,
+                lock_expiration = CASE WHEN $3 THEN lock_expiration END
+            WHERE id = $1
+            RETURNING contribution_count
+        "));
+
+        let rows = try!(update.query(&[&self.id, &now, &keep_lock]));
+        let row = try!(first(&rows));
+
+        self.contribution_count = row.get(0);
+        self.update_time = now;
+
+        if ! keep_lock {
+            self.lock_user_id = None;
+            self.lock_expiration = None;
+        }
+
+        Ok(())
+    }
+
+    /// Pass the lock of a story in `StoryMode::Sprint` to the writer after `previous`, in the order
+    /// that writers were granted access, for one turn beginning at `now`. If `previous` is `None`
+    /// or is no longer a writer, continue from the author of the most recent `Snippet` instead.
+    /// The next writer is recorded as attempting to contribute, which closes the most recent
+    /// snippet to edits by its author. Stories in other modes are left unchanged. The caller must
+    /// hold the story's row lock.
+    pub fn advance_turn(&mut self, conn: &GenericConnection, previous: Option<i64>, now: DateTime<UTC>) -> FictResult<()> {
+        let turn_s = match self.mode {
+            StoryMode::Sprint(seconds) => seconds,
+            _ => return Ok(())
+        };
+
+        let writers = try!(StoryAccess::writers(conn, self));
+
+        let previous_index = match previous.and_then(|id| writers.iter().position(|w| w.id == Some(id))) {
+            Some(i) => Some(i),
+            None => try!(Snippet::most_recent(conn, self))
+                .and_then(|snippet| writers.iter().position(|w| w.id == Some(snippet.user_id)))
+        };
+
+        let next_index = previous_index
+            .map(|i| (i + 1) % writers.len())
+            .unwrap_or(0);
+
+        let (lock_user_id, lock_expiration) = match writers.get(next_index) {
+            Some(next) => {
+                try!(ContributionAttempt::record(conn, self, next));
+                (next.id, Some(now + Duration::seconds(turn_s)))
+            },
+            None => (None, None)
+        };
+
+        try!(conn.execute("
+            UPDATE stories
+            SET
+                lock_user_id = $2,
+                lock_expiration = $3
+            WHERE id = $1
+        ", &[&self.id, &lock_user_id, &lock_expiration]));
+
+        self.lock_user_id = lock_user_id;
+        self.lock_expiration = lock_expiration;
+
+        Ok(())
+    }
+
+    /// Skip the turn of each writer who has let their turn expire in an unfinished story in
+    /// `StoryMode::Sprint`, and begin the first turn of any sprint that has none. Sprints whose
+    /// deadline has passed are finished instead. Produce the number of stories changed.
+    pub fn rotate_sprint_turns(conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<usize> {
+        let transaction = try!(conn.transaction());
+
+        let selection = try!(transaction.prepare(&format!("
+            SELECT {}
+            FROM stories
+            WHERE
+                mode_code = $1 AND NOT finished AND
+                (lock_expiration IS NULL OR lock_expiration <= $2)
+            FOR UPDATE
+        ", STORY_COLUMNS)));
+
+        let (sprint_code, _) = StoryMode::Sprint(0).encode();
+        let rows = try!(selection.query(&[&sprint_code, &now]));
+
+        let mut count = 0;
+        for mut story in rows.iter().map(Story::from_row) {
+            let previous = story.lock_user_id;
+            if previous.is_some() {
+                try!(StoryEvent::record(&transaction, story.id, EventKind::LockExpired, None, previous, None));
+            }
+
+            if story.completion.deadline_passed(now) {
+                try!(story.finish(&transaction, now));
+            } else {
+                try!(story.advance_turn(&transaction, previous, now));
+
+                if story.lock_user_id.is_some() {
+                    try!(StoryEvent::record(&transaction, story.id, EventKind::LockAcquired, None, story.lock_user_id, None));
+                } else if previous.is_none() {
+                    // Sprints without any writers have no turn to begin, so nothing has changed.
+                    continue;
+                }
+            }
+
+            count += 1;
+        }
+
+        try!(transaction.commit());
+
+        Ok(count)
+    }
+
+    /// Finish each unfinished story whose deadline has passed, recording an
+    /// `EventKind::StoryPublished` event for each that its `CompletionRules` publish. Produce the
+    /// number of stories finished.
+    pub fn finish_overdue(conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<usize> {
+        let transaction = try!(conn.transaction());
+
+        let selection = try!(transaction.prepare(&format!("
+            SELECT {}
+            FROM stories
+            WHERE NOT finished AND deadline <= $1
+            FOR UPDATE
+        ", STORY_COLUMNS)));
+
+        let rows = try!(selection.query(&[&now]));
+
+        let mut count = 0;
+        for mut story in rows.iter().map(Story::from_row) {
+            let was_published = story.published;
+            try!(story.finish(&transaction, now));
+
+            if story.published && ! was_published {
+                try!(StoryEvent::record(&transaction, story.id, EventKind::StoryPublished, None, None, None));
+            }
+
+            count += 1;
+        }
+
+        try!(transaction.commit());
+
+        Ok(count)
+    }
+
+    /// Release each lock that has expired without a contribution, recording a
+    /// `EventKind::LockExpired` event for each. Stories in `StoryMode::Sprint` are left to
+    /// `Story::rotate_sprint_turns`. Produce the number of locks released.
+    pub fn expire_locks(conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<usize> {
+        let transaction = try!(conn.transaction());
+
+        let update = try!(transaction.prepare("
+            UPDATE stories
+            SET
+                lock_user_id = NULL,
+                lock_expiration = NULL
+            WHERE
+                mode_code <> $1 AND
+                lock_user_id IS NOT NULL AND
+                lock_expiration <= $2
+            RETURNING id, lock_user_id
+        "));
+
+        let (sprint_code, _) = StoryMode::Sprint(0).encode();
+        let rows = try!(update.query(&[&sprint_code, &now]));
+
+        for row in rows.iter() {
+            let holder: Option<i64> = row.get(1);
+            try!(StoryEvent::record(&transaction, row.get(0), EventKind::LockExpired, None, holder, None));
+        }
+
+        try!(transaction.commit());
+
+        Ok(rows.len())
+    }
+
+    /// Project the turns of a story in `StoryMode::Sprint` through one full rotation of its
+    /// writers, beginning with the current turn. Later turns assume that each writer uses their
+    /// whole turn. Produce an empty schedule if no turn is in progress.
+    pub fn sprint_schedule(&self, conn: &GenericConnection) -> FictResult<Vec<SprintTurn>> {
+        let turn = match self.mode {
+            StoryMode::Sprint(seconds) => Duration::seconds(seconds),
+            _ => return Ok(Vec::new())
+        };
+
+        let writers = try!(StoryAccess::writers(conn, self));
+
+        let (current_index, expiration) = match (self.lock_user_id, self.lock_expiration) {
+            (Some(id), Some(expiration)) => match writers.iter().position(|w| w.id == Some(id)) {
+                Some(i) => (i, expiration),
+                None => return Ok(Vec::new())
+            },
+            _ => return Ok(Vec::new())
+        };
+
+        let count = writers.len();
+        let mut start_time = expiration - turn;
+
+        Ok(writers.into_iter().cycle().skip(current_index).take(count).map(|user| {
+            let t = SprintTurn{
+                user: user,
+                start_time: start_time,
+                end_time: start_time + turn
+            };
+            start_time = start_time + turn;
+            t
+        }).collect())
+    }
+
+    /// Mark this story as finished at `now`, publishing it if its `CompletionRules` call for it.
+    /// No further locks will be granted, so any outstanding lock is released.
+    pub fn finish(&mut self, conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<()> {
+        self.finished = true;
+        self.finish_time = Some(now);
+        self.update_time = now;
+
+        if self.completion.auto_publish {
+            self.publish(now);
+        }
+
+        try!(self.save(conn));
+
+        try!(conn.execute("
+            UPDATE stories
+            SET
+                lock_user_id = NULL,
+                lock_expiration = NULL
+            WHERE id = $1
+        ", &[&self.id]));
+
+        self.lock_user_id = None;
+        self.lock_expiration = None;
+
+        Ok(())
+    }
+
+    /// Publish this story as of `now`, if it has not already been published. Call `save` to
+    /// persist the change.
+    pub fn publish(&mut self, now: DateTime<UTC>) {
+        if ! self.published {
+            self.published = true;
+            self.publish_time = Some(now);
+        }
+    }
+
+    /// Acquire or extend the lock on the story `id` on behalf of `applicant`, verified as by
+    /// `Story::locked_for_write`, and record the `StoryEvent`. Produce what the new lock holder
+    /// needs to continue the story: as much of it so far as its visibility policy reveals, the
+    /// prompt that it began from if nobody has contributed yet, and its constraints.
+    ///
+    /// If someone else holds the lock, `applicant` is told once the story is available again and
+    /// `Err(FictError::AlreadyLocked)` is returned.
+    pub fn acquire_lock(conn: &GenericConnection, id: i64, applicant: &User) -> FictResult<LockGrant> {
+        let transaction = try!(conn.transaction());
+
+        let story = match Story::locked_for_write(&transaction, id, applicant, true) {
+            Ok(story) => story,
+            Err(e @ FictError::AlreadyLocked {..}) => {
+                drop(transaction);
+                try!(LockWaiter::wait(conn, id, applicant));
+                return Err(e);
+            },
+            Err(e) => return Err(e)
+        };
+
+        let expiration = try!(story.lock_expiration.ok_or(fict_err("Story missing expiration date")));
+
+        try!(StoryEvent::record(
+            &transaction, story.id, EventKind::LockAcquired, applicant.id, None,
+            Some(timestamp(&expiration))
+        ));
+
+        let excerpts = try!(story.visibility.excerpts(&transaction, &story));
+
+        // The first writer of a story that began from a prompt sees the prompt instead.
+        let prompt = if story.contribution_count == 0 {
+            try!(StoryPrompt::for_story(&transaction, &story))
+        } else {
+            None
+        };
+
+        let constraints = try!(Constraint::for_story(&transaction, &story));
+
+        try!(transaction.commit());
+
+        Ok(LockGrant {
+            story: story,
+            expiration: expiration,
+            excerpts: excerpts,
+            prompt: prompt,
+            constraints: constraints
+        })
+    }
+
+    /// Give up the lock on the story `id` held by `holder`, verified as by
+    /// `Story::locked_for_write`. Sprints pass the lock straight to the next writer instead. The
+    /// story's row stays locked from the check until the resulting `StoryEvent`s are recorded, so
+    /// that a concurrent rotation can't hand out the same turn.
+    pub fn release_lock(conn: &GenericConnection, id: i64, holder: &User, now: DateTime<UTC>) -> FictResult<Story> {
+        let transaction = try!(conn.transaction());
+
+        let mut story = try!(Story::locked_for_write(&transaction, id, holder, false));
+
+        if story.mode.is_sprint() {
+            try!(story.advance_turn(&transaction, holder.id, now));
+        } else {
+            try!(story.unlock(&transaction));
+        }
+
+        try!(StoryEvent::record(&transaction, story.id, EventKind::LockReleased, holder.id, None, None));
+
+        if story.mode.is_sprint() && story.lock_user_id.is_some() {
+            try!(StoryEvent::record(&transaction, story.id, EventKind::LockAcquired, holder.id, story.lock_user_id, None));
+        }
+
+        try!(transaction.commit());
+
+        Ok(story)
+    }
+
     /// Revoke the currently-held story lock, if any.
     pub fn unlock(&self, conn: &GenericConnection) -> FictResult<()> {
         let update = try!(conn.prepare("
@@ -294,15 +934,47 @@ impl Story {
                 contribution_count = $6,
                 creation_time = $7,
                 update_time = $8,
-                publish_time = $9
+                publish_time = $9,
+                min_chars = $10,
+                max_chars = $11,
+                min_words = $12,
+                max_words = $13,
+                turn_policy_code = $14,
+                turn_policy_count = $15,
+                turn_policy_cooldown_s = $16,
+                visibility_code = $17,
+                visibility_count = $18,
+                max_snippets = $19,
+                word_target = $20,
+                deadline = $21,
+                auto_publish = $22,
+                finished = $23,
+                finish_time = $24,
+                mode_code = $25,
+                round_duration_s = $26,
+                open_to_join = $27,
+                reveal_code = $28
             WHERE id = $1
         "));
 
+        let (turn_policy_code, turn_policy_count, turn_policy_cooldown_s) = self.turn_policy.encode();
+        let (visibility_code, visibility_count) = self.visibility.encode();
+        let (mode_code, round_duration_s) = self.mode.encode();
+        let reveal_code = self.reveal.encode();
+
         let count = try!(update.execute(&[
             &self.id,
             &self.title, &self.published, &self.world_readable, &self.lock_duration_s,
             &self.contribution_count,
-            &self.creation_time, &self.update_time, &self.publish_time
+            &self.creation_time, &self.update_time, &self.publish_time,
+            &self.limits.min_chars, &self.limits.max_chars,
+            &self.limits.min_words, &self.limits.max_words,
+            &turn_policy_code, &turn_policy_count, &turn_policy_cooldown_s,
+            &visibility_code, &visibility_count,
+            &self.completion.max_snippets, &self.completion.word_target,
+            &self.completion.deadline, &self.completion.auto_publish,
+            &self.finished, &self.finish_time,
+            &mode_code, &round_duration_s, &self.open_to_join, &reveal_code
         ]));
 
         if count == 1 {
@@ -311,6 +983,96 @@ impl Story {
             Err(fict_err("Unable to update story"))
         }
     }
+
+    /// Construct a `Story` from a row containing each of the `STORY_COLUMNS`.
+    fn from_row(row: Row) -> Story {
+        Story{
+            id: row.get(0),
+            title: row.get(1),
+            published: row.get(2),
+            world_readable: row.get(3),
+            lock_duration_s: row.get(4),
+            contribution_count: row.get(5),
+            creation_time: row.get(6),
+            update_time: row.get(7),
+            publish_time: row.get(8),
+            lock_user_id: row.get(9),
+            lock_expiration: row.get(10),
+            limits: ContentLimits{
+                min_chars: row.get(11),
+                max_chars: row.get(12),
+                min_words: row.get(13),
+                max_words: row.get(14)
+            },
+            turn_policy: TurnPolicy::decode(row.get(15), row.get(16), row.get(17)),
+            visibility: VisibilityPolicy::decode(row.get(18), row.get(19)),
+            completion: CompletionRules{
+                max_snippets: row.get(20),
+                word_target: row.get(21),
+                deadline: row.get(22),
+                auto_publish: row.get(23)
+            },
+            finished: row.get(24),
+            finish_time: row.get(25),
+            parent_story_id: row.get(26),
+            fork_position: row.get(27),
+            mode: StoryMode::decode(row.get(28), row.get(29)),
+            open_to_join: row.get(30),
+            reveal: RevealPolicy::decode(row.get(31))
+        }
+    }
+}
+
+#[cfg(test)]
+impl Story {
+
+    /// Construct an unsaved `Story` with default settings, for tests that don't need a database.
+    pub fn sample(id: i64, title: Option<&str>) -> Story {
+        let now = UTC::now();
+
+        Story{
+            id: id,
+            title: title.map(|t| t.to_owned()),
+            published: false,
+            world_readable: false,
+            lock_duration_s: 21600,
+            contribution_count: 0,
+            creation_time: now,
+            update_time: now,
+            publish_time: None,
+            lock_user_id: None,
+            lock_expiration: None,
+            limits: Default::default(),
+            turn_policy: Default::default(),
+            visibility: Default::default(),
+            completion: Default::default(),
+            finished: false,
+            finish_time: None,
+            parent_story_id: None,
+            fork_position: None,
+            mode: Default::default(),
+            open_to_join: false,
+            reveal: Default::default()
+        }
+    }
+
+}
+
+/// Scheduled turn of a writer in a `Story` in `StoryMode::Sprint`.
+pub struct SprintTurn {
+    pub user: User,
+    pub start_time: DateTime<UTC>,
+    pub end_time: DateTime<UTC>
+}
+
+/// Lock on a `Story` granted by `Story::acquire_lock`, along with what its holder is shown to
+/// continue the story.
+pub struct LockGrant {
+    pub story: Story,
+    pub expiration: DateTime<UTC>,
+    pub excerpts: Vec<Excerpt>,
+    pub prompt: Option<StoryPrompt>,
+    pub constraints: Vec<Constraint>
 }
 
 /// Level of access granted to a specific `User` on a `Story`.
@@ -324,7 +1086,7 @@ pub enum AccessLevel {
 impl AccessLevel {
 
     /// Convert an AccessLevel into an integer for serialization within a database table.
-    fn encode(&self) -> i32 {
+    pub fn encode(&self) -> i32 {
         match *self {
             AccessLevel::NoAccess => 0,
             AccessLevel::Reader => 1,
@@ -334,7 +1096,7 @@ impl AccessLevel {
     }
 
     /// Create an AccessLevel from an integer previously encoded with `::encode()`.
-    fn decode(value: i32) -> FictResult<AccessLevel> {
+    pub fn decode(value: i32) -> FictResult<AccessLevel> {
         match value {
             0 => Ok(AccessLevel::NoAccess),
             1 => Ok(AccessLevel::Reader),
@@ -344,6 +1106,28 @@ impl AccessLevel {
         }
     }
 
+    /// Name used for this level within request and response documents.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AccessLevel::NoAccess => "none",
+            AccessLevel::Reader => "reader",
+            AccessLevel::Writer => "writer",
+            AccessLevel::Owner => "owner"
+        }
+    }
+
+    /// Interpret a level named within a request document. Produce `None` if the name is
+    /// unrecognized.
+    pub fn from_name(name: &str) -> Option<AccessLevel> {
+        match name {
+            "none" => Some(AccessLevel::NoAccess),
+            "reader" => Some(AccessLevel::Reader),
+            "writer" => Some(AccessLevel::Writer),
+            "owner" => Some(AccessLevel::Owner),
+            _ => None
+        }
+    }
+
     /// Return true if this level permits users to know the existence of this `Story` in search
     /// results and so on.
     pub fn grants_read(&self) -> bool {
@@ -414,8 +1198,12 @@ impl StoryAccess {
     }
 
     /// Grant a `User` access to a `Story` at a specified level. If level is `NoAccess`, any
-    /// access will be removed.
+    /// access will be removed. Either way, an `EventKind::AccessChanged` event is recorded.
     pub fn grant(conn: &GenericConnection, story: &Story, user: &User, level: &AccessLevel) -> FictResult<()> {
+        try!(StoryEvent::record(
+            conn, story.id, EventKind::AccessChanged, None, user.id, Some(level.name().to_owned())
+        ));
+
         match *level {
             AccessLevel::NoAccess => {
                 // Revoke any existing access instead.
@@ -455,19 +1243,45 @@ impl StoryAccess {
         Ok(())
     }
 
-    /// Determine the current access level that a `User` has on a `Story`.
+    /// List each `User` who may contribute to a `Story`, either individually or as a member of a
+    /// `Group`. Users granted access individually are listed first, in the order that they were
+    /// granted access, followed by those granted access only through their groups.
+    pub fn writers(conn: &GenericConnection, story: &Story) -> FictResult<Vec<User>> {
+        let selection = try!(conn.prepare("
+            SELECT users.id, users.name, users.email
+            FROM story_grants
+            INNER JOIN users ON users.id = story_grants.user_id
+            WHERE story_grants.story_id = $1
+            GROUP BY users.id, users.name, users.email
+            HAVING MAX(story_grants.access_level_code) >= $2
+            ORDER BY
+                MIN(CASE WHEN story_grants.via_group THEN NULL ELSE story_grants.grant_id END) ASC NULLS LAST,
+                MIN(story_grants.grant_id) ASC
+        "));
+
+        let rows = try!(selection.query(&[&story.id, &AccessLevel::Writer.encode()]));
+
+        Ok(rows.iter().map(|row| User{
+            id: Some(row.get(0)),
+            name: row.get(1),
+            email: row.get(2)
+        }).collect())
+    }
+
+    /// Determine the current access level that a `User` has on a `Story`: the highest of the
+    /// levels granted to them individually and to each `Group` that they belong to.
     fn access_for(conn: &GenericConnection, user: &User, story: &Story) -> FictResult<AccessLevel> {
         let locate = try!(conn.prepare("
-            SELECT access_level_code
-            FROM story_access
+            SELECT MAX(access_level_code)
+            FROM story_grants
             WHERE user_id = $1 AND story_id = $2
         "));
 
         let rows = try!(locate.query(&[&user.id, &story.id]));
-        let row_opt = try!(first_opt(&rows));
+        let code: Option<i32> = try!(first(&rows)).get(0);
 
-        row_opt
-            .map(|row| AccessLevel::decode(row.get(0)))
+        code
+            .map(AccessLevel::decode)
             .unwrap_or(Ok(AccessLevel::NoAccess))
     }
 
@@ -507,7 +1321,9 @@ impl ContributionAttempt {
         Ok(())
     }
 
-    fn most_recent_attempt(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<Option<i32>> {
+    /// Find the contribution count that a story had the last time a `User` locked it, if they
+    /// ever have.
+    pub fn most_recent_attempt(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<Option<i32>> {
         let select = try!(conn.prepare("
             SELECT contribution_count
             FROM contribution_attempts
@@ -520,6 +1336,35 @@ impl ContributionAttempt {
         Ok(row_opt.map(|row| row.get(0)))
     }
 
+    /// Determine whether or not any `User` other than `user` has locked the story for
+    /// contribution since its most recent `Snippet` was contributed.
+    pub fn attempted_by_other(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<bool> {
+        let select = try!(conn.prepare("
+            SELECT 1
+            FROM contribution_attempts
+            WHERE story_id = $1 AND user_id <> $2 AND contribution_count >= $3
+        "));
+
+        let rows = try!(select.query(&[&story.id, &user.id, &story.contribution_count]));
+
+        Ok(!rows.is_empty())
+    }
+
+    /// Rewind any contribution attempts made beyond a story's current contribution count, after
+    /// its most recent `Snippet` has been retracted. Users who locked the story to continue the
+    /// retracted snippet are treated as though they had seen the snippet that's now most recent.
+    pub fn rewind(conn: &GenericConnection, story: &Story) -> FictResult<()> {
+        let update = try!(conn.prepare("
+            UPDATE contribution_attempts
+            SET contribution_count = $2
+            WHERE story_id = $1 AND contribution_count > $2
+        "));
+
+        try!(update.execute(&[&story.id, &story.contribution_count]));
+
+        Ok(())
+    }
+
     /// Record a new contribution attempt.
     pub fn record(conn: &GenericConnection, story: &Story, user: &User) -> FictResult<()> {
         let update = try!(conn.prepare("
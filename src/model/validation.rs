@@ -0,0 +1,138 @@
+//! Normalization and validation of user-provided content.
+
+use unicode_normalization::UnicodeNormalization;
+
+use error::{FictResult, FieldError};
+use error::FictError::Invalid;
+
+/// Bounds on the length of each `Snippet` contributed to a `Story`. A bound of `None` is not
+/// enforced.
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+pub struct ContentLimits {
+    pub min_chars: Option<i32>,
+    pub max_chars: Option<i32>,
+    pub min_words: Option<i32>,
+    pub max_words: Option<i32>
+}
+
+impl ContentLimits {
+
+    /// Ensure that these limits are coherent with one another. Report any problems as errors on
+    /// fields beneath `prefix`.
+    pub fn check(&self, prefix: &str) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        let bounds = [
+            ("min_chars", self.min_chars), ("max_chars", self.max_chars),
+            ("min_words", self.min_words), ("max_words", self.max_words)
+        ];
+
+        for &(name, bound) in bounds.iter() {
+            if bound.map(|b| b < 0).unwrap_or(false) {
+                errors.push(FieldError::new(
+                    format!("{}.{}", prefix, name), "negative", "Limits may not be negative."
+                ));
+            }
+        }
+
+        let ranges = [
+            ("min_chars", self.min_chars, self.max_chars),
+            ("min_words", self.min_words, self.max_words)
+        ];
+
+        for &(name, min, max) in ranges.iter() {
+            if let (Some(lower), Some(upper)) = (min, max) {
+                if lower > upper {
+                    errors.push(FieldError::new(
+                        format!("{}.{}", prefix, name), "exceeds_max",
+                        "A minimum may not exceed its corresponding maximum."
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Normalize submitted snippet content and verify it against these limits. Produce the
+    /// normalized content if it's acceptable, or `Err(FictError::Invalid)` describing each
+    /// violated limit.
+    pub fn accept(&self, content: String) -> FictResult<String> {
+        let normalized = normalize(&content);
+        let mut errors = Vec::new();
+
+        if normalized.trim().is_empty() {
+            errors.push(FieldError::new(
+                "snippet.content", "blank", "Content may not be empty."
+            ));
+
+            return Err(Invalid(errors));
+        }
+
+        let chars = normalized.chars().count() as i32;
+        let words = count_words(&normalized);
+
+        if let Some(min) = self.min_chars {
+            if chars < min {
+                errors.push(FieldError::new(
+                    "snippet.content", "too_few_chars",
+                    format!("Content must be at least {} characters long.", min)
+                ));
+            }
+        }
+
+        if let Some(max) = self.max_chars {
+            if chars > max {
+                errors.push(FieldError::new(
+                    "snippet.content", "too_many_chars",
+                    format!("Content may be no more than {} characters long.", max)
+                ));
+            }
+        }
+
+        if let Some(min) = self.min_words {
+            if words < min {
+                errors.push(FieldError::new(
+                    "snippet.content", "too_few_words",
+                    format!("Content must contain at least {} words.", min)
+                ));
+            }
+        }
+
+        if let Some(max) = self.max_words {
+            if words > max {
+                errors.push(FieldError::new(
+                    "snippet.content", "too_many_words",
+                    format!("Content may contain no more than {} words.", max)
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(normalized)
+        } else {
+            Err(Invalid(errors))
+        }
+    }
+
+}
+
+/// Convert content to Unicode normalization form C and use `\n` for every line ending.
+pub fn normalize(content: &str) -> String {
+    let composed: String = content.nfc().collect();
+
+    composed.replace("\r\n", "\n").replace("\r", "\n")
+}
+
+/// Normalize a story title like content, without its surrounding whitespace. Titles left empty
+/// become `None`.
+pub fn normalize_title(title: Option<String>) -> Option<String> {
+    title
+        .map(|t| normalize(t.trim()))
+        .and_then(|t| if t.is_empty() { None } else { Some(t) })
+}
+
+/// Count the whitespace-separated words within a piece of content.
+pub fn count_words(content: &str) -> i32 {
+    content.split_whitespace().count() as i32
+}
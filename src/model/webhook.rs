@@ -0,0 +1,479 @@
+//! Subscriptions that deliver story events to external HTTP endpoints, and the queue of
+//! deliveries made on their behalf.
+
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+use rand::{OsRng, Rng};
+
+use model::{first, first_opt, Story, AccessLevel, User, EventKind};
+use error::{FictResult, FictError};
+
+/// Columns selected by each query that produces a `Webhook`, in the order expected by
+/// `Webhook::from_row`.
+const WEBHOOK_COLUMNS: &'static str = "
+    webhooks.id, webhooks.owner_id, webhooks.story_id, webhooks.url, webhooks.secret,
+    webhooks.event_mask, webhooks.creation_time
+";
+
+/// Columns selected by each query that produces a `WebhookDelivery`, in the order expected by
+/// `WebhookDelivery::from_row`.
+const DELIVERY_COLUMNS: &'static str = "
+    webhook_deliveries.id, webhook_deliveries.webhook_id, webhook_deliveries.event_id,
+    webhook_deliveries.state_code, webhook_deliveries.attempt_count,
+    webhook_deliveries.next_attempt_time, webhook_deliveries.last_attempt_time,
+    webhook_deliveries.response_status, webhook_deliveries.last_error,
+    webhook_deliveries.creation_time
+";
+
+/// Length of each randomly generated signing secret.
+const SECRET_LEN: usize = 32;
+
+/// Number of attempts made to deliver an event before giving up on it.
+pub const MAX_ATTEMPTS: i32 = 8;
+
+/// Seconds to wait before retrying a failed delivery for the first time. Each later retry waits
+/// twice as long as the one before it.
+const RETRY_BASE_S: i64 = 30;
+
+/// Subscription that delivers each `StoryEvent` whose kind is among `events` to `url`, as a JSON
+/// document signed with `secret`. A webhook with a `story_id` follows that story alone; one without
+/// follows every story that its owner may read, and changes to its owner's own access.
+pub struct Webhook {
+    pub id: i64,
+    pub owner_id: i64,
+    pub story_id: Option<i64>,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<EventKind>,
+    pub creation_time: DateTime<UTC>
+}
+
+impl Webhook {
+
+    /// Initialize database tables and indices used to store `Webhook` and `WebhookDelivery`
+    /// objects.
+    ///
+    /// Depends on `User::initialize`, `Story::initialize`, and `StoryEvent::initialize`.
+    pub fn initialize(conn: &GenericConnection) -> FictResult<()> {
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS webhooks (
+                id BIGSERIAL PRIMARY KEY,
+                owner_id BIGINT NOT NULL REFERENCES users (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                story_id BIGINT REFERENCES stories (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                url VARCHAR NOT NULL,
+                secret VARCHAR NOT NULL,
+                event_mask INT NOT NULL,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc')
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS webhooks_owner_id_index
+            ON webhooks (owner_id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS webhooks_story_id_index
+            ON webhooks (story_id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id BIGSERIAL PRIMARY KEY,
+                webhook_id BIGINT NOT NULL REFERENCES webhooks (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                event_id BIGINT NOT NULL REFERENCES story_events (id)
+                    ON DELETE CASCADE
+                    ON UPDATE CASCADE,
+                state_code INT NOT NULL DEFAULT 0,
+                attempt_count INT NOT NULL DEFAULT 0,
+                next_attempt_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc'),
+                last_attempt_time TIMESTAMP WITH TIME ZONE,
+                response_status INT,
+                last_error VARCHAR,
+                creation_time TIMESTAMP WITH TIME ZONE NOT NULL
+                    DEFAULT (now() AT TIME ZONE 'utc')
+            )
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_index
+            ON webhook_deliveries (webhook_id, id)
+        ", &[]));
+
+        try!(conn.execute("
+            CREATE INDEX IF NOT EXISTS webhook_deliveries_pending_index
+            ON webhook_deliveries (next_attempt_time)
+            WHERE state_code = 0
+        ", &[]));
+
+        Ok(())
+    }
+
+    /// Create and persist a new `Webhook` owned by `owner` with a randomly generated secret. If
+    /// `story` is given, only its events are delivered. If `events` is empty, every kind of event
+    /// is delivered.
+    pub fn create(conn: &GenericConnection, owner: &User, story: Option<&Story>, url: String, events: &[EventKind]) -> FictResult<Webhook> {
+        let mut rng = try!(OsRng::new());
+        let secret: String = rng.gen_ascii_chars().take(SECRET_LEN).collect();
+
+        let event_mask = if events.is_empty() {
+            EventKind::mask(&EventKind::all())
+        } else {
+            EventKind::mask(events)
+        };
+
+        let insertion = try!(conn.prepare(&format!("
+            INSERT INTO webhooks (owner_id, story_id, url, secret, event_mask)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING {}
+        ", WEBHOOK_COLUMNS)));
+
+        let story_id = story.map(|s| s.id);
+        let rows = try!(insertion.query(&[&owner.id, &story_id, &url, &secret, &event_mask]));
+
+        Ok(Webhook::from_row(try!(first(&rows))))
+    }
+
+    /// Search for an existing `Webhook` by ID.
+    pub fn with_id(conn: &GenericConnection, id: i64) -> FictResult<Option<Webhook>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM webhooks
+            WHERE id = $1
+        ", WEBHOOK_COLUMNS)));
+
+        let rows = try!(selection.query(&[&id]));
+        let row_opt = try!(first_opt(&rows));
+
+        Ok(row_opt.map(Webhook::from_row))
+    }
+
+    /// Retrieve each `Webhook` owned by a `User`, in the order that they were created.
+    pub fn for_owner(conn: &GenericConnection, owner: &User) -> FictResult<Vec<Webhook>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM webhooks
+            WHERE owner_id = $1
+            ORDER BY id ASC
+        ", WEBHOOK_COLUMNS)));
+
+        let rows = try!(selection.query(&[&owner.id]));
+
+        Ok(rows.iter().map(Webhook::from_row).collect())
+    }
+
+    /// Determine whether or not a `User` owns this webhook.
+    pub fn is_owned_by(&self, user: &User) -> bool {
+        user.id == Some(self.owner_id)
+    }
+
+    /// Delete this webhook, along with its delivery log. Deliveries that haven't been made yet are
+    /// abandoned.
+    pub fn delete(&self, conn: &GenericConnection) -> FictResult<()> {
+        let count = try!(conn.execute("
+            DELETE FROM webhooks
+            WHERE id = $1
+        ", &[&self.id]));
+
+        if count == 1 {
+            Ok(())
+        } else {
+            Err(FictError::NotFound)
+        }
+    }
+
+    /// Construct a `Webhook` from a row containing each of the `WEBHOOK_COLUMNS`.
+    fn from_row(row: Row) -> Webhook {
+        Webhook{
+            id: row.get(0),
+            owner_id: row.get(1),
+            story_id: row.get(2),
+            url: row.get(3),
+            secret: row.get(4),
+            events: EventKind::from_mask(row.get(5)),
+            creation_time: row.get(6)
+        }
+    }
+
+}
+
+/// Progress of a `WebhookDelivery`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed
+}
+
+impl DeliveryState {
+
+    /// Convert a DeliveryState into an integer for serialization within a database table.
+    fn encode(&self) -> i32 {
+        match *self {
+            DeliveryState::Pending => 0,
+            DeliveryState::Delivered => 1,
+            DeliveryState::Failed => 2
+        }
+    }
+
+    /// Create a DeliveryState from an integer previously encoded with `::encode()`. Produce a
+    /// default and log a warning if the code is unrecognized.
+    fn decode(code: i32) -> DeliveryState {
+        match code {
+            0 => DeliveryState::Pending,
+            1 => DeliveryState::Delivered,
+            2 => DeliveryState::Failed,
+            _ => {
+                warn!("Invalid encoded delivery state [{}]. Defaulting to failed.", code);
+                DeliveryState::Failed
+            }
+        }
+    }
+
+    /// Name used for this state within API responses.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DeliveryState::Pending => "pending",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Failed => "failed"
+        }
+    }
+
+}
+
+/// Attempt to deliver a single `StoryEvent` to a `Webhook`. Pending deliveries are attempted once
+/// `next_attempt_time` arrives; each failed attempt pushes it further back, until `MAX_ATTEMPTS`
+/// have been made. Each delivery records the outcome of its most recent attempt.
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event_id: i64,
+    pub state: DeliveryState,
+    pub attempt_count: i32,
+    pub next_attempt_time: DateTime<UTC>,
+    pub last_attempt_time: Option<DateTime<UTC>>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub creation_time: DateTime<UTC>
+}
+
+impl WebhookDelivery {
+
+    /// Queue a delivery of the event `event_id` to each webhook subscribed to it whose owner may
+    /// still read its story: those following its story, and those following every story of their
+    /// owner. A webhook that follows every story is also sent changes to its owner's own access,
+    /// including its removal.
+    pub fn enqueue(conn: &GenericConnection, event_id: i64, story_id: i64, kind: &EventKind, subject_id: Option<i64>) -> FictResult<u64> {
+        let count = try!(conn.execute("
+            INSERT INTO webhook_deliveries (webhook_id, event_id)
+            SELECT webhooks.id, $1
+            FROM webhooks
+            WHERE
+                (webhooks.event_mask & $3) <> 0 AND (
+                    (webhooks.story_id IS NULL AND webhooks.owner_id = $4) OR (
+                        (webhooks.story_id = $2 OR webhooks.story_id IS NULL) AND
+                        webhooks.owner_id IN (
+                            SELECT user_id
+                            FROM story_grants
+                            WHERE story_id = $2 AND access_level_code >= $5
+                        )
+                    )
+                )
+        ", &[&event_id, &story_id, &EventKind::mask(&[kind.clone()]), &subject_id, &AccessLevel::Reader.encode()]));
+
+        Ok(count)
+    }
+
+    /// Retrieve up to `limit` pending deliveries that are due to be attempted at `now`, along
+    /// with their webhooks, oldest first.
+    pub fn due(conn: &GenericConnection, now: DateTime<UTC>, limit: i64) -> FictResult<Vec<(WebhookDelivery, Webhook)>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}, {}
+            FROM webhook_deliveries
+            INNER JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+            WHERE webhook_deliveries.state_code = $1 AND webhook_deliveries.next_attempt_time <= $2
+            ORDER BY webhook_deliveries.next_attempt_time ASC, webhook_deliveries.id ASC
+            LIMIT $3
+        ", DELIVERY_COLUMNS, WEBHOOK_COLUMNS)));
+
+        let rows = try!(selection.query(&[&DeliveryState::Pending.encode(), &now, &limit]));
+
+        Ok(rows.iter().map(|row| {
+            let delivery = WebhookDelivery::from_row(&row);
+            let webhook = Webhook{
+                id: row.get(10),
+                owner_id: row.get(11),
+                story_id: row.get(12),
+                url: row.get(13),
+                secret: row.get(14),
+                events: EventKind::from_mask(row.get(15)),
+                creation_time: row.get(16)
+            };
+
+            (delivery, webhook)
+        }).collect())
+    }
+
+    /// Retrieve up to `limit` deliveries made to a `Webhook`, most recent first.
+    pub fn for_webhook(conn: &GenericConnection, webhook: &Webhook, limit: i64) -> FictResult<Vec<WebhookDelivery>> {
+        let selection = try!(conn.prepare(&format!("
+            SELECT {}
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY id DESC
+            LIMIT $2
+        ", DELIVERY_COLUMNS)));
+
+        let rows = try!(selection.query(&[&webhook.id, &limit]));
+
+        Ok(rows.iter().map(|row| WebhookDelivery::from_row(&row)).collect())
+    }
+
+    /// Record the outcome of an attempt made at `now`: the HTTP status of the response, if one
+    /// was received, or a description of what went wrong. A successful status completes the
+    /// delivery. Otherwise, it's retried after an exponentially increasing delay, or abandoned once
+    /// `MAX_ATTEMPTS` have been made.
+    pub fn record_attempt(&mut self, conn: &GenericConnection, now: DateTime<UTC>, response_status: Option<i32>, error: Option<String>) -> FictResult<()> {
+        self.attempted(now, response_status, error);
+
+        try!(conn.execute("
+            UPDATE webhook_deliveries
+            SET
+                state_code = $2, attempt_count = $3, next_attempt_time = $4, last_attempt_time = $5,
+                response_status = $6, last_error = $7
+            WHERE id = $1
+        ", &[
+            &self.id, &self.state.encode(), &self.attempt_count, &self.next_attempt_time,
+            &self.last_attempt_time, &self.response_status, &self.last_error
+        ]));
+
+        Ok(())
+    }
+
+    /// Update the state of this delivery after an attempt, as `record_attempt` describes, without
+    /// saving it.
+    fn attempted(&mut self, now: DateTime<UTC>, response_status: Option<i32>, error: Option<String>) {
+        let succeeded = response_status.map(|s| s >= 200 && s < 300).unwrap_or(false);
+
+        self.attempt_count += 1;
+        self.last_attempt_time = Some(now);
+        self.response_status = response_status;
+        self.last_error = error;
+
+        if succeeded {
+            self.state = DeliveryState::Delivered;
+        } else if self.attempt_count >= MAX_ATTEMPTS {
+            self.state = DeliveryState::Failed;
+        } else {
+            self.next_attempt_time = now + Duration::seconds(RETRY_BASE_S << (self.attempt_count - 1));
+        }
+    }
+
+    /// Construct a `WebhookDelivery` from a row beginning with each of the `DELIVERY_COLUMNS`.
+    fn from_row(row: &Row) -> WebhookDelivery {
+        WebhookDelivery{
+            id: row.get(0),
+            webhook_id: row.get(1),
+            event_id: row.get(2),
+            state: DeliveryState::decode(row.get(3)),
+            attempt_count: row.get(4),
+            next_attempt_time: row.get(5),
+            last_attempt_time: row.get(6),
+            response_status: row.get(7),
+            last_error: row.get(8),
+            creation_time: row.get(9)
+        }
+    }
+
+}
+
+#[cfg(test)]
+impl WebhookDelivery {
+
+    /// Construct an unsaved delivery of the event with ID `event_id` that hasn't been attempted
+    /// yet, for tests that don't need a database.
+    pub fn sample(id: i64, event_id: i64) -> WebhookDelivery {
+        use chrono::TimeZone;
+
+        let created = UTC.ymd(2016, 3, 1).and_hms(12, 0, 0);
+
+        WebhookDelivery {
+            id: id,
+            webhook_id: 1,
+            event_id: event_id,
+            state: DeliveryState::Pending,
+            attempt_count: 0,
+            next_attempt_time: created,
+            last_attempt_time: None,
+            response_status: None,
+            last_error: None,
+            creation_time: created
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::duration::Duration;
+
+    use super::{WebhookDelivery, DeliveryState, MAX_ATTEMPTS};
+
+    #[test]
+    fn success_completes_the_delivery() {
+        let mut delivery = WebhookDelivery::sample(1, 1);
+        let now = delivery.creation_time;
+
+        delivery.attempted(now, Some(204), None);
+
+        assert_eq!(delivery.state, DeliveryState::Delivered);
+        assert_eq!(delivery.attempt_count, 1);
+        assert_eq!(delivery.last_attempt_time, Some(now));
+        assert_eq!(delivery.response_status, Some(204));
+    }
+
+    #[test]
+    fn failures_back_off_exponentially() {
+        let mut delivery = WebhookDelivery::sample(1, 1);
+        let now = delivery.creation_time;
+
+        delivery.attempted(now, Some(500), Some("Endpoint responded with 500".to_owned()));
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.next_attempt_time, now + Duration::seconds(30));
+        assert_eq!(delivery.last_error, Some("Endpoint responded with 500".to_owned()));
+
+        delivery.attempted(now, None, Some("Unable to reach the webhook URL".to_owned()));
+        assert_eq!(delivery.next_attempt_time, now + Duration::seconds(60));
+        assert_eq!(delivery.response_status, None);
+
+        delivery.attempted(now, Some(302), None);
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.next_attempt_time, now + Duration::seconds(120));
+    }
+
+    #[test]
+    fn deliveries_are_abandoned_after_the_last_attempt() {
+        let mut delivery = WebhookDelivery::sample(1, 1);
+        let now = delivery.creation_time;
+
+        for _ in 1..MAX_ATTEMPTS {
+            delivery.attempted(now, Some(500), None);
+        }
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.next_attempt_time, now + Duration::seconds(30 << (MAX_ATTEMPTS - 2)));
+
+        delivery.attempted(now, Some(500), None);
+        assert_eq!(delivery.state, DeliveryState::Failed);
+        assert_eq!(delivery.attempt_count, MAX_ATTEMPTS);
+    }
+}
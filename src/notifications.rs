@@ -0,0 +1,234 @@
+//! In-app notification routes.
+//!
+//! * `GET /notifications` - List the notifications in your inbox, most recent first.
+//! * `PUT /notifications/:id/read` - Mark the notification :id as read.
+//! * `PUT /notifications/read` - Mark every notification in your inbox as read.
+//! * `PUT /stories/:id/follow` - Be notified of each snippet contributed to the story :id.
+//! * `DELETE /stories/:id/follow` - Stop following the story :id.
+//!
+//! Notifications are delivered by the scheduler as events are recorded:
+//!
+//! * A sprint passes the lock to you.
+//! * Your lock expires.
+//! * Someone else contributes a snippet to a story that you follow. Contributing to a story
+//!   follows it.
+//! * You're granted access to a story, individually or through a group.
+//! * Someone redeems an invite that you created.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use plugin::Extensible;
+use rustc_serialize::json;
+use postgres::GenericConnection;
+use chrono::UTC;
+
+use model::{Database, Story, StoryEvent, EventKind, Notification, User};
+use auth::{AuthUser, RequireUser};
+use notify::granted_users;
+use error::{FictResult, IntoIronResult};
+use responses::{EventDoc, timestamp};
+use params;
+
+/// Name of the `event_cursors` entry that tracks which events have been delivered to inboxes.
+const CURSOR: &'static str = "inbox";
+
+/// Maximum number of events processed by each run of the scheduler.
+const BATCH_SIZE: i64 = 100;
+
+/// Maximum number of notifications listed by a single request.
+const PAGE_SIZE: i64 = 50;
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct NotificationDoc<'a> {
+    id: i64,
+    read: bool,
+    creation_time: String,
+    event: EventDoc<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct NotificationsResponse<'a> {
+    notifications: Vec<NotificationDoc<'a>>,
+    unread_count: i64
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct UnreadResponse {
+    unread_count: i64
+}
+
+/// `GET /notifications` to list the most recent notifications in your inbox, along with the
+/// number that you haven't read. Set the `unread` query parameter to `true` to list only those
+/// that you haven't read.
+pub fn index(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let unread_only = params::query(req, "unread").map(|v| v == "true").unwrap_or(false);
+
+    debug!("GET /notifications [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let notifications = try!(Notification::for_user(conn, &user, unread_only, PAGE_SIZE).iron());
+    let unread_count = try!(Notification::unread_count(conn, &user).iron());
+
+    let r = NotificationsResponse {
+        notifications: notifications.iter().map(|n| NotificationDoc{
+            id: n.id,
+            read: n.read_time.is_some(),
+            creation_time: timestamp(&n.creation_time),
+            event: EventDoc::new(&n.event)
+        }).collect(),
+        unread_count: unread_count
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /notifications/:id/read` to mark a notification in your inbox as read.
+pub fn mark_read(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let id = try!(params::numeric(req, "id"));
+
+    debug!("PUT /notifications/{}/read [{}]", id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    try!(Notification::mark_read(conn, &user, id, UTC::now()).iron());
+
+    respond_unread(conn, &user)
+}
+
+/// `PUT /notifications/read` to mark every notification in your inbox as read.
+pub fn mark_all_read(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("PUT /notifications/read [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    try!(Notification::mark_all_read(conn, &user, UTC::now()).iron());
+
+    respond_unread(conn, &user)
+}
+
+/// `PUT /stories/:id/follow` to have each snippet contributed to a story that you can read
+/// delivered to your inbox.
+pub fn follow(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("PUT /stories/{}/follow [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, _) = try!(Story::visible_to(conn, story_id, &user).iron());
+    try!(Notification::follow(conn, &story, &user).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `DELETE /stories/:id/follow` to stop following a story.
+pub fn unfollow(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("DELETE /stories/{}/follow [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, _) = try!(Story::visible_to(conn, story_id, &user).iron());
+    try!(Notification::unfollow(conn, &story, &user).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// Respond with the number of notifications that a user hasn't read.
+fn respond_unread(conn: &GenericConnection, user: &User) -> IronResult<Response> {
+    let r = UnreadResponse {
+        unread_count: try!(Notification::unread_count(conn, user).iron())
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// Deliver each event recorded since the last run to the inboxes of the users that it concerns.
+/// Produce the number of events processed.
+pub fn deliver_pending(conn: &GenericConnection) -> FictResult<usize> {
+    let last = try!(StoryEvent::cursor(conn, CURSOR));
+    let events = try!(StoryEvent::after(conn, &last, BATCH_SIZE));
+
+    for event in events.iter() {
+        let transaction = try!(conn.transaction());
+
+        if let Some(story) = try!(Story::with_id(&transaction, event.story_id)) {
+            let recipients = try!(recipients_for(&transaction, event, &story));
+            try!(Notification::deliver(&transaction, event, &recipients));
+        }
+
+        try!(StoryEvent::advance_cursor(&transaction, CURSOR, &event.position()));
+        try!(transaction.commit());
+    }
+
+    Ok(events.len())
+}
+
+/// Determine whose inboxes should receive an event within a story. Users are never notified of
+/// their own actions.
+fn recipients_for(conn: &GenericConnection, event: &StoryEvent, story: &Story) -> FictResult<Vec<User>> {
+    let candidates = match event.kind {
+        EventKind::LockAcquired | EventKind::LockExpired | EventKind::InviteRedeemed => {
+            match event.subject_id {
+                Some(subject_id) => vec![try!(User::with_id(conn, subject_id))],
+                None => Vec::new()
+            }
+        },
+        EventKind::SnippetContributed => {
+            let mut readers = Vec::new();
+            for follower in try!(Notification::followers(conn, story)) {
+                if try!(story.access_for(conn, &follower)).grants_read() {
+                    readers.push(follower);
+                }
+            }
+            readers
+        },
+        EventKind::AccessChanged => try!(granted_users(conn, event)),
+        EventKind::LockReleased | EventKind::StoryPublished => Vec::new()
+    };
+
+    Ok(candidates.into_iter().filter(|u| u.id != event.actor_id).collect())
+}
+
+/// Register notification routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut index_chain = Chain::new(index);
+    index_chain.link_before(RequireUser);
+    router.get("/notifications", index_chain);
+
+    let mut mark_all_chain = Chain::new(mark_all_read);
+    mark_all_chain.link_before(RequireUser);
+    router.put("/notifications/read", mark_all_chain);
+
+    let mut mark_chain = Chain::new(mark_read);
+    mark_chain.link_before(RequireUser);
+    router.put("/notifications/:id/read", mark_chain);
+
+    let mut follow_chain = Chain::new(follow);
+    follow_chain.link_before(RequireUser);
+    router.put("/stories/:id/follow", follow_chain);
+
+    let mut unfollow_chain = Chain::new(unfollow);
+    unfollow_chain.link_before(RequireUser);
+    router.delete("/stories/:id/follow", unfollow_chain);
+}
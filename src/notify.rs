@@ -0,0 +1,208 @@
+//! Email notifications of story activity, sent by the scheduler as events are recorded.
+//!
+//! * `lock_available` - A story that you tried to lock while someone else held it is available
+//!   again.
+//! * `story_published` - A story that you contributed to has been published.
+//! * `access_granted` - You've been granted access to a story, individually or through a group.
+//!
+//! Each email carries a link that disables its kind of notification without logging in.
+
+use postgres::GenericConnection;
+
+use config::env_opt;
+use model::{Story, StoryEvent, EventKind, Group, User, AccessLevel, StoryMode};
+use model::{NotificationPreferences, NotificationKind, LockWaiter, MailFailure};
+use mail::{Mail, Transport};
+use error::FictResult;
+
+/// Name of the `event_cursors` entry that tracks which events have been notified.
+const CURSOR: &'static str = "email_notifications";
+
+/// Maximum number of events processed by each run of the scheduler.
+const BATCH_SIZE: i64 = 100;
+
+/// Base URL of unsubscribe links when `FICTION_PUBLIC_URL` is unset.
+const DEFAULT_PUBLIC_URL: &'static str = "http://localhost:3000";
+
+/// Send the notifications produced by each event recorded since the last run. Produce the number
+/// of messages sent.
+///
+/// Each event is marked as notified, and its waiters released, before its messages are sent, so
+/// that nobody is sent the same message twice. A message that can't be sent is recorded as a
+/// `MailFailure` rather than retried, so that one undeliverable address doesn't hold up everyone
+/// else's notifications.
+pub fn send_pending(conn: &GenericConnection, transport: &mut Transport) -> FictResult<usize> {
+    let public_url = try!(public_url());
+
+    let last = try!(StoryEvent::cursor(conn, CURSOR));
+    let events = try!(StoryEvent::after(conn, &last, BATCH_SIZE));
+
+    let mut sent = 0;
+
+    for event in events.iter() {
+        let mut outgoing = Vec::new();
+
+        let transaction = try!(conn.transaction());
+
+        if let Some(story) = try!(Story::with_id(&transaction, event.story_id)) {
+            for (recipient, kind) in try!(notices_for(&transaction, event, &story)) {
+                let preferences = try!(NotificationPreferences::for_user(&transaction, &recipient));
+                if ! preferences.wants_immediately(&kind) {
+                    continue;
+                }
+
+                let mail = compose(&recipient, &kind, &story, &preferences, &public_url);
+                outgoing.push((recipient, kind, mail));
+            }
+        }
+
+        try!(StoryEvent::advance_cursor(&transaction, CURSOR, &event.position()));
+        try!(transaction.commit());
+
+        for (recipient, kind, mail) in outgoing.into_iter() {
+            match transport.send(&mail) {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    warn!("Unable to send a {} notification to {}: {}", kind.name(), recipient.name, e);
+                    if let Err(e) = MailFailure::record(conn, &recipient, &kind, Some(event.id), &format!("{}", e)) {
+                        error!("Unable to record a failed notification: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Determine who should be notified of an event within a story, and how.
+fn notices_for(conn: &GenericConnection, event: &StoryEvent, story: &Story) -> FictResult<Vec<(User, NotificationKind)>> {
+    let mut notices = Vec::new();
+
+    match event.kind {
+        // Contributing a snippet releases its author's lock, too.
+        EventKind::LockReleased | EventKind::LockExpired | EventKind::SnippetContributed => {
+            let waiters = try!(LockWaiter::release(conn, story.id));
+
+            // Finished stories, and those whose turns are scheduled or voted on, can't be locked,
+            // so their waiters are released without being told otherwise.
+            if story.finished || story.mode != StoryMode::Locking {
+                return Ok(notices);
+            }
+
+            for waiter in waiters {
+                if waiter.user.id == event.actor_id {
+                    continue;
+                }
+
+                if try!(story.access_for(conn, &waiter.user)).grants_write() {
+                    notices.push((waiter.user, NotificationKind::LockAvailable));
+                }
+            }
+        },
+        EventKind::StoryPublished => {
+            for contributor in try!(story.contributors(conn)) {
+                if contributor.id != event.actor_id {
+                    notices.push((contributor, NotificationKind::StoryPublished));
+                }
+            }
+        },
+        EventKind::AccessChanged => {
+            for recipient in try!(granted_users(conn, event)) {
+                if recipient.id != event.actor_id {
+                    notices.push((recipient, NotificationKind::AccessGranted));
+                }
+            }
+        },
+        EventKind::LockAcquired | EventKind::InviteRedeemed => ()
+    }
+
+    Ok(notices)
+}
+
+/// Determine who gained access through an `AccessChanged` event: its subject, for an individual
+/// grant, or each member of the group named by its detail, for a group grant. Removing access
+/// notifies no one.
+pub fn granted_users(conn: &GenericConnection, event: &StoryEvent) -> FictResult<Vec<User>> {
+    let detail = event.detail.as_ref().map(|d| &d[..]).unwrap_or("");
+    let words: Vec<&str> = detail.split_whitespace().collect();
+
+    match (event.subject_id, &words[..]) {
+        (Some(subject_id), _) => {
+            if AccessLevel::from_name(detail).map(|l| l.grants_read()).unwrap_or(false) {
+                Ok(vec![try!(User::with_id(conn, subject_id))])
+            } else {
+                Ok(Vec::new())
+            }
+        },
+        (None, words) if words.len() == 3 && words[0] == "group" => {
+            let granted = AccessLevel::from_name(words[2]).map(|l| l.grants_read()).unwrap_or(false);
+            let group = match words[1].parse::<i64>() {
+                Ok(group_id) => try!(Group::with_id(conn, group_id)),
+                Err(_) => None
+            };
+
+            match group {
+                Some(ref group) if granted => group.members(conn),
+                _ => Ok(Vec::new())
+            }
+        },
+        _ => Ok(Vec::new())
+    }
+}
+
+/// Write the message that notifies a user of an event.
+fn compose(recipient: &User, kind: &NotificationKind, story: &Story, preferences: &NotificationPreferences, public_url: &str) -> Mail {
+    let title = story_title(story);
+
+    let (subject, summary) = match *kind {
+        NotificationKind::LockAvailable => (
+            format!("Your turn: {} is available", title),
+            format!("{} is no longer locked, so you may write the next snippet.", title)
+        ),
+        NotificationKind::StoryPublished => (
+            format!("{} has been published", title),
+            format!("{}, which you contributed to, has been published.", title)
+        ),
+        NotificationKind::AccessGranted => (
+            format!("You've been invited to {}", title),
+            format!("You've been granted access to {}.", title)
+        ),
+        NotificationKind::Digest => unreachable!("Digests are composed by digest::compose")
+    };
+
+    let unsubscribe_url = unsubscribe_url(public_url, preferences, kind);
+
+    let body = format!(
+        "Hi {},\n\n{}\n\nTo stop receiving these emails, visit:\n{}\n",
+        recipient.name, summary, unsubscribe_url
+    );
+
+    Mail {
+        to: recipient.email.clone(),
+        subject: subject,
+        body: body,
+        unsubscribe_url: Some(unsubscribe_url)
+    }
+}
+
+/// Base URL of links within messages.
+pub fn public_url() -> FictResult<String> {
+    Ok(try!(env_opt::<String>("FICTION_PUBLIC_URL")).unwrap_or(DEFAULT_PUBLIC_URL.to_owned()))
+}
+
+/// Link that disables a kind of notification for the owner of a set of preferences.
+pub fn unsubscribe_url(public_url: &str, preferences: &NotificationPreferences, kind: &NotificationKind) -> String {
+    format!(
+        "{}/unsubscribe/{}?kind={}",
+        public_url.trim_right_matches('/'), preferences.unsubscribe_token, kind.name()
+    )
+}
+
+/// Name a story within a message, whether or not it has a title.
+pub fn story_title(story: &Story) -> String {
+    match story.title {
+        Some(ref title) => format!("\"{}\"", title),
+        None => format!("Story #{}", story.id)
+    }
+}
@@ -1,7 +1,7 @@
 //! OAuth2 authentication providers.
 
 use std::io::Read;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
 use std::error::Error;
 
@@ -12,7 +12,6 @@ use iron::Handler;
 use iron::modifiers::Redirect;
 use iron::typemap::Key;
 use router::Router;
-use persistent::Write;
 use rand::{OsRng, Rng};
 use hyper::Client;
 use hyper::Url as HyperUrl;
@@ -22,7 +21,8 @@ use rustc_serialize::json;
 use postgres::Connection;
 
 use error::{FictResult, fict_err, as_fict_err};
-use model::{Database, User, Session};
+use model::{Database, User, Session, Invite};
+use params;
 
 mod connection;
 mod github;
@@ -49,7 +49,7 @@ struct Options {
 /// Mutable state to be shared among the request handlers installed by a specific `Provider`.
 pub struct Shared {
     rng: OsRng,
-    valid_states: HashSet<String>,
+    valid_states: HashMap<String, Option<String>>,
 }
 
 impl Shared {
@@ -58,19 +58,21 @@ impl Shared {
     fn new() -> Shared {
         Shared{
             rng: OsRng::new().unwrap(),
-            valid_states: HashSet::with_capacity(INIT_STATE_CAPACITY),
+            valid_states: HashMap::with_capacity(INIT_STATE_CAPACITY),
         }
     }
 
-    /// Generate an unguessable random string for use as a `state` parameter. Remember it as valid.
-    fn generate_state(&mut self) -> String {
+    /// Generate an unguessable random string for use as a `state` parameter. Remember it as valid,
+    /// along with the invite token to redeem once the user has authenticated, if any.
+    fn generate_state(&mut self, invite: Option<String>) -> String {
         let state: String = self.rng.gen_ascii_chars().take(STATE_LEN).collect();
-        self.valid_states.insert(state.clone());
+        self.valid_states.insert(state.clone(), invite);
         state
     }
 
-    /// Verify that a given state is valid. Discard it from the provider's store if it is.
-    fn validate_state(&mut self, state: &str) -> bool {
+    /// Verify that a given state is valid. Discard it from the provider's store and produce its
+    /// invite token if it is.
+    fn validate_state(&mut self, state: &str) -> Option<Option<String>> {
         self.valid_states.remove(state)
     }
 
@@ -145,13 +147,14 @@ pub trait Provider : Key + Send + Sync + Clone {
     }
 
     /// *Phase 1:* Redirect to the OAuth provider's authorization page with a randomly generated
-    /// `state` parameter.
+    /// `state` parameter. An `invite` query parameter is remembered alongside the state.
     fn request_handler(&self, req: &mut Request) -> IronResult<Response> {
         let o = self.options();
+        let invite = params::query(req, "invite");
 
         let mutex = self.shared_mutex(req);
         let mut shared = mutex.lock().unwrap();
-        let state = shared.generate_state();
+        let state = shared.generate_state(invite);
 
         let mut u = o.request_uri.clone();
         u.query = Some(format!(
@@ -166,22 +169,29 @@ pub trait Provider : Key + Send + Sync + Clone {
 
     /// *Phase 2:* Accept the redirect back from the OAuth provider. Validate the `state` and
     /// exchange the `code` for an access token. Use the access token with the provider's API
-    /// to locate the authenticated user's username and email address.
+    /// to locate the authenticated user's username and email address. Redeem the invite that
+    /// accompanied the authorization request, if any.
     fn callback_handler(&self, req: &mut Request) -> IronResult<Response> {
-        let mutex = req.get::<Write<Database>>().unwrap_or_else(|_| {
-            panic!("No database connection available");
-        });
-        let pool = mutex.lock().unwrap();
-        let conn = pool.get().unwrap();
+        let conn = try!(Database::connection(req));
 
         let mutex = self.shared_mutex(req);
         let mut shared = mutex.lock().unwrap();
 
         let result = self.extract_callback_params(req)
-            .and_then(|(code, state)| self.validate_state(&mut *shared, &state).map(|_| { code }))
-            .and_then(|code| self.generate_token(code))
-            .and_then(|token| self.find_user(&*conn, token))
-            .and_then(|user| Session::assign(&*conn, user, &mut shared.rng));
+            .and_then(|(code, state)| self.validate_state(&mut *shared, &state).map(|invite| (code, invite)))
+            .and_then(|(code, invite)| self.generate_token(code).map(|token| (token, invite)))
+            .and_then(|(token, invite)| self.find_user(&*conn, token).map(|user| (user, invite)))
+            .and_then(|(user, invite)| {
+                if let Some(invite_token) = invite {
+                    // A problem with the invite shouldn't prevent the user from logging in.
+                    match Invite::redeem(&*conn, &invite_token, &user) {
+                        Ok(story) => debug!("Redeemed an invite to story {} for {}.", story.id, user),
+                        Err(e) => warn!("Unable to redeem an invite for {}: {}", user, e),
+                    }
+                }
+
+                Session::assign(&*conn, user, &mut shared.rng)
+            });
 
         match result {
             Ok(session) => {
@@ -232,13 +242,10 @@ pub trait Provider : Key + Send + Sync + Clone {
     }
 
     /// Ensure that the `state` returned by the OAuth provider is one that was generated by this
-    /// service.
-    fn validate_state(&self, shared: &mut Shared, state: &str) -> FictResult<()> {
-        if shared.validate_state(state) {
-            Ok(())
-        } else {
-            Err(fict_err("Unfamiliar state encountered. Danger: this could be an XSS attack!"))
-        }
+    /// service. Produce the invite token that was remembered along with it, if any.
+    fn validate_state(&self, shared: &mut Shared, state: &str) -> FictResult<Option<String>> {
+        shared.validate_state(state)
+            .ok_or(fict_err("Unfamiliar state encountered. Danger: this could be an XSS attack!"))
     }
 
     /// Exchange a `code` obtained through an OAuth handshake for an access token.
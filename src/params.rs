@@ -0,0 +1,54 @@
+//! Extraction of route parameters and request bodies.
+
+use std::any::Any;
+
+use iron::{Request, IronResult, IronError};
+use iron::status;
+use router::Router;
+use bodyparser;
+use plugin::Pluggable;
+use rustc_serialize::Decodable;
+
+use error::fict_err;
+
+/// Extract a numeric route parameter, such as the `:id` in `/stories/:id`. Respond with a 400 if
+/// the parameter is not numeric.
+///
+/// Panics if the parameter is not part of the matched route.
+pub fn numeric(req: &Request, name: &str) -> IronResult<i64> {
+    let params = req.extensions.get::<Router>()
+        .expect("No route parameters");
+
+    params[name].parse::<i64>().map_err(|_| {
+        let message = format!("{} must be numeric", name);
+        IronError::new(fict_err(message.clone()), (status::BadRequest, message))
+    })
+}
+
+/// Parse the JSON request body as a `T`. Respond with a 400 if the body is missing or cannot be
+/// decoded.
+pub fn body<T: Decodable + Clone + Any>(req: &mut Request) -> IronResult<T> {
+    match req.get::<bodyparser::Struct<T>>() {
+        Ok(Some(b)) => Ok(b),
+        Ok(None) => {
+            let message = "Expected a request body";
+            Err(IronError::new(fict_err(message), (status::BadRequest, message)))
+        },
+        Err(err) => {
+            warn!("Unable to parse request body: {:?}", err);
+
+            let message = "Unable to parse request body";
+            Err(IronError::new(fict_err(message), (status::BadRequest, message)))
+        }
+    }
+}
+
+/// Extract an optional query string parameter, such as the `format` in `?format=text`. If the
+/// parameter is given more than once, the first value is produced.
+pub fn query(req: &Request, name: &str) -> Option<String> {
+    let u = req.url.clone().into_generic_url();
+
+    u.query_pairs()
+        .and_then(|pairs| pairs.into_iter().find(|&(ref key, _)| key == name))
+        .map(|(_, value)| value)
+}
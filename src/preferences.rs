@@ -0,0 +1,225 @@
+//! Notification preference routes.
+//!
+//! * `GET /preferences/notifications` - Show which email notifications you receive.
+//! * `PUT /preferences/notifications` - Choose which email notifications you receive.
+//! * `GET /unsubscribe/:token` - Confirm that you'd like to stop receiving email notifications.
+//! * `POST /unsubscribe/:token` - Stop receiving email notifications, without logging in.
+//!
+//! Enabling `digest` replaces the other kinds of email notification with a daily summary.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use hyper::header::ContentType;
+use hyper::mime::Mime;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+
+use model::{Database, NotificationPreferences, NotificationKind};
+use auth::{AuthUser, RequireUser};
+use error::{IntoIronResult, FieldError};
+use error::FictError::{NotFound, Invalid};
+use params;
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct UpdateBody {
+    notifications: PreferencesBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct PreferencesBody {
+    lock_available: Option<bool>,
+    story_published: Option<bool>,
+    access_granted: Option<bool>,
+    digest: Option<bool>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct PreferencesDoc {
+    lock_available: bool,
+    story_published: bool,
+    access_granted: bool,
+    digest: bool
+}
+
+impl PreferencesDoc {
+
+    fn new(preferences: &NotificationPreferences) -> PreferencesDoc {
+        PreferencesDoc{
+            lock_available: preferences.lock_available,
+            story_published: preferences.story_published,
+            access_granted: preferences.access_granted,
+            digest: preferences.digest
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct PreferencesResponse {
+    notifications: PreferencesDoc
+}
+
+/// `GET /preferences/notifications` to show which kinds of email notification you receive.
+pub fn show(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("GET /preferences/notifications [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let preferences = try!(NotificationPreferences::for_user(conn, &user).iron());
+
+    respond(&preferences)
+}
+
+/// `PUT /preferences/notifications` to enable or disable kinds of email notification. Kinds that
+/// are omitted are left unchanged.
+pub fn update(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let body = try!(params::body::<UpdateBody>(req)).notifications;
+
+    debug!("PUT /preferences/notifications [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let mut preferences = try!(NotificationPreferences::for_user(conn, &user).iron());
+
+    let choices = vec![
+        (NotificationKind::LockAvailable, body.lock_available),
+        (NotificationKind::StoryPublished, body.story_published),
+        (NotificationKind::AccessGranted, body.access_granted),
+        (NotificationKind::Digest, body.digest)
+    ];
+
+    for (kind, choice) in choices.into_iter() {
+        if let Some(enabled) = choice {
+            preferences.set(&kind, enabled);
+        }
+    }
+
+    try!(preferences.save(conn).iron());
+
+    respond(&preferences)
+}
+
+/// `GET /unsubscribe/:token` to confirm that you'd like to stop receiving email notifications of
+/// the kind named by the `kind` query parameter, or of every kind if it's omitted. Linked from each
+/// notification, so that recipients may opt out without logging in. Nothing changes until the
+/// page's form is submitted, so that mail scanners that follow links don't unsubscribe anyone.
+pub fn confirm_unsubscribe(req: &mut Request) -> IronResult<Response> {
+    let (token, kinds) = try!(unsubscribe_params(req));
+
+    debug!("GET /unsubscribe/.. [{}]", kinds_label(&kinds));
+
+    let ref conn = *try!(Database::connection(req));
+
+    try!(try!(NotificationPreferences::with_token(conn, &token).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    let page = format!("<!DOCTYPE html>
+<html>
+<head><title>Unsubscribe</title></head>
+<body>
+<form method=\"post\" action=\"\">
+<p>Stop receiving {} email notifications?</p>
+<button type=\"submit\">Unsubscribe</button>
+</form>
+</body>
+</html>
+", kinds_label(&kinds));
+
+    let mime: Mime = "text/html; charset=utf-8".parse().expect("Invalid page MIME type");
+    let mut res = Response::with((status::Ok, page));
+    res.headers.set(ContentType(mime));
+
+    Ok(res)
+}
+
+/// `POST /unsubscribe/:token` to stop receiving email notifications of the kind named by the
+/// `kind` query parameter, or of every kind if it's omitted. Submitted by the confirmation page,
+/// or directly by mail clients that support one-click unsubscription (RFC 8058). Unsubscribing
+/// from `digest` also stops the notifications that it summarizes.
+pub fn unsubscribe(req: &mut Request) -> IronResult<Response> {
+    let (token, kinds) = try!(unsubscribe_params(req));
+
+    debug!("POST /unsubscribe/.. [{}]", kinds_label(&kinds));
+
+    let ref conn = *try!(Database::connection(req));
+
+    let mut preferences = try!(try!(NotificationPreferences::with_token(conn, &token).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    for kind in kinds.iter() {
+        preferences.unsubscribe(kind);
+    }
+
+    try!(preferences.save(conn).iron());
+
+    Ok(Response::with((status::Ok, "You've been unsubscribed.")))
+}
+
+/// Read the unsubscribe token and the kinds of notification named by an unsubscribe link.
+fn unsubscribe_params(req: &mut Request) -> IronResult<(String, Vec<NotificationKind>)> {
+    let token = req.extensions().get::<Router>()
+        .expect("No route parameters")["token"].to_owned();
+
+    let kinds = match params::query(req, "kind") {
+        Some(ref name) => match NotificationKind::from_name(name) {
+            Some(kind) => vec![kind],
+            None => {
+                let errors = vec![FieldError::new(
+                    "kind", "unknown",
+                    "Kind must be one of: lock_available, story_published, access_granted, digest."
+                )];
+                return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+            }
+        },
+        None => vec![
+            NotificationKind::LockAvailable,
+            NotificationKind::StoryPublished,
+            NotificationKind::AccessGranted,
+            NotificationKind::Digest
+        ]
+    };
+
+    Ok((token, kinds))
+}
+
+/// Describe the kinds of notification named by an unsubscribe link.
+fn kinds_label(kinds: &[NotificationKind]) -> &'static str {
+    if kinds.len() == 1 { kinds[0].name() } else { "all" }
+}
+
+/// Respond with the current state of a user's preferences.
+fn respond(preferences: &NotificationPreferences) -> IronResult<Response> {
+    let r = PreferencesResponse {
+        notifications: PreferencesDoc::new(preferences)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register notification preference routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut show_chain = Chain::new(show);
+    show_chain.link_before(RequireUser);
+    router.get("/preferences/notifications", show_chain);
+
+    let mut update_chain = Chain::new(update);
+    update_chain.link_before(RequireUser);
+    update_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/preferences/notifications", update_chain);
+
+    router.get("/unsubscribe/:token", confirm_unsubscribe);
+    router.post("/unsubscribe/:token", unsubscribe);
+}
@@ -0,0 +1,142 @@
+//! JSON documents shared among several endpoints.
+
+use chrono::{DateTime, UTC};
+
+use model::{Snippet, Story, ContentLimits, PromptSettings};
+use model::{TurnPolicySettings, VisibilitySettings, CompletionSettings, StoryModeSettings};
+use model::{RevealSettings, StoryEvent};
+
+/// Consistent DateTime format to be used throughout the API: `Fri, 10 May 2015 17:58:28 +0000`
+pub const TIMESTAMP_FORMAT: &'static str = "%a, %d %b %Y %T %z";
+
+/// Render a timestamp in the API's `TIMESTAMP_FORMAT`.
+pub fn timestamp(t: &DateTime<UTC>) -> String {
+    format!("{}", t.format(TIMESTAMP_FORMAT))
+}
+
+/// Public representation of a contributed `Snippet`.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct SnippetDoc<'a> {
+    pub id: i64,
+    pub story_id: i64,
+    pub position: i32,
+    pub creation_time: String,
+    pub edit_time: Option<String>,
+    pub content: &'a str
+}
+
+impl<'a> SnippetDoc<'a> {
+
+    pub fn new(snippet: &'a Snippet) -> SnippetDoc<'a> {
+        SnippetDoc{
+            id: snippet.id,
+            story_id: snippet.story_id,
+            position: snippet.position,
+            creation_time: timestamp(&snippet.creation_time),
+            edit_time: snippet.edit_time.as_ref().map(timestamp),
+            content: &snippet.content
+        }
+    }
+
+}
+
+/// Wrapper used to respond with a single `SnippetDoc`.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct SnippetResponse<'a> {
+    pub snippet: SnippetDoc<'a>
+}
+
+/// Public representation of a `Story` and its settings.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct StoryDoc<'a> {
+    pub id: i64,
+    pub title: Option<&'a str>,
+    pub published: bool,
+    pub world_readable: bool,
+    pub lock_duration_s: i64,
+    pub contribution_count: i32,
+    pub creation_time: String,
+    pub update_time: String,
+    pub publish_time: Option<String>,
+    pub limits: &'a ContentLimits,
+    pub turn_policy: TurnPolicySettings,
+    pub visibility: VisibilitySettings,
+    pub completion: CompletionSettings,
+    pub finished: bool,
+    pub finish_time: Option<String>,
+    pub parent_story_id: Option<i64>,
+    pub fork_position: Option<i32>,
+    pub mode: StoryModeSettings,
+    pub open_to_join: bool,
+    pub reveal: RevealSettings
+}
+
+impl<'a> StoryDoc<'a> {
+
+    pub fn new(story: &'a Story) -> StoryDoc<'a> {
+        StoryDoc{
+            id: story.id,
+            title: story.title.as_ref().map(|t| &t[..]),
+            published: story.published,
+            world_readable: story.world_readable,
+            lock_duration_s: story.lock_duration_s,
+            contribution_count: story.contribution_count,
+            creation_time: timestamp(&story.creation_time),
+            update_time: timestamp(&story.update_time),
+            publish_time: story.publish_time.as_ref().map(timestamp),
+            limits: &story.limits,
+            turn_policy: story.turn_policy.settings(),
+            visibility: story.visibility.settings(),
+            completion: story.completion.settings(),
+            finished: story.finished,
+            finish_time: story.finish_time.as_ref().map(timestamp),
+            parent_story_id: story.parent_story_id,
+            fork_position: story.fork_position,
+            mode: story.mode.settings(),
+            open_to_join: story.open_to_join,
+            reveal: story.reveal.settings()
+        }
+    }
+
+}
+
+/// Wrapper used to respond with a single `StoryDoc`.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct StoryResponse<'a> {
+    pub story: StoryDoc<'a>
+}
+
+/// Wrapper used to respond with a `StoryDoc` along with its prompt, if it has one.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct StoryWithPromptResponse<'a> {
+    pub story: StoryDoc<'a>,
+    pub prompt: Option<PromptSettings>
+}
+
+/// Public representation of a `StoryEvent`.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct EventDoc<'a> {
+    pub id: i64,
+    pub story_id: i64,
+    pub kind: &'a str,
+    pub actor_id: Option<i64>,
+    pub subject_id: Option<i64>,
+    pub detail: Option<&'a str>,
+    pub creation_time: String
+}
+
+impl<'a> EventDoc<'a> {
+
+    pub fn new(event: &'a StoryEvent) -> EventDoc<'a> {
+        EventDoc{
+            id: event.id,
+            story_id: event.story_id,
+            kind: event.kind.name(),
+            actor_id: event.actor_id,
+            subject_id: event.subject_id,
+            detail: event.detail.as_ref().map(|d| &d[..]),
+            creation_time: timestamp(&event.creation_time)
+        }
+    }
+
+}
@@ -0,0 +1,193 @@
+//! Voting round routes, for stories that choose each snippet by vote.
+//!
+//! * `GET /stories/:id/round` - Show the open round of the story :id and its candidates.
+//! * `POST /stories/:id/round/candidates` - Submit a candidate for the next snippet of the story :id.
+//! * `POST /stories/:id/round/votes` - Vote for a candidate in the open round of the story :id.
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+
+use model::{Database, Story, VotingRound, Candidate};
+use auth::{AuthUser, RequireUser};
+use error::IntoIronResult;
+use responses::timestamp;
+use params;
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CandidateBody {
+    candidate: CandidateContentBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CandidateContentBody {
+    content: String
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct VoteBody {
+    vote: VoteChoiceBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct VoteChoiceBody {
+    candidate_id: i64
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RoundDoc {
+    id: i64,
+    story_id: i64,
+    position: i32,
+    open_time: String,
+    close_time: String
+}
+
+impl RoundDoc {
+
+    fn new(round: &VotingRound) -> RoundDoc {
+        RoundDoc{
+            id: round.id,
+            story_id: round.story_id,
+            position: round.position,
+            open_time: timestamp(&round.open_time),
+            close_time: timestamp(&round.close_time)
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct CandidateDoc<'a> {
+    id: i64,
+    content: Option<&'a str>,
+    creation_time: String,
+    votes: i64
+}
+
+impl<'a> CandidateDoc<'a> {
+
+    /// Describe a candidate, withholding its content unless `revealed`.
+    fn new(candidate: &'a Candidate, revealed: bool) -> CandidateDoc<'a> {
+        CandidateDoc{
+            id: candidate.id,
+            content: if revealed { Some(&candidate.content) } else { None },
+            creation_time: timestamp(&candidate.creation_time),
+            votes: candidate.votes
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RoundResponse<'a> {
+    round: Option<RoundDoc>,
+    candidates: Vec<CandidateDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct CandidateResponse<'a> {
+    round: RoundDoc,
+    candidate: CandidateDoc<'a>
+}
+
+/// `GET /stories/:id/round` to see the candidates submitted so far during the open round of a
+/// story you can read. If no round is open, `round` is null. Spectators who may not contribute
+/// only see each candidate's content once the story's reveal policy allows it.
+pub fn show(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/round [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let open = try!(VotingRound::open_for(conn, story_id, &user).iron());
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    let revealed = story.reveal.reveals(&story, &access);
+
+    let r = match open {
+        Some((ref round, ref candidates)) => RoundResponse {
+            round: Some(RoundDoc::new(round)),
+            candidates: candidates.iter().map(|c| CandidateDoc::new(c, revealed)).collect()
+        },
+        None => RoundResponse {
+            round: None,
+            candidates: Vec::new()
+        }
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `POST /stories/:id/round/candidates` to submit your candidate for the next snippet of a story
+/// you may contribute to. Submitting the first candidate opens a new round.
+pub fn submit(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<CandidateBody>(req)).candidate;
+
+    debug!("POST /stories/{}/round/candidates [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (round, candidate) = try!(VotingRound::submit(conn, story_id, &user, body.content).iron());
+
+    debug!(".. Submitted candidate {} in round {}.", candidate.id, round.id);
+
+    let r = CandidateResponse {
+        round: RoundDoc::new(&round),
+        candidate: CandidateDoc::new(&candidate, true)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `POST /stories/:id/round/votes` to vote for someone else's candidate in the open round of a
+/// story you can read. You may vote once per round.
+pub fn vote(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<VoteBody>(req)).vote;
+
+    debug!("POST /stories/{}/round/votes [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    try!(VotingRound::vote(conn, story_id, &user, body.candidate_id).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register voting round routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut show_chain = Chain::new(show);
+    show_chain.link_before(RequireUser);
+    router.get("/stories/:id/round", show_chain);
+
+    let mut submit_chain = Chain::new(submit);
+    submit_chain.link_before(RequireUser);
+    submit_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories/:id/round/candidates", submit_chain);
+
+    let mut vote_chain = Chain::new(vote);
+    vote_chain.link_before(RequireUser);
+    vote_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories/:id/round/votes", vote_chain);
+}
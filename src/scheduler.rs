@@ -0,0 +1,94 @@
+//! Background jobs that run periodically, independently of any request.
+
+use std::thread;
+use std::time::Duration;
+
+use chrono::UTC;
+
+use config::env_opt;
+use model::{PostgresPool, Story, VotingRound};
+use error::FictResult;
+use mail::{self, Transport};
+use notify;
+use notifications;
+use digest;
+
+/// Seconds to wait between runs when `FICTION_SCHEDULER_INTERVAL_S` is unset.
+const DEFAULT_INTERVAL_S: u64 = 15;
+
+/// Launch a thread that runs each scheduled job at a regular interval, using connections from
+/// `pool`. A job that fails is logged and retried on the next run.
+pub fn start(pool: PostgresPool) -> FictResult<()> {
+    let interval_s = try!(env_opt::<u64>("FICTION_SCHEDULER_INTERVAL_S"))
+        .unwrap_or(DEFAULT_INTERVAL_S);
+    let mut transport = try!(mail::transport());
+
+    try!(thread::Builder::new().name("scheduler".to_owned()).spawn(move || {
+        info!("Running scheduled jobs every {} seconds.", interval_s);
+
+        loop {
+            if let Err(e) = run(&pool, &mut *transport) {
+                error!("Unable to run scheduled jobs: {}", e);
+            }
+
+            thread::sleep(Duration::from_secs(interval_s));
+        }
+    }));
+
+    Ok(())
+}
+
+/// Run each scheduled job once. A job that fails is logged without holding up the jobs after it.
+fn run(pool: &PostgresPool, transport: &mut Transport) -> FictResult<()> {
+    let conn = try!(pool.get());
+    let now = UTC::now();
+
+    let closed = report("close voting rounds", VotingRound::close_due(&*conn, now));
+    if closed > 0 {
+        debug!("Closed {} voting rounds.", closed);
+    }
+
+    let finished = report("finish overdue stories", Story::finish_overdue(&*conn, now));
+    if finished > 0 {
+        debug!("Finished {} stories whose deadline has passed.", finished);
+    }
+
+    let rotated = report("rotate sprint turns", Story::rotate_sprint_turns(&*conn, now));
+    if rotated > 0 {
+        debug!("Rotated the turn in {} sprints.", rotated);
+    }
+
+    let expired = report("expire locks", Story::expire_locks(&*conn, now));
+    if expired > 0 {
+        debug!("Released {} expired locks.", expired);
+    }
+
+    let inboxed = report("deliver notifications to inboxes", notifications::deliver_pending(&*conn));
+    if inboxed > 0 {
+        debug!("Delivered {} events to notification inboxes.", inboxed);
+    }
+
+    let notified = report("send email notifications", notify::send_pending(&*conn, transport));
+    if notified > 0 {
+        debug!("Sent {} email notifications.", notified);
+    }
+
+    let digests = report("send digests", digest::send_due(&*conn, transport, now));
+    if digests > 0 {
+        debug!("Sent {} daily digests.", digests);
+    }
+
+    Ok(())
+}
+
+/// Log the failure of the scheduled job `job`, if it failed. Produce the number of items that it
+/// processed, or 0 if it failed.
+fn report(job: &str, result: FictResult<usize>) -> usize {
+    match result {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Unable to {}: {}", job, e);
+            0
+        }
+    }
+}
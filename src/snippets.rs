@@ -1,16 +1,25 @@
 //! Snippet creation endpoints.
+//!
+//! * `POST /snippets` - Begin a new story or continue a story you have locked.
+//! * `PUT /snippets/:id` - Edit the most recent snippet you contributed to a story.
+//! * `DELETE /snippets/:id` - Retract the most recent snippet from a story.
+//! * `GET /snippets/:id/revisions` - List prior versions of a snippet in a story you own.
 
 use iron::{Request, Response, IronResult, Chain};
 use iron::status;
 use router::Router;
-use persistent::{Read, Write};
+use persistent::Read;
 use bodyparser;
 use plugin::Pluggable;
 use plugin::Extensible;
+use rustc_serialize::json;
 
-use model::{Database, Snippet, Story, ContributionAttempt};
+use model::{Database, Snippet, SnippetRevision, Story};
 use auth::{AuthUser, RequireUser};
 use error::IntoIronResult;
+use error::FictError::{NotFound, Forbidden};
+use responses::{SnippetDoc, SnippetResponse, timestamp};
+use params;
 
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 struct CreationBody {
@@ -23,6 +32,29 @@ struct SnippetBody {
     story_id: Option<i64>
 }
 
+#[derive(Debug, Clone, RustcDecodable)]
+struct EditBody {
+    snippet: EditSnippetBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct EditSnippetBody {
+    content: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RevisionDoc<'a> {
+    content: &'a str,
+    creation_time: String,
+    replacement_time: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RevisionsResponse<'a> {
+    snippet: SnippetDoc<'a>,
+    revisions: Vec<RevisionDoc<'a>>
+}
+
 pub fn post(req: &mut Request) -> IronResult<Response> {
     let u = req.extensions().get::<AuthUser>().cloned()
         .expect("No authenticated user");
@@ -40,48 +72,118 @@ pub fn post(req: &mut Request) -> IronResult<Response> {
 
     debug!("POST /snippets [{}]", u.name);
 
-    let mutex = req.extensions().get::<Write<Database>>()
-        .cloned()
-        .expect("No database connection available");
-    let pool = mutex.lock().unwrap();
-    let ref conn = *pool.get().unwrap();
+    let ref conn = *try!(Database::connection(req));
 
     match body.snippet.story_id {
         Some(id) => {
             debug!(".. Into existing story id {}", id);
 
-            // Ensure that the current user holds an active lock on an existing Story.
-            let mut story = try!(Story::locked_for_write(conn, id, &u, false).iron());
-
-            try!(Snippet::contribute(conn, &story, &u, body.snippet.content).iron());
-
-            story.contribution_count += 1;
+            // Ensure that the current user holds an active lock on an existing Story and that the
+            // content obeys its constraints, then contribute the Snippet, release the lock, and
+            // record what happened.
+            let (snippet, _) = try!(Snippet::contribute(conn, id, &u, body.snippet.content).iron());
 
-            try!(story.save(conn).iron());
-            try!(story.unlock(conn).iron());
-
-            Ok(Response::with(status::Created))
+            respond(status::Created, &snippet)
         },
         None => {
             // Begin a new Story belonging to the authenticated User and containing the newly
             // created Snippet.
             debug!(".. Creating a new Story");
 
-            let (_, mut story) = try!(Snippet::begin(conn, &u, body.snippet.content).iron());
+            let (snippet, _) = try!(Snippet::begin(conn, &u, body.snippet.content).iron());
 
-            try!(ContributionAttempt::record(conn, &story, &u).iron());
+            respond(status::Created, &snippet)
+        }
+    }
+}
+
+/// `PUT /snippets/:id` to correct the most recent snippet you've contributed to a story, as long
+/// as nobody else has locked the story to continue it yet.
+pub fn edit(req: &mut Request) -> IronResult<Response> {
+    let u = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let snippet_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<EditBody>(req));
 
-            story.contribution_count += 1;
-            try!(story.save(conn).iron());
+    debug!("PUT /snippets/{} [{}]", snippet_id, u.name);
 
-            Ok(Response::with(status::Created))
-        }
+    let ref conn = *try!(Database::connection(req));
+
+    let snippet = try!(Snippet::edit(conn, snippet_id, &u, body.snippet.content).iron());
+
+    respond(status::Ok, &snippet)
+}
+
+/// `DELETE /snippets/:id` to retract the most recent snippet from a story. Authors may retract
+/// their own snippet until someone else locks the story to continue it; story owners may retract
+/// the most recent snippet at any time.
+pub fn retract(req: &mut Request) -> IronResult<Response> {
+    let u = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let snippet_id = try!(params::numeric(req, "id"));
+
+    debug!("DELETE /snippets/{} [{}]", snippet_id, u.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let story = try!(Snippet::retract(conn, snippet_id, &u).iron());
+
+    debug!(".. Retracted from story {}. {} contributions remain.", story.id, story.contribution_count);
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `GET /snippets/:id/revisions` to see each prior version of a snippet within a story that you
+/// own.
+pub fn revisions(req: &mut Request) -> IronResult<Response> {
+    let u = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let snippet_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /snippets/{}/revisions [{}]", snippet_id, u.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let snippet = try!(try!(Snippet::with_id(conn, snippet_id).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    let (_, access) = try!(Story::visible_to(conn, snippet.story_id, &u).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
     }
+
+    let revisions = try!(SnippetRevision::for_snippet(conn, &snippet).iron());
+
+    let r = RevisionsResponse {
+        snippet: SnippetDoc::new(&snippet),
+        revisions: revisions.iter().map(|revision| RevisionDoc{
+            content: &revision.content,
+            creation_time: timestamp(&revision.creation_time),
+            replacement_time: timestamp(&revision.replacement_time)
+        }).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// Respond with a JSON document describing a `Snippet`.
+fn respond(st: status::Status, snippet: &Snippet) -> IronResult<Response> {
+    let r = SnippetResponse {
+        snippet: SnippetDoc::new(snippet)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((st, encoded)))
 }
 
-const MAX_BODY_LENGTH: usize = 1024 * 1024 * 10;
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
 
-/// Add the `/snippets` route and its required middleware to a borrowed Router.
+/// Add the `/snippets` routes and their required middleware to a borrowed Router.
 pub fn route(router: &mut Router) {
     let mut chain = Chain::new(post);
 
@@ -89,4 +191,17 @@ pub fn route(router: &mut Router) {
     chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
 
     router.post("/snippets", chain);
+
+    let mut edit_chain = Chain::new(edit);
+    edit_chain.link_before(RequireUser);
+    edit_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/snippets/:id", edit_chain);
+
+    let mut retract_chain = Chain::new(retract);
+    retract_chain.link_before(RequireUser);
+    router.delete("/snippets/:id", retract_chain);
+
+    let mut revisions_chain = Chain::new(revisions);
+    revisions_chain.link_before(RequireUser);
+    router.get("/snippets/:id/revisions", revisions_chain);
 }
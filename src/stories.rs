@@ -1,18 +1,124 @@
 //! Story routes.
 //!
+//! * `GET /stories` - List the stories that you may read or ask to join.
+//! * `POST /stories` - Create a new story from a prompt.
+//! * `GET /stories/:id` - Follow the progress of the story :id.
+//! * `GET /stories/:id/snippets` - Read the full text of the story :id.
+//! * `PUT /stories/:id` - Change the title and settings of the story :id.
+//! * `GET /stories/:id/retractions` - List snippets retracted from the story :id.
+//! * `GET /stories/:id/constraints` - List the writing constraints of the story :id.
+//! * `PUT /stories/:id/constraints` - Replace the writing constraints of the story :id.
+//! * `POST /stories/:id/forks` - Fork the story :id from one of its snippets.
+//! * `GET /stories/:id/forks` - List the tree of forks that includes the story :id.
+//! * `GET /stories/:id/schedule` - Show the upcoming sprint turns of the story :id.
 //! * `POST /stories/:id/lock` - Acquire a lock on the story :id.
+//! * `DELETE /stories/:id/lock` - Release a lock on the story :id.
 
 use iron::{Request, Response, IronResult, Chain};
 use iron::status;
 use router::Router;
-use persistent::Write;
+use persistent::Read;
+use bodyparser;
 use plugin::Extensible;
 use rustc_serialize::json;
+use chrono::UTC;
 
-use model::{Database, Story, ContributionAttempt, Snippet};
+use model::{Database, Story, ContentLimits, Retraction, normalize_title};
+use model::{TurnPolicy, TurnPolicySettings, VisibilityPolicy, VisibilitySettings};
+use model::{CompletionRules, CompletionSettings, StoryPrompt, PromptSettings};
+use model::{StoryMode, StoryModeSettings, VotingRound, Constraint, ConstraintSettings};
+use model::{RevealPolicy, RevealSettings, Snippet, User, StoryEvent, EventKind};
 use auth::{AuthUser, RequireUser};
-use error::IntoIronResult;
-use error::FictError::{Cooldown, AlreadyLocked, NotFound};
+use error::{IntoIronResult, FieldError, as_fict_err};
+use responses::{timestamp, StoryDoc, StoryResponse, StoryWithPromptResponse, SnippetDoc};
+use params;
+use error::FictError::{Cooldown, AlreadyLocked, NotYourTurn, Finished, VotingOnly, Scheduled};
+use error::FictError::{NotFound, Forbidden, Invalid, Unrevealed};
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CreationBody {
+    story: NewStoryBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct NewStoryBody {
+    title: Option<String>,
+    prompt: PromptSettings
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct UpdateBody {
+    story: StorySettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct StorySettingsBody {
+    title: Option<String>,
+    published: Option<bool>,
+    world_readable: Option<bool>,
+    lock_duration_s: Option<i64>,
+    limits: Option<ContentLimits>,
+    turn_policy: Option<TurnPolicySettings>,
+    visibility: Option<VisibilitySettings>,
+    completion: Option<CompletionSettings>,
+    mode: Option<StoryModeSettings>,
+    open_to_join: Option<bool>,
+    reveal: Option<RevealSettings>
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct ConstraintsBody {
+    constraints: Vec<ConstraintSettings>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockStateDoc<'a> {
+    state: &'a str,
+    owner: Option<String>,
+    expires: Option<String>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct StoryProgressResponse<'a> {
+    story: StoryDoc<'a>,
+    lock: LockStateDoc<'a>,
+    revealed: bool
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct SnippetsResponse<'a> {
+    snippets: Vec<SnippetDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct StoriesResponse<'a> {
+    stories: Vec<StoryDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct ForkBody {
+    fork: ForkSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct ForkSettingsBody {
+    snippet_id: i64,
+    title: Option<String>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ForkDoc<'a> {
+    id: i64,
+    title: Option<&'a str>,
+    parent_story_id: Option<i64>,
+    fork_position: Option<i32>,
+    contribution_count: i32
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ForksResponse<'a> {
+    forks: Vec<ForkDoc<'a>>
+}
 
 #[derive(Debug, Clone, RustcEncodable)]
 struct LockGranted<'a> {
@@ -22,13 +128,18 @@ struct LockGranted<'a> {
 
 #[derive(Debug, Clone, RustcEncodable)]
 struct PriorSnippet<'a> {
-    content: &'a str
+    position: i32,
+    content: &'a str,
+    truncated: bool
 }
 
 #[derive(Debug, Clone, RustcEncodable)]
 struct LockGrantedResponse<'a> {
     lock: LockGranted<'a>,
-    snippet: PriorSnippet<'a>
+    snippet: Option<PriorSnippet<'a>>,
+    snippets: Vec<PriorSnippet<'a>>,
+    prompt: Option<PromptSettings>,
+    constraints: Vec<ConstraintSettings>
 }
 
 #[derive(Debug, Clone, RustcEncodable)]
@@ -55,11 +166,604 @@ struct LockCooldownResponse<'a> {
     lock: LockCooldown<'a>
 }
 
-/// Consistent DateTime format to be used throughout the API: `Fri, 10 May 2015 17:58:28 +0000`
-const TIMESTAMP_FORMAT: &'static str = "%a, %d %b %Y %T %z";
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockFinished<'a> {
+    state: &'a str,
+    reason: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockFinishedResponse<'a> {
+    lock: LockFinished<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockVoting<'a> {
+    state: &'a str,
+    reason: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockVotingResponse<'a> {
+    lock: LockVoting<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockScheduled<'a> {
+    state: &'a str,
+    reason: &'a str,
+    owner: Option<&'a str>,
+    expires: Option<String>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockScheduledResponse<'a> {
+    lock: LockScheduled<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct TurnDoc<'a> {
+    username: &'a str,
+    start_time: String,
+    end_time: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ScheduleDoc<'a> {
+    turn_duration_s: Option<i64>,
+    turns: Vec<TurnDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct ScheduleResponse<'a> {
+    schedule: ScheduleDoc<'a>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockOutOfTurn<'a> {
+    state: &'a str,
+    reason: &'a str,
+    next: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct LockOutOfTurnResponse<'a> {
+    lock: LockOutOfTurn<'a>
+}
+
+/// `GET /stories` to discover stories: those that you may read, along with any that are open to
+/// join requests. The most recently updated stories are listed first.
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("GET /stories [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let stories = try!(Story::listed_for(conn, &user, MAX_LISTED).iron());
+
+    let r = StoriesResponse {
+        stories: stories.iter().map(StoryDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `POST /stories` to create a new story that begins from a prompt, rather than from an opening
+/// snippet. You become the story's owner.
+pub fn create(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let body = try!(params::body::<CreationBody>(req)).story;
+
+    debug!("POST /stories [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, prompt) = try!(StoryPrompt::begin(conn, &user, body.title, &body.prompt).iron());
+
+    debug!(".. Created story {} from a prompt.", story.id);
+
+    let r = StoryWithPromptResponse {
+        story: StoryDoc::new(&story),
+        prompt: Some(prompt.settings())
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `GET /stories/:id` to follow the progress of a story you can read: its settings, contribution
+/// count, and who holds its lock. `revealed` reports whether or not you may read its full text
+/// yet with `GET /stories/:id/snippets`.
+pub fn show(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{} [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+
+    let now = UTC::now();
+    let active_lock = match (story.lock_user_id, story.lock_expiration) {
+        (Some(owner_id), Some(expiration)) if expiration > now => Some((owner_id, expiration)),
+        _ => None
+    };
+
+    let lock = match active_lock {
+        Some((owner_id, expiration)) => {
+            let owner = try!(User::with_id(conn, owner_id).iron());
+
+            LockStateDoc{
+                state: "locked",
+                owner: Some(owner.name),
+                expires: Some(timestamp(&expiration))
+            }
+        },
+        None => LockStateDoc{ state: "unlocked", owner: None, expires: None }
+    };
+
+    let r = StoryProgressResponse {
+        story: StoryDoc::new(&story),
+        lock: lock,
+        revealed: story.reveal.reveals(&story, &access)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `GET /stories/:id/snippets` to read the full text of a story, oldest snippet first. Writers and
+/// owners may always read it; spectators must wait until its reveal policy allows.
+pub fn snippets(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/snippets [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! story.reveal.reveals(&story, &access) {
+        return Err(Unrevealed.to_iron_error(status::Forbidden));
+    }
+
+    let snippets = try!(Snippet::for_story(conn, &story).iron());
+
+    let r = SnippetsResponse {
+        snippets: snippets.iter().map(SnippetDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /stories/:id` to change the title or settings of a story that you own. Settings that are
+/// omitted from the request body are left unchanged. If `limits` is present, it replaces all of
+/// the story's existing content limits. Titles are normalized like those of new stories, and a
+/// blank title removes it.
+pub fn update(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<UpdateBody>(req)).story;
+
+    debug!("PUT /stories/{} [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+    let transaction = try!(conn.transaction().map_err(as_fict_err).iron());
+
+    // Hold the story's row lock until the new settings are saved, so that contributions,
+    // retractions, and turn rotations that commit meanwhile aren't overwritten.
+    let mut story = try!(try!(Story::with_id_for_update(&transaction, story_id).iron())
+        .ok_or(NotFound.to_iron_error(status::NotFound)));
+
+    let access = try!(story.access_for(&transaction, &user).iron());
+    if ! access.grants_read() {
+        return Err(NotFound.to_iron_error(status::NotFound));
+    }
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
 
-/// `POST /stories/:id/lock` to acquire a lock on an existing story and retrieve the most recent
-/// contributed Snippet.
+    let mut errors = Vec::new();
+
+    if let Some(duration) = body.lock_duration_s {
+        if duration <= 0 {
+            errors.push(FieldError::new(
+                "story.lock_duration_s", "not_positive", "Lock duration must be positive."
+            ));
+        }
+    }
+
+    if let Some(ref limits) = body.limits {
+        errors.extend(limits.check("story.limits"));
+    }
+
+    let turn_policy = match body.turn_policy {
+        Some(ref settings) => match TurnPolicy::from_settings(settings, "story.turn_policy") {
+            Ok(policy) => Some(policy),
+            Err(policy_errors) => {
+                errors.extend(policy_errors);
+                None
+            }
+        },
+        None => None
+    };
+
+    let visibility = match body.visibility {
+        Some(ref settings) => match VisibilityPolicy::from_settings(settings, "story.visibility") {
+            Ok(policy) => Some(policy),
+            Err(policy_errors) => {
+                errors.extend(policy_errors);
+                None
+            }
+        },
+        None => None
+    };
+
+    let completion = match body.completion {
+        Some(ref settings) => match CompletionRules::from_settings(settings, "story.completion") {
+            Ok(rules) => Some(rules),
+            Err(rule_errors) => {
+                errors.extend(rule_errors);
+                None
+            }
+        },
+        None => None
+    };
+
+    let reveal = match body.reveal {
+        Some(ref settings) => match RevealPolicy::from_settings(settings, "story.reveal") {
+            Ok(policy) => Some(policy),
+            Err(policy_errors) => {
+                errors.extend(policy_errors);
+                None
+            }
+        },
+        None => None
+    };
+
+    let mode = match body.mode {
+        Some(ref settings) => match StoryMode::from_settings(settings, "story.mode") {
+            Ok(mode) => Some(mode),
+            Err(mode_errors) => {
+                errors.extend(mode_errors);
+                None
+            }
+        },
+        None => None
+    };
+
+    if ! errors.is_empty() {
+        return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+    }
+
+    if let Some(title) = body.title {
+        story.title = normalize_title(Some(title));
+    }
+    if let Some(world_readable) = body.world_readable {
+        story.world_readable = world_readable;
+    }
+    if let Some(open_to_join) = body.open_to_join {
+        story.open_to_join = open_to_join;
+    }
+    if let Some(policy) = reveal {
+        story.reveal = policy;
+    }
+    if let Some(duration) = body.lock_duration_s {
+        story.lock_duration_s = duration;
+    }
+    if let Some(limits) = body.limits {
+        story.limits = limits;
+    }
+    if let Some(policy) = turn_policy {
+        story.turn_policy = policy;
+    }
+    if let Some(policy) = visibility {
+        story.visibility = policy;
+    }
+    if let Some(rules) = completion {
+        story.completion = rules;
+    }
+    let mut begin_sprint = false;
+    if let Some(mode) = mode {
+        // Candidates submitted under the previous mode are discarded.
+        if story.mode.is_voting() && ! mode.is_voting() {
+            try!(VotingRound::abandon(&transaction, &story).iron());
+        }
+
+        begin_sprint = mode.is_sprint() && ! story.mode.is_sprint();
+        story.mode = mode;
+    }
+
+    let now = UTC::now();
+    story.update_time = now;
+
+    let was_published = story.published;
+    match body.published {
+        Some(true) => story.publish(now),
+        Some(false) => {
+            story.published = false;
+            story.publish_time = None;
+        },
+        None => ()
+    }
+
+    try!(story.save(&transaction).iron());
+
+    // Changed completion rules may already be met.
+    if ! story.finished && try!(story.completion.satisfied_by(&transaction, &story, now).iron()) {
+        try!(story.finish(&transaction, now).iron());
+    }
+
+    if begin_sprint && ! story.finished {
+        // The sprint's schedule takes the lock from whoever held it.
+        if let Some(holder_id) = story.lock_user_id {
+            let expired = story.lock_expiration.map(|e| e <= now).unwrap_or(true);
+            let (kind, actor_id) = if expired {
+                (EventKind::LockExpired, None)
+            } else {
+                (EventKind::LockReleased, user.id)
+            };
+
+            try!(StoryEvent::record(&transaction, story.id, kind, actor_id, Some(holder_id), None).iron());
+        }
+
+        try!(story.advance_turn(&transaction, None, now).iron());
+
+        if story.lock_user_id.is_some() {
+            try!(StoryEvent::record(&transaction, story.id, EventKind::LockAcquired, user.id, story.lock_user_id, None).iron());
+        }
+    }
+
+    if story.published && ! was_published {
+        try!(StoryEvent::record(&transaction, story.id, EventKind::StoryPublished, user.id, None, None).iron());
+    }
+
+    try!(transaction.commit().map_err(as_fict_err).iron());
+
+    let r = StoryResponse {
+        story: StoryDoc::new(&story)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RetractionDoc<'a> {
+    snippet_id: i64,
+    position: i32,
+    author_id: Option<i64>,
+    retractor_id: Option<i64>,
+    content: &'a str,
+    creation_time: String,
+    retraction_time: String
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct RetractionsResponse<'a> {
+    retractions: Vec<RetractionDoc<'a>>
+}
+
+/// `GET /stories/:id/retractions` to audit the snippets that have been retracted from a story
+/// that you own.
+pub fn retractions(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/retractions [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let retractions = try!(Retraction::for_story(conn, story.id).iron());
+
+    let r = RetractionsResponse {
+        retractions: retractions.iter().map(|retraction| RetractionDoc{
+            snippet_id: retraction.snippet_id,
+            position: retraction.position,
+            author_id: retraction.author_id,
+            retractor_id: retraction.retractor_id,
+            content: &retraction.content,
+            creation_time: timestamp(&retraction.creation_time),
+            retraction_time: timestamp(&retraction.retraction_time)
+        }).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `GET /stories/:id/constraints` to see the rules that each snippet contributed to a story you
+/// can read must obey.
+pub fn constraints(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/constraints [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, _) = try!(Story::visible_to(conn, story_id, &user).iron());
+    let constraints = try!(Constraint::for_story(conn, &story).iron());
+
+    let r = ConstraintsBody {
+        constraints: constraints.iter().map(|c| c.settings()).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `PUT /stories/:id/constraints` to replace the rules that each snippet contributed to a story
+/// that you own must obey. Snippets that were contributed before the change are unaffected.
+pub fn update_constraints(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<ConstraintsBody>(req));
+
+    debug!("PUT /stories/{}/constraints [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+    if ! access.grants_admin() {
+        return Err(Forbidden.to_iron_error(status::Forbidden));
+    }
+
+    let mut errors = Vec::new();
+    let mut constraints = Vec::new();
+
+    for (i, settings) in body.constraints.iter().enumerate() {
+        match Constraint::from_settings(settings, &format!("constraints.{}", i)) {
+            Ok(constraint) => constraints.push(constraint),
+            Err(constraint_errors) => errors.extend(constraint_errors)
+        }
+    }
+
+    if ! errors.is_empty() {
+        return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+    }
+
+    try!(Constraint::replace_all(conn, &story, &constraints).iron());
+
+    let r = ConstraintsBody {
+        constraints: constraints.iter().map(|c| c.settings()).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `POST /stories/:id/forks` to start a new story that continues from a snippet in the history of
+/// a story that you may contribute to. The fork shares each snippet up to and including that one,
+/// and you become its owner.
+pub fn fork(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+    let body = try!(params::body::<ForkBody>(req)).fork;
+
+    debug!("POST /stories/{}/forks [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let title = normalize_title(body.title);
+
+    let story = try!(Story::fork(conn, story_id, body.snippet_id, &user, title).iron());
+
+    debug!(".. Forked story {} as {}.", story_id, story.id);
+
+    let r = StoryResponse {
+        story: StoryDoc::new(&story)
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `GET /stories/:id/forks` to navigate the tree of forks that includes a story you can read. The
+/// tree begins with the original story and omits any fork that you may not read.
+pub fn forks(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/forks [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, _) = try!(Story::visible_to(conn, story_id, &user).iron());
+
+    let mut readable = Vec::new();
+    for candidate in try!(Story::fork_tree(conn, story.id).iron()) {
+        if try!(candidate.access_for(conn, &user).iron()).grants_read() {
+            readable.push(candidate);
+        }
+    }
+
+    let r = ForksResponse {
+        forks: readable.iter().map(|fork| ForkDoc{
+            id: fork.id,
+            title: fork.title.as_ref().map(|t| &t[..]),
+            parent_story_id: fork.parent_story_id,
+            fork_position: fork.fork_position,
+            contribution_count: fork.contribution_count
+        }).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `GET /stories/:id/schedule` to see whose turn it is in a sprint, and when each writer's next
+/// turn begins if everyone uses their whole turn.
+pub fn schedule(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let story_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /stories/{}/schedule [{}]", story_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let (story, _) = try!(Story::visible_to(conn, story_id, &user).iron());
+    let turns = try!(story.sprint_schedule(conn).iron());
+
+    let r = ScheduleResponse {
+        schedule: ScheduleDoc{
+            turn_duration_s: story.mode.settings().turn_duration_s,
+            turns: turns.iter().map(|turn| TurnDoc{
+                username: &turn.user.name,
+                start_time: timestamp(&turn.start_time),
+                end_time: timestamp(&turn.end_time)
+            }).collect()
+        }
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `POST /stories/:id/lock` to acquire a lock on an existing story and retrieve as much of its most
+/// recently contributed Snippets as the story's visibility policy reveals.
 pub fn acquire_lock(req: &mut Request) -> IronResult<Response> {
     let applicant = req.extensions().get::<AuthUser>().cloned()
         .expect("No authenticated user");
@@ -73,32 +777,30 @@ pub fn acquire_lock(req: &mut Request) -> IronResult<Response> {
 
     debug!("POST /stories/{}/lock [{}]", story_id, applicant.name);
 
-    let mutex = req.extensions().get::<Write<Database>>()
-        .cloned()
-        .expect("No database connection available");
-    let pool = mutex.lock().unwrap();
-    let ref conn = *pool.get().unwrap();
+    let ref conn = *try!(Database::connection(req));
 
-    match Story::locked_for_write(conn, story_id, &applicant, true) {
-        Ok(story) => {
-            debug!(".. Lock granted until {:?}.", story.lock_expiration);
+    match Story::acquire_lock(conn, story_id, &applicant) {
+        Ok(grant) => {
+            debug!(".. Lock granted until {:?}.", grant.expiration);
 
-            try!(ContributionAttempt::record(conn, &story, &applicant).iron());
+            let formatted_expiration = timestamp(&grant.expiration);
 
-            let formatted_expiration = story.lock_expiration.map(|exp| {
-                format!("{}", exp.format(TIMESTAMP_FORMAT))
-            }).expect("Story missing expiration date");
-
-            let snippet = try!(Snippet::most_recent(conn, &story).iron());
+            // Reveal as much of the story so far as its visibility policy permits.
+            let prior: Vec<PriorSnippet> = grant.excerpts.iter().map(|excerpt| PriorSnippet{
+                position: excerpt.position,
+                content: &excerpt.content,
+                truncated: excerpt.truncated
+            }).collect();
 
             let r = LockGrantedResponse {
                 lock: LockGranted{
                     state: "granted",
                     expires: &formatted_expiration
                 },
-                snippet: PriorSnippet{
-                    content: &snippet.content
-                }
+                snippet: prior.last().cloned(),
+                snippets: prior,
+                prompt: grant.prompt.as_ref().map(|p| p.settings()),
+                constraints: grant.constraints.iter().map(|c| c.settings()).collect()
             };
 
             let encoded = json::encode(&r)
@@ -114,7 +816,7 @@ pub fn acquire_lock(req: &mut Request) -> IronResult<Response> {
                     state: "denied",
                     reason: "conflict",
                     owner: &username,
-                    expires: &format!("{}", expiration.format(TIMESTAMP_FORMAT))
+                    expires: &timestamp(&expiration)
                 }
             };
 
@@ -138,6 +840,75 @@ pub fn acquire_lock(req: &mut Request) -> IronResult<Response> {
 
             Ok(Response::with((status::Conflict, encoded)))
         },
+        Err(Finished) => {
+            debug!(".. Lock denied: story has finished.");
+
+            let r = LockFinishedResponse {
+                lock: LockFinished{
+                    state: "denied",
+                    reason: "story finished"
+                }
+            };
+
+            let encoded = json::encode(&r)
+                .expect("Unable to encode response JSON");
+
+            Ok(Response::with((status::Conflict, encoded)))
+        },
+        Err(VotingOnly) => {
+            debug!(".. Lock denied: story chooses snippets by vote.");
+
+            let r = LockVotingResponse {
+                lock: LockVoting{
+                    state: "denied",
+                    reason: "story chooses snippets by vote"
+                }
+            };
+
+            let encoded = json::encode(&r)
+                .expect("Unable to encode response JSON");
+
+            Ok(Response::with((status::Conflict, encoded)))
+        },
+        Err(Scheduled) => {
+            debug!(".. Lock denied: story's lock rotates on a schedule.");
+
+            let turns = match try!(Story::with_id(conn, story_id).iron()) {
+                Some(story) => try!(story.sprint_schedule(conn).iron()),
+                None => Vec::new()
+            };
+            let current = turns.first();
+
+            let r = LockScheduledResponse {
+                lock: LockScheduled{
+                    state: "denied",
+                    reason: "scheduled",
+                    owner: current.map(|turn| &turn.user.name[..]),
+                    expires: current.map(|turn| timestamp(&turn.end_time))
+                }
+            };
+
+            let encoded = json::encode(&r)
+                .expect("Unable to encode response JSON");
+
+            Ok(Response::with((status::Conflict, encoded)))
+        },
+        Err(NotYourTurn { username }) => {
+            debug!(".. Lock denied: [{}] is next.", username);
+
+            let r = LockOutOfTurnResponse {
+                lock: LockOutOfTurn{
+                    state: "denied",
+                    reason: "not your turn",
+                    next: &username
+                }
+            };
+
+            let encoded = json::encode(&r)
+                .expect("Unable to encode response JSON");
+
+            Ok(Response::with((status::Conflict, encoded)))
+        },
         Err(NotFound) => {
             debug!(".. Story not found or permission denied");
 
@@ -150,7 +921,8 @@ pub fn acquire_lock(req: &mut Request) -> IronResult<Response> {
     }
 }
 
-/// `DELETE /stories/:id/lock` to revoke a lock on a story that you currently hold.
+/// `DELETE /stories/:id/lock` to revoke a lock on a story that you currently hold. Revoking your
+/// lock during a sprint passes your turn to the next writer.
 pub fn revoke_lock(req: &mut Request) -> IronResult<Response> {
     let user = req.extensions().get::<AuthUser>().cloned()
         .expect("No authenticated user");
@@ -164,22 +936,70 @@ pub fn revoke_lock(req: &mut Request) -> IronResult<Response> {
 
     debug!("DELETE /stories/{}/lock [{}]", story_id, user.name);
 
-    let mutex = req.extensions().get::<Write<Database>>()
-        .cloned()
-        .expect("No database connection available");
-    let pool = mutex.lock().unwrap();
-    let ref conn = *pool.get().unwrap();
+    let ref conn = *try!(Database::connection(req));
 
-    let story = try!(Story::locked_for_write(conn, story_id, &user, false).iron());
-    try!(story.unlock(conn).iron());
+    try!(Story::release_lock(conn, story_id, &user, UTC::now()).iron());
 
     debug!(".. Lock revoked succesfully.");
 
     Ok(Response::with(status::NoContent))
 }
 
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Maximum number of stories included in a response to `GET /stories`.
+const MAX_LISTED: i64 = 100;
+
 /// Register `/stories` routes and their required middleware.
 pub fn route(router: &mut Router) {
+    let mut list_chain = Chain::new(list);
+    list_chain.link_before(RequireUser);
+    router.get("/stories", list_chain);
+
+    let mut create_chain = Chain::new(create);
+    create_chain.link_before(RequireUser);
+    create_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories", create_chain);
+
+    let mut show_chain = Chain::new(show);
+    show_chain.link_before(RequireUser);
+    router.get("/stories/:id", show_chain);
+
+    let mut snippets_chain = Chain::new(snippets);
+    snippets_chain.link_before(RequireUser);
+    router.get("/stories/:id/snippets", snippets_chain);
+
+    let mut update_chain = Chain::new(update);
+    update_chain.link_before(RequireUser);
+    update_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/stories/:id", update_chain);
+
+    let mut retractions_chain = Chain::new(retractions);
+    retractions_chain.link_before(RequireUser);
+    router.get("/stories/:id/retractions", retractions_chain);
+
+    let mut constraints_chain = Chain::new(constraints);
+    constraints_chain.link_before(RequireUser);
+    router.get("/stories/:id/constraints", constraints_chain);
+
+    let mut update_constraints_chain = Chain::new(update_constraints);
+    update_constraints_chain.link_before(RequireUser);
+    update_constraints_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.put("/stories/:id/constraints", update_constraints_chain);
+
+    let mut fork_chain = Chain::new(fork);
+    fork_chain.link_before(RequireUser);
+    fork_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/stories/:id/forks", fork_chain);
+
+    let mut forks_chain = Chain::new(forks);
+    forks_chain.link_before(RequireUser);
+    router.get("/stories/:id/forks", forks_chain);
+
+    let mut schedule_chain = Chain::new(schedule);
+    schedule_chain.link_before(RequireUser);
+    router.get("/stories/:id/schedule", schedule_chain);
+
     let mut acquire_lock_chain = Chain::new(acquire_lock);
     acquire_lock_chain.link_before(RequireUser);
     router.post("/stories/:id/lock", acquire_lock_chain);
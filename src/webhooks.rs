@@ -0,0 +1,771 @@
+//! Webhook routes, and delivery of the events they subscribe to.
+//!
+//! * `POST /webhooks` - Subscribe an HTTP endpoint to story events.
+//! * `GET /webhooks` - List the webhooks that you own.
+//! * `DELETE /webhooks/:id` - Remove the webhook :id.
+//! * `GET /webhooks/:id/deliveries` - List recent deliveries made to the webhook :id.
+//!
+//! Each event is delivered as a `POST` of a JSON document to the webhook's URL. The
+//! `X-Fiction-Signature` header carries `sha256=` followed by the hex-encoded HMAC-SHA256 of the
+//! request body, keyed with the webhook's secret, so that receivers can verify where it came from.
+//! Any `2xx` response acknowledges the delivery; anything else is retried with exponential backoff.
+//! Deliveries are made from a thread of their own, so that slow endpoints never delay other
+//! background jobs.
+//!
+//! Webhooks may not deliver to loopback, private, or link-local addresses, which are checked each
+//! time a URL's host is resolved. Set `FICTION_WEBHOOK_ALLOW_PRIVATE` to `true` to allow them, so
+//! that a local stand-in can receive deliveries during development.
+
+use std::io::{self, Read as IoRead};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use iron::{Request, Response, IronResult, Chain};
+use iron::status;
+use router::Router;
+use persistent::Read;
+use bodyparser;
+use plugin::Extensible;
+use rustc_serialize::json;
+use rustc_serialize::hex::ToHex;
+use postgres::GenericConnection;
+use hyper::Client;
+use hyper::Url as HyperUrl;
+use hyper::Error as HyperError;
+use hyper::client::RedirectPolicy;
+use hyper::header::{ContentType, Headers, UserAgent};
+use hyper::net::{NetworkConnector, HttpStream, HttpsStream, Openssl, Ssl};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use chrono::{DateTime, UTC};
+use chrono::duration::Duration;
+
+use config::env_opt;
+use model::{Database, PostgresPool, Story, Snippet, StoryEvent, EventKind, User};
+use model::{Webhook, WebhookDelivery, DeliveryState};
+use auth::{AuthUser, RequireUser};
+use error::{FictResult, IntoIronResult, FieldError};
+use error::FictError::{NotFound, Forbidden, Invalid};
+use responses::{timestamp, EventDoc, SnippetDoc};
+use params;
+
+/// Custom user agent to use for webhook deliveries.
+const USER_AGENT: &'static str = "collabfict-webhooks/0.0.1";
+
+/// Maximum number of deliveries attempted by each run.
+const DELIVERY_BATCH_SIZE: i64 = 50;
+
+/// Seconds to wait between runs of the delivery thread.
+const DELIVERY_INTERVAL_S: u64 = 5;
+
+/// Seconds after the start of a run beyond which no more deliveries are begun. The rest wait for
+/// the next run.
+const RUN_BUDGET_S: i64 = 60;
+
+/// Seconds to wait for a connection to a webhook's endpoint before counting the attempt as failed.
+const CONNECT_TIMEOUT_S: i64 = 5;
+
+/// Milliseconds to wait between checks on a connection attempt in progress.
+const CONNECT_POLL_MS: u64 = 50;
+
+/// Seconds to wait for a webhook's endpoint to respond before counting the attempt as failed.
+const DELIVERY_TIMEOUT_S: u64 = 10;
+
+/// Maximum number of deliveries listed by `GET /webhooks/:id/deliveries`.
+const MAX_LISTED_DELIVERIES: i64 = 100;
+
+/// Error recorded for an attempt to deliver to an address that webhooks may not reach.
+const FORBIDDEN_DESTINATION: &'static str = "Webhook URL resolves to a private or local address";
+
+/// Error recorded for an attempt that failed before the endpoint responded. Specific causes are
+/// logged rather than recorded, so that the delivery log can't be used to probe the network.
+const UNREACHABLE: &'static str = "Unable to reach the webhook URL";
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct CreationBody {
+    webhook: WebhookSettingsBody
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+struct WebhookSettingsBody {
+    url: String,
+    story_id: Option<i64>,
+    events: Option<Vec<String>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct WebhookDoc<'a> {
+    id: i64,
+    story_id: Option<i64>,
+    url: &'a str,
+    events: Vec<&'static str>,
+    creation_time: String
+}
+
+impl<'a> WebhookDoc<'a> {
+
+    fn new(webhook: &'a Webhook) -> WebhookDoc<'a> {
+        WebhookDoc{
+            id: webhook.id,
+            story_id: webhook.story_id,
+            url: &webhook.url,
+            events: webhook.events.iter().map(EventKind::name).collect(),
+            creation_time: timestamp(&webhook.creation_time)
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct DeliveryDoc<'a> {
+    id: i64,
+    event_id: i64,
+    state: &'static str,
+    attempt_count: i32,
+    next_attempt_time: Option<String>,
+    last_attempt_time: Option<String>,
+    response_status: Option<i32>,
+    last_error: Option<&'a str>,
+    creation_time: String
+}
+
+impl<'a> DeliveryDoc<'a> {
+
+    fn new(delivery: &'a WebhookDelivery) -> DeliveryDoc<'a> {
+        let pending = delivery.state == DeliveryState::Pending;
+
+        DeliveryDoc{
+            id: delivery.id,
+            event_id: delivery.event_id,
+            state: delivery.state.name(),
+            attempt_count: delivery.attempt_count,
+            next_attempt_time: if pending { Some(timestamp(&delivery.next_attempt_time)) } else { None },
+            last_attempt_time: delivery.last_attempt_time.as_ref().map(timestamp),
+            response_status: delivery.response_status,
+            last_error: delivery.last_error.as_ref().map(|e| &e[..]),
+            creation_time: timestamp(&delivery.creation_time)
+        }
+    }
+
+}
+
+/// The secret is only revealed once, when the webhook is created.
+#[derive(Debug, Clone, RustcEncodable)]
+struct CreatedWebhookResponse<'a> {
+    webhook: WebhookDoc<'a>,
+    secret: &'a str
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct WebhooksResponse<'a> {
+    webhooks: Vec<WebhookDoc<'a>>
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+struct DeliveriesResponse<'a> {
+    webhook: WebhookDoc<'a>,
+    deliveries: Vec<DeliveryDoc<'a>>
+}
+
+/// Document `POST`ed to a webhook's URL for each event it's subscribed to. `snippet` accompanies
+/// `snippet_contributed` events if the webhook's owner may read the story's text.
+#[derive(Debug, Clone, RustcEncodable)]
+struct DeliveryPayload<'a> {
+    delivery_id: i64,
+    webhook_id: i64,
+    event: EventDoc<'a>,
+    snippet: Option<SnippetDoc<'a>>
+}
+
+/// `POST /webhooks` to subscribe an HTTP endpoint to story events. Given a `story_id` of a story
+/// that you own, only that story's events are delivered; otherwise, events from every story you
+/// can read are. `events` limits deliveries to events of the named kinds. The response includes
+/// the secret used to sign each delivery, which is never shown again.
+pub fn create(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let body = try!(params::body::<CreationBody>(req)).webhook;
+
+    debug!("POST /webhooks [{}]", user.name);
+
+    let allow_private = try!(allow_private().iron());
+
+    let mut errors = Vec::new();
+
+    match HyperUrl::parse(&body.url) {
+        Ok(ref url) if url.scheme == "http" || url.scheme == "https" => {
+            let host = url.serialize_host().unwrap_or(String::new());
+            let port = url.port_or_default().unwrap_or(0);
+
+            if let Err(e) = destination(&host, port, allow_private) {
+                debug!(".. Refusing webhook URL {}: {}", body.url, e);
+                errors.push(FieldError::new(
+                    "webhook.url", "forbidden",
+                    "URL must resolve to a public address."
+                ));
+            }
+        },
+        _ => errors.push(FieldError::new(
+            "webhook.url", "invalid", "URL must be an absolute http or https URL."
+        ))
+    }
+
+    let mut events = Vec::new();
+    for name in body.events.as_ref().map(|e| &e[..]).unwrap_or(&[]) {
+        match EventKind::from_name(name) {
+            Some(kind) => events.push(kind),
+            None => errors.push(FieldError::new(
+                "webhook.events", "unknown", format!("Unknown event kind: {}.", name)
+            ))
+        }
+    }
+
+    if ! errors.is_empty() {
+        return Err(Invalid(errors).to_iron_error(status::UnprocessableEntity));
+    }
+
+    let ref conn = *try!(Database::connection(req));
+
+    let story = match body.story_id {
+        Some(story_id) => {
+            let (story, access) = try!(Story::visible_to(conn, story_id, &user).iron());
+            if ! access.grants_admin() {
+                return Err(Forbidden.to_iron_error(status::Forbidden));
+            }
+            Some(story)
+        },
+        None => None
+    };
+
+    let webhook = try!(Webhook::create(conn, &user, story.as_ref(), body.url, &events).iron());
+
+    debug!(".. Created webhook {}.", webhook.id);
+
+    let r = CreatedWebhookResponse {
+        webhook: WebhookDoc::new(&webhook),
+        secret: &webhook.secret
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Created, encoded)))
+}
+
+/// `GET /webhooks` to list each webhook that you own.
+pub fn list(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+
+    debug!("GET /webhooks [{}]", user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let webhooks = try!(Webhook::for_owner(conn, &user).iron());
+
+    let r = WebhooksResponse {
+        webhooks: webhooks.iter().map(WebhookDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// `DELETE /webhooks/:id` to remove a webhook that you own. Deliveries that haven't been made yet
+/// are abandoned.
+pub fn delete(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let webhook_id = try!(params::numeric(req, "id"));
+
+    debug!("DELETE /webhooks/{} [{}]", webhook_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let webhook = try!(owned_webhook(conn, webhook_id, &user));
+    try!(webhook.delete(conn).iron());
+
+    Ok(Response::with(status::NoContent))
+}
+
+/// `GET /webhooks/:id/deliveries` to list the most recent deliveries made to a webhook that you
+/// own, including the outcome of each one's latest attempt.
+pub fn deliveries(req: &mut Request) -> IronResult<Response> {
+    let user = req.extensions().get::<AuthUser>().cloned()
+        .expect("No authenticated user");
+    let webhook_id = try!(params::numeric(req, "id"));
+
+    debug!("GET /webhooks/{}/deliveries [{}]", webhook_id, user.name);
+
+    let ref conn = *try!(Database::connection(req));
+
+    let webhook = try!(owned_webhook(conn, webhook_id, &user));
+    let deliveries = try!(WebhookDelivery::for_webhook(conn, &webhook, MAX_LISTED_DELIVERIES).iron());
+
+    let r = DeliveriesResponse {
+        webhook: WebhookDoc::new(&webhook),
+        deliveries: deliveries.iter().map(DeliveryDoc::new).collect()
+    };
+
+    let encoded = json::encode(&r)
+        .expect("Unable to encode response JSON");
+
+    Ok(Response::with((status::Ok, encoded)))
+}
+
+/// Load the webhook `id`, ensuring that it's owned by `user`. Webhooks owned by anyone else are
+/// reported as missing.
+fn owned_webhook(conn: &GenericConnection, id: i64, user: &User) -> IronResult<Webhook> {
+    match try!(Webhook::with_id(conn, id).iron()) {
+        Some(ref webhook) if ! webhook.is_owned_by(user) => Err(NotFound.to_iron_error(status::NotFound)),
+        Some(webhook) => Ok(webhook),
+        None => Err(NotFound.to_iron_error(status::NotFound))
+    }
+}
+
+/// Launch a thread that makes each webhook delivery as it comes due, using connections from
+/// `pool`.
+pub fn start(pool: PostgresPool) -> FictResult<()> {
+    try!(thread::Builder::new().name("webhooks".to_owned()).spawn(move || {
+        info!("Delivering webhooks every {} seconds.", DELIVERY_INTERVAL_S);
+
+        loop {
+            match pool.get() {
+                Ok(conn) => match deliver_due(&*conn, UTC::now()) {
+                    Ok(delivered) if delivered > 0 => debug!("Attempted {} webhook deliveries.", delivered),
+                    Ok(_) => (),
+                    Err(e) => error!("Unable to deliver webhooks: {}", e)
+                },
+                Err(e) => error!("Unable to deliver webhooks: {}", e)
+            }
+
+            thread::sleep(StdDuration::from_secs(DELIVERY_INTERVAL_S));
+        }
+    }));
+
+    Ok(())
+}
+
+/// Attempt each webhook delivery that's due at `now`, until `RUN_BUDGET_S` elapses. Produce the
+/// number of deliveries attempted.
+pub fn deliver_due(conn: &GenericConnection, now: DateTime<UTC>) -> FictResult<usize> {
+    let due = try!(WebhookDelivery::due(conn, now, DELIVERY_BATCH_SIZE));
+    let deadline = now + Duration::seconds(RUN_BUDGET_S);
+    let mut count = 0;
+
+    let mut client = Client::with_connector(GuardedConnector {
+        allow_private: try!(allow_private())
+    });
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    client.set_read_timeout(Some(StdDuration::from_secs(DELIVERY_TIMEOUT_S)));
+    client.set_write_timeout(Some(StdDuration::from_secs(DELIVERY_TIMEOUT_S)));
+
+    for (mut delivery, webhook) in due.into_iter() {
+        if UTC::now() >= deadline {
+            debug!("Deferring the remaining webhook deliveries to the next run.");
+            break;
+        }
+
+        count += 1;
+
+        let (response_status, error) = match payload(conn, &delivery, &webhook) {
+            Ok(body) => post(&client, &webhook, &delivery, &body),
+            Err(e) => (None, Some(format!("Unable to render event: {}", e)))
+        };
+
+        if let Some(ref e) = error {
+            debug!("Delivery {} to webhook {} failed: {}", delivery.id, webhook.id, e);
+        }
+
+        try!(delivery.record_attempt(conn, UTC::now(), response_status, error));
+    }
+
+    Ok(count)
+}
+
+/// Render the document delivered for a webhook's event.
+fn payload(conn: &GenericConnection, delivery: &WebhookDelivery, webhook: &Webhook) -> FictResult<String> {
+    let event = try!(try!(StoryEvent::with_id(conn, delivery.event_id)).ok_or(NotFound));
+
+    let snippet = match (&event.kind, event.detail.as_ref().and_then(|d| d.parse::<i64>().ok())) {
+        (&EventKind::SnippetContributed, Some(snippet_id)) => {
+            if try!(owner_may_read(conn, webhook, event.story_id)) {
+                try!(Snippet::with_id(conn, snippet_id))
+            } else {
+                None
+            }
+        },
+        _ => None
+    };
+
+    let p = DeliveryPayload {
+        delivery_id: delivery.id,
+        webhook_id: webhook.id,
+        event: EventDoc::new(&event),
+        snippet: snippet.as_ref().map(SnippetDoc::new)
+    };
+
+    Ok(try!(json::encode(&p)))
+}
+
+/// Determine whether or not the owner of a webhook may currently read the text of a story.
+fn owner_may_read(conn: &GenericConnection, webhook: &Webhook, story_id: i64) -> FictResult<bool> {
+    let story = match try!(Story::with_id(conn, story_id)) {
+        Some(story) => story,
+        None => return Ok(false)
+    };
+
+    let owner = try!(User::with_id(conn, webhook.owner_id));
+    let access = try!(story.access_for(conn, &owner));
+
+    Ok(story.reveal.reveals(&story, &access))
+}
+
+/// `POST` a rendered payload to a webhook's URL, signed with its secret. Produce the response's
+/// status, if one was received, and a description of the failure, if the attempt failed.
+fn post(client: &Client, webhook: &Webhook, delivery: &WebhookDelivery, body: &str) -> (Option<i32>, Option<String>) {
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    headers.set(UserAgent(USER_AGENT.to_owned()));
+    headers.set_raw("X-Fiction-Signature", vec![signature(&webhook.secret, body).into_bytes()]);
+    headers.set_raw("X-Fiction-Delivery", vec![delivery.id.to_string().into_bytes()]);
+
+    match client.post(&webhook.url[..]).headers(headers).body(body).send() {
+        Ok(mut response) => {
+            let code = response.status.to_u16() as i32;
+
+            // Drain the response so that the connection may be reused.
+            let mut discarded = Vec::new();
+            let _ = response.read_to_end(&mut discarded);
+
+            if response.status.is_success() {
+                (Some(code), None)
+            } else {
+                (Some(code), Some(format!("Endpoint responded with {}", response.status)))
+            }
+        },
+        Err(e) => {
+            debug!("Unable to reach webhook {}: {}", webhook.id, e);
+
+            let error = match e {
+                HyperError::Io(ref e) if e.kind() == io::ErrorKind::PermissionDenied => FORBIDDEN_DESTINATION,
+                _ => UNREACHABLE
+            };
+
+            (None, Some(error.to_owned()))
+        }
+    }
+}
+
+/// Determine whether or not webhooks may deliver to loopback, private, and link-local addresses.
+fn allow_private() -> FictResult<bool> {
+    Ok(try!(env_opt::<bool>("FICTION_WEBHOOK_ALLOW_PRIVATE")).unwrap_or(false))
+}
+
+/// Resolve the host of a webhook URL and produce the addresses that a delivery may connect to.
+/// Unless `allow_private` is set, fail with `PermissionDenied` if any of them is a loopback,
+/// private, or link-local address.
+fn destination(host: &str, port: u16, allow_private: bool) -> io::Result<Vec<SocketAddr>> {
+    // IPv6 literals are written within brackets in URLs.
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    let addresses: Vec<SocketAddr> = try!((host, port).to_socket_addrs()).collect();
+
+    if addresses.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host)));
+    }
+
+    if ! allow_private && addresses.iter().any(is_internal) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, FORBIDDEN_DESTINATION));
+    }
+
+    Ok(addresses)
+}
+
+/// Determine whether or not an address belongs to this host or a private network: unspecified,
+/// loopback, private, shared, link-local, multicast, or broadcast addresses, along with IPv6
+/// unique local addresses and IPv4 addresses mapped into IPv6.
+fn is_internal(address: &SocketAddr) -> bool {
+    match *address {
+        SocketAddr::V4(ref a) => is_internal_v4(a.ip().octets()),
+        SocketAddr::V6(ref a) => {
+            let s = a.ip().segments();
+            let prefix_zero = s[0] == 0 && s[1] == 0 && s[2] == 0 && s[3] == 0 && s[4] == 0;
+
+            if prefix_zero && s[5] == 0xffff {
+                is_internal_v4([(s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8])
+            } else {
+                (prefix_zero && s[5] == 0 && s[6] == 0 && s[7] <= 1) ||
+                    (s[0] & 0xfe00) == 0xfc00 ||
+                    (s[0] & 0xffc0) == 0xfe80 ||
+                    (s[0] & 0xff00) == 0xff00
+            }
+        }
+    }
+}
+
+/// Determine whether or not an IPv4 address, given as its octets, is internal as described by
+/// `is_internal`.
+fn is_internal_v4(o: [u8; 4]) -> bool {
+    o[0] == 0 || o[0] == 10 || o[0] == 127 ||
+        (o[0] == 100 && (o[1] & 0xc0) == 64) ||
+        (o[0] == 169 && o[1] == 254) ||
+        (o[0] == 172 && (o[1] & 0xf0) == 16) ||
+        (o[0] == 192 && o[1] == 168) ||
+        o[0] >= 224
+}
+
+/// Connect to the first of a set of addresses that accepts a connection.
+fn connect_any(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No addresses to connect to");
+
+    for address in addresses.iter() {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Connect to one of a set of addresses as `connect_any` does, but give up once `timeout` has
+/// elapsed. The attempt is made from a thread of its own, which is left to finish on its own
+/// once it's been given up on.
+fn connect_within(addresses: Vec<SocketAddr>, timeout: Duration) -> io::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+
+    try!(thread::Builder::new().name("webhook-connect".to_owned()).spawn(move || {
+        let _ = sender.send(connect_any(&addresses));
+    }));
+
+    let deadline = UTC::now() + timeout;
+
+    loop {
+        match receiver.try_recv() {
+            Ok(result) => return result,
+            Err(TryRecvError::Disconnected) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "Connection attempt abandoned"));
+            },
+            Err(TryRecvError::Empty) => {
+                if UTC::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"));
+                }
+
+                thread::sleep(StdDuration::from_millis(CONNECT_POLL_MS));
+            }
+        }
+    }
+}
+
+/// Connector that opens connections to a webhook's endpoint only after checking each address
+/// that its host resolves to, and connects to those same addresses, so that a host can't resolve
+/// to a public address when checked and a private one when connected to.
+struct GuardedConnector {
+    allow_private: bool
+}
+
+impl NetworkConnector for GuardedConnector {
+    type Stream = HttpsStream<<Openssl as Ssl>::Stream>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> Result<Self::Stream, HyperError> {
+        let addresses = try!(destination(host, port, self.allow_private));
+        let stream = HttpStream(try!(connect_within(addresses, Duration::seconds(CONNECT_TIMEOUT_S))));
+
+        match scheme {
+            "http" => Ok(HttpsStream::Http(stream)),
+            "https" => Openssl::default().wrap_client(stream, host).map(HttpsStream::Https),
+            _ => Err(HyperError::Io(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported URL scheme")))
+        }
+    }
+}
+
+/// Compute the `X-Fiction-Signature` header value of a payload.
+fn signature(secret: &str, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body.as_bytes());
+
+    format!("sha256={}", hmac.result().code().to_hex())
+}
+
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Register webhook routes and their required middleware.
+pub fn route(router: &mut Router) {
+    let mut create_chain = Chain::new(create);
+    create_chain.link_before(RequireUser);
+    create_chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
+    router.post("/webhooks", create_chain);
+
+    let mut list_chain = Chain::new(list);
+    list_chain.link_before(RequireUser);
+    router.get("/webhooks", list_chain);
+
+    let mut delete_chain = Chain::new(delete);
+    delete_chain.link_before(RequireUser);
+    router.delete("/webhooks/:id", delete_chain);
+
+    let mut deliveries_chain = Chain::new(deliveries);
+    deliveries_chain.link_before(RequireUser);
+    router.get("/webhooks/:id/deliveries", deliveries_chain);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ascii::AsciiExt;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, SocketAddr};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use hyper::Client;
+    use chrono::UTC;
+
+    use model::{Webhook, WebhookDelivery};
+    use super::{GuardedConnector, FORBIDDEN_DESTINATION, post, signature, destination, is_internal};
+
+    /// Start a stand-in endpoint on a local port that accepts a single request, replies to it
+    /// with `status_line`, and sends the raw request back through the produced channel.
+    fn endpoint(status_line: &'static str) -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+
+            while ! complete(&request) {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend(buffer[..read].iter().cloned());
+            }
+
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status_line);
+            stream.write_all(response.as_bytes()).unwrap();
+            sender.send(String::from_utf8(request).unwrap()).unwrap();
+        });
+
+        (port, receiver)
+    }
+
+    /// Determine whether or not a raw HTTP request has been received in full.
+    fn complete(request: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(request);
+
+        match text.find("\r\n\r\n") {
+            Some(end) => {
+                let length = text[..end].lines()
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, ':');
+                        match (parts.next(), parts.next()) {
+                            (Some(name), Some(value)) if name.eq_ignore_ascii_case("content-length") => {
+                                value.trim().parse::<usize>().ok()
+                            },
+                            _ => None
+                        }
+                    })
+                    .next()
+                    .unwrap_or(0);
+
+                request.len() >= end + 4 + length
+            },
+            None => false
+        }
+    }
+
+    /// Construct an unsaved webhook that delivers to `url`.
+    fn webhook(url: String) -> Webhook {
+        Webhook {
+            id: 1,
+            owner_id: 1,
+            story_id: None,
+            url: url,
+            secret: "secret".to_owned(),
+            events: Vec::new(),
+            creation_time: UTC::now()
+        }
+    }
+
+    fn client(allow_private: bool) -> Client {
+        Client::with_connector(GuardedConnector { allow_private: allow_private })
+    }
+
+    fn internal(address: &str) -> bool {
+        is_internal(&address.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn signature_is_an_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn internal_addresses() {
+        for address in ["0.0.0.0:80", "10.1.2.3:80", "127.0.0.1:80", "100.64.0.1:80", "169.254.169.254:80",
+                        "172.16.0.1:80", "172.31.255.255:80", "192.168.1.1:80", "224.0.0.1:80",
+                        "255.255.255.255:80", "[::]:80", "[::1]:80", "[fc00::1]:80", "[fd12::1]:80",
+                        "[fe80::1]:80", "[ff02::1]:80", "[::ffff:127.0.0.1]:80", "[::ffff:10.0.0.1]:80"].iter() {
+            assert!(internal(address), "{} should be internal", address);
+        }
+    }
+
+    #[test]
+    fn public_addresses() {
+        for address in ["8.8.8.8:80", "93.184.216.34:443", "100.128.0.1:80", "172.32.0.1:80",
+                        "192.169.0.1:80", "[2001:db8::1]:80", "[::ffff:8.8.8.8]:80"].iter() {
+            assert!(! internal(address), "{} should be public", address);
+        }
+    }
+
+    #[test]
+    fn destination_refuses_private_addresses() {
+        assert!(destination("93.184.216.34", 443, false).is_ok());
+        assert!(destination("127.0.0.1", 80, false).is_err());
+        assert!(destination("[::1]", 80, false).is_err());
+        assert_eq!(destination("127.0.0.1", 80, true).unwrap(), vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn post_delivers_a_signed_payload() {
+        let (port, requests) = endpoint("200 OK");
+        let body = r#"{"event":"snippet_contributed"}"#;
+
+        let result = post(&client(true), &webhook(format!("http://127.0.0.1:{}/hook", port)), &WebhookDelivery::sample(42, 7), body);
+        assert_eq!(result, (Some(200), None));
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("X-Fiction-Signature: {}\r\n", signature("secret", body))));
+        assert!(request.contains("X-Fiction-Delivery: 42\r\n"));
+        assert!(request.ends_with(body));
+    }
+
+    #[test]
+    fn post_reports_unsuccessful_responses() {
+        let (port, requests) = endpoint("500 Internal Server Error");
+
+        let (status, error) = post(&client(true), &webhook(format!("http://127.0.0.1:{}/", port)), &WebhookDelivery::sample(42, 7), "{}");
+        assert_eq!(status, Some(500));
+        assert!(error.is_some());
+
+        requests.recv().unwrap();
+    }
+
+    #[test]
+    fn post_refuses_private_destinations() {
+        let result = post(&client(false), &webhook("http://127.0.0.1:9/".to_owned()), &WebhookDelivery::sample(42, 7), "{}");
+        assert_eq!(result, (None, Some(FORBIDDEN_DESTINATION.to_owned())));
+    }
+}